@@ -0,0 +1,264 @@
+use std::hash::Hasher;
+use std::io::{Read, Write};
+
+/// 64-bit FNV-1a hasher.
+///
+/// Unlike the standard library's `DefaultHasher`, the algorithm is fixed,
+/// so hashes can be stored and compared across builds and platforms.
+/// It isn't resistant to deliberate collisions.
+#[derive(Clone, Copy, Debug)]
+pub struct Fnv1aHasher(u64);
+
+impl Fnv1aHasher {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+
+    /// Creates a hasher in its initial state.
+    pub fn new() -> Self {
+        Self(Self::OFFSET_BASIS)
+    }
+}
+
+impl Default for Fnv1aHasher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Hasher for Fnv1aHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ u64::from(byte)).wrapping_mul(Self::PRIME);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Writer that feeds everything written to it into a hasher.
+///
+/// Lets output be compared by hash without keeping it in memory. Bytes
+/// are passed to `Hasher::write` as they arrive; with the default
+/// [`Fnv1aHasher`] the result doesn't depend on how writes are chunked
+/// and is the same on every build.
+pub struct HashingWriter<H: Hasher = Fnv1aHasher> {
+    hasher: H,
+}
+
+impl HashingWriter {
+    /// Creates a writer using [`Fnv1aHasher`].
+    pub fn new() -> Self {
+        Self::with_hasher(Fnv1aHasher::new())
+    }
+}
+
+impl Default for HashingWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<H: Hasher> HashingWriter<H> {
+    /// Creates a writer feeding the given hasher.
+    pub fn with_hasher(hasher: H) -> Self {
+        Self { hasher }
+    }
+
+    /// Returns the hash of everything written so far.
+    pub fn finish(&self) -> u64 {
+        self.hasher.finish()
+    }
+}
+
+impl<H: Hasher> Write for HashingWriter<H> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.hasher.write(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Writer that copies everything written to it to several sinks.
+///
+/// Pass it as a program's output to send that output to more than one
+/// place, e.g. `TeeWriter::new(vec![&mut a as &mut dyn Write, &mut b])`.
+/// A failed write or flush on any sink fails the whole operation.
+pub struct TeeWriter<W: Write> {
+    sinks: Vec<W>,
+}
+
+impl<W: Write> TeeWriter<W> {
+    /// Creates a writer copying to each of the given sinks, in order.
+    pub fn new(sinks: Vec<W>) -> Self {
+        Self { sinks }
+    }
+
+    /// Returns the sinks.
+    pub fn into_inner(self) -> Vec<W> {
+        self.sinks
+    }
+}
+
+impl<W: Write> Write for TeeWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        for sink in &mut self.sinks {
+            sink.write_all(buf)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        for sink in &mut self.sinks {
+            sink.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Reader that yields the same bytes over and over.
+///
+/// Feeds periodic input to long-running programs. With `times` set,
+/// the reader is at EOF once the bytes have been yielded that many
+/// times; with None it never is. An empty buffer is at EOF right away.
+pub struct RepeatReader {
+    data: Vec<u8>,
+    pos: usize,
+    remaining: Option<usize>,
+}
+
+impl RepeatReader {
+    /// Creates a reader cycling over `data`, `times` times or forever.
+    pub fn new(data: Vec<u8>, times: Option<usize>) -> Self {
+        Self {
+            data,
+            pos: 0,
+            remaining: times,
+        }
+    }
+}
+
+impl Read for RepeatReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.data.is_empty() || self.remaining == Some(0) {
+            return Ok(0);
+        }
+        let n = buf.len().min(self.data.len() - self.pos);
+        buf[..n].copy_from_slice(&self.data[self.pos..self.pos + n]);
+        self.pos += n;
+        if self.pos == self.data.len() {
+            self.pos = 0;
+            if let Some(remaining) = &mut self.remaining {
+                *remaining -= 1;
+            }
+        }
+        Ok(n)
+    }
+}
+
+// Reader that counts the bytes read through it.
+pub(crate) struct CountingReader<R: Read> {
+    pub(crate) inner: R,
+    pub(crate) count: usize,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count += n;
+        Ok(n)
+    }
+}
+
+// Writer that counts the bytes written through it.
+pub(crate) struct CountingWriter<W: Write> {
+    pub(crate) inner: W,
+    pub(crate) count: usize,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count += n;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hashing_writer_chunking() {
+        let mut a = HashingWriter::new();
+        a.write_all(b"hello world").unwrap();
+        let mut b = HashingWriter::new();
+        b.write_all(b"hello").unwrap();
+        b.write_all(b" ").unwrap();
+        b.write_all(b"world").unwrap();
+        assert_eq!(a.finish(), b.finish());
+
+        let mut c = HashingWriter::new();
+        c.write_all(b"hello worle").unwrap();
+        assert_ne!(a.finish(), c.finish());
+    }
+
+    #[test]
+    fn test_hashing_writer_fnv1a() {
+        // Published FNV-1a test vectors, so stored hashes stay valid
+        let hash = |bytes: &[u8]| {
+            let mut w = HashingWriter::new();
+            w.write_all(bytes).unwrap();
+            w.finish()
+        };
+        assert_eq!(hash(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(hash(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(hash(b"foobar"), 0x8594_4171_f739_67e8);
+    }
+
+    #[test]
+    fn test_tee_writer() {
+        let mut a = Vec::new();
+        let mut b = Vec::new();
+        let mut tee = TeeWriter::new(vec![&mut a as &mut dyn Write, &mut b]);
+        tee.write_all(b"hello").unwrap();
+        tee.write_all(b" world").unwrap();
+        tee.flush().unwrap();
+        drop(tee);
+        assert_eq!(a, b"hello world");
+        assert_eq!(b, a);
+    }
+
+    #[test]
+    fn test_repeat_reader() {
+        let mut output = Vec::new();
+        RepeatReader::new(b"ab".to_vec(), Some(3))
+            .read_to_end(&mut output)
+            .unwrap();
+        assert_eq!(output, b"ababab");
+
+        let mut output = [0; 10];
+        RepeatReader::new(b"xyz".to_vec(), None)
+            .read_exact(&mut output)
+            .unwrap();
+        assert_eq!(&output, b"xyzxyzxyzx");
+
+        let mut output = Vec::new();
+        RepeatReader::new(Vec::new(), None)
+            .read_to_end(&mut output)
+            .unwrap();
+        assert!(output.is_empty());
+        RepeatReader::new(b"ab".to_vec(), Some(0))
+            .read_to_end(&mut output)
+            .unwrap();
+        assert!(output.is_empty());
+    }
+}
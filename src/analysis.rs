@@ -0,0 +1,269 @@
+use crate::{CellUsage, Op};
+use std::collections::BTreeMap;
+
+fn touch(usage: &mut CellUsage, offset: i32) {
+    usage.min_offset = usage.min_offset.min(offset);
+    usage.max_offset = usage.max_offset.max(offset);
+}
+
+// Walks the loop-free prefix of the program, tracking the pointer
+// offset relative to where execution starts. Stops at the first op
+// whose effect on the pointer depends on tape contents.
+pub(crate) fn cell_usage(ops: &[Op]) -> CellUsage {
+    let mut usage = CellUsage::default();
+    let mut offset: i32 = 0;
+
+    for op in ops {
+        match op {
+            Op::Add(_) | Op::Set(_) | Op::In => {
+                touch(&mut usage, offset);
+                usage.written.insert(offset);
+            }
+            Op::Out => touch(&mut usage, offset),
+            Op::Debug => {}
+            Op::Move(n) => {
+                let Some(new_offset) = offset.checked_add(*n) else {
+                    break;
+                };
+                offset = new_offset;
+                touch(&mut usage, offset);
+            }
+            Op::Mul(target, _) => {
+                let Some(target) = offset.checked_add(*target) else {
+                    break;
+                };
+                touch(&mut usage, offset);
+                touch(&mut usage, target);
+                usage.written.insert(target);
+            }
+            Op::Open(_) | Op::Close(_) | Op::Scan(_) | Op::Halt => break,
+        }
+    }
+
+    usage
+}
+
+// Finds the highest offset the program can touch, relative to where
+// execution starts. Loops whose body ends where it started touch the
+// same cells on every iteration, so they are walked once. Loops that
+// drift and scans can reach any cell, which gives None.
+pub(crate) fn max_offset(ops: &[Op]) -> Option<i64> {
+    let mut offset: i64 = 0;
+    let mut max: i64 = 0;
+    let mut loop_starts = Vec::new();
+
+    for op in ops {
+        match op {
+            Op::Move(n) => {
+                offset += *n as i64;
+                max = max.max(offset);
+            }
+            Op::Mul(target, _) => max = max.max(offset + *target as i64),
+            Op::Open(_) => loop_starts.push(offset),
+            Op::Close(_) => {
+                if loop_starts.pop() != Some(offset) {
+                    return None;
+                }
+            }
+            Op::Scan(_) => return None,
+            Op::Add(_) | Op::Set(_) | Op::Out | Op::In | Op::Halt | Op::Debug => {}
+        }
+    }
+    Some(max)
+}
+
+// A run of Add, Move and Set ops. Offsets are relative to the pointer
+// on entry and cover every cell the run touches.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Block {
+    pub(crate) end: usize,
+    pub(crate) min_offset: i64,
+    pub(crate) max_offset: i64,
+}
+
+// Finds straight-line runs with more than one Move, where checking the
+// bounds once on entry saves work. The result is indexed by op, with
+// each run's block stored at its first op.
+pub(crate) fn straight_line_blocks(ops: &[Op]) -> Vec<Option<Block>> {
+    let mut blocks = vec![None; ops.len()];
+    let mut start = 0;
+    while start < ops.len() {
+        let (block, moves) = scan_block(ops, start);
+        if moves > 1 {
+            blocks[start] = Some(block);
+        }
+        start = block.end + 1;
+    }
+    blocks
+}
+
+// The block starting at start, if the ops there still make one. Ops
+// can be edited after blocks are found, so a block is checked against
+// them before it is run.
+pub(crate) fn block_at(ops: &[Op], start: usize) -> Option<Block> {
+    let (block, moves) = scan_block(ops, start);
+    (moves > 1).then_some(block)
+}
+
+// The longest straight-line run from start, and the number of Moves in
+// it.
+fn scan_block(ops: &[Op], start: usize) -> (Block, usize) {
+    let mut end = start;
+    let mut offset: i64 = 0;
+    let mut min_offset: i64 = 0;
+    let mut max_offset: i64 = 0;
+    let mut moves = 0;
+    while let Some(op) = ops.get(end) {
+        match op {
+            Op::Add(_) | Op::Set(_) => {}
+            Op::Move(n) => {
+                offset += *n as i64;
+                min_offset = min_offset.min(offset);
+                max_offset = max_offset.max(offset);
+                moves += 1;
+            }
+            _ => break,
+        }
+        end += 1;
+    }
+    let block = Block {
+        end,
+        min_offset,
+        max_offset,
+    };
+    (block, moves)
+}
+
+// Matches the cat program ,[.,] as compiled.
+pub(crate) fn is_cat(ops: &[Op]) -> bool {
+    ops == [Op::In, Op::Open(4), Op::Out, Op::In, Op::Close(1)]
+}
+
+// Counts ops by kind, keyed on the variant name.
+pub(crate) fn op_histogram(ops: &[Op]) -> BTreeMap<&'static str, usize> {
+    let mut histogram = BTreeMap::new();
+    for op in ops {
+        let name = match op {
+            Op::Add(_) => "Add",
+            Op::Move(_) => "Move",
+            Op::Out => "Out",
+            Op::In => "In",
+            Op::Open(_) => "Open",
+            Op::Close(_) => "Close",
+            Op::Set(_) => "Set",
+            Op::Mul(_, _) => "Mul",
+            Op::Scan(_) => "Scan",
+            Op::Halt => "Halt",
+            Op::Debug => "Debug",
+        };
+        *histogram.entry(name).or_insert(0) += 1;
+    }
+    histogram
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compile::compile;
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn test_straight_line_blocks() {
+        let (ops, _) = compile(",>+>>-<<<<.[>-]>>>+<").unwrap();
+        let blocks = straight_line_blocks(&ops);
+        assert_eq!(blocks.len(), ops.len());
+        assert_eq!(
+            blocks[1],
+            Some(Block {
+                end: 6,
+                min_offset: -1,
+                max_offset: 3,
+            })
+        );
+        // Loop bodies are runs too, but a single move isn't worth it
+        assert_eq!(ops[8], Op::Move(1));
+        assert!(blocks[8].is_none());
+        assert_eq!(
+            blocks[11],
+            Some(Block {
+                end: 14,
+                min_offset: 0,
+                max_offset: 3,
+            })
+        );
+        let starts: Vec<usize> = (0..ops.len()).filter(|&i| blocks[i].is_some()).collect();
+        assert_eq!(starts, vec![1, 11]);
+    }
+
+    fn usage(source: &str) -> CellUsage {
+        let (ops, _) = compile(source).unwrap();
+        cell_usage(&ops)
+    }
+
+    #[test]
+    fn test_cell_usage_straight_line() {
+        assert_eq!(
+            usage(">>+<."),
+            CellUsage {
+                min_offset: 0,
+                max_offset: 2,
+                written: BTreeSet::from([2]),
+            }
+        );
+
+        assert_eq!(
+            usage("<<,>>>-"),
+            CellUsage {
+                min_offset: -2,
+                max_offset: 1,
+                written: BTreeSet::from([-2, 1]),
+            }
+        );
+
+        assert_eq!(usage(""), CellUsage::default());
+    }
+
+    // Mul ops come from loops, but are straight-line themselves.
+    #[test]
+    fn test_cell_usage_mul() {
+        assert_eq!(
+            usage("+[->>+++<<]"),
+            CellUsage {
+                min_offset: 0,
+                max_offset: 2,
+                written: BTreeSet::from([0, 2]),
+            }
+        );
+    }
+
+    // Analysis stops at the first loop or scan.
+    #[test]
+    fn test_cell_usage_stops_at_loop() {
+        assert_eq!(
+            usage(">+[>.<-]>>>>+"),
+            CellUsage {
+                min_offset: 0,
+                max_offset: 1,
+                written: BTreeSet::from([1]),
+            }
+        );
+
+        assert_eq!(
+            usage(">+<[>]>>>>+"),
+            CellUsage {
+                min_offset: 0,
+                max_offset: 1,
+                written: BTreeSet::from([1]),
+            }
+        );
+    }
+
+    #[test]
+    fn test_is_cat() {
+        assert!(is_cat(&compile(",[.,]").unwrap().0));
+        assert!(is_cat(&compile("cat: ,[.,]\n").unwrap().0));
+        assert!(!is_cat(&compile(",[.,]+").unwrap().0));
+        assert!(!is_cat(&compile(",[..,]").unwrap().0));
+        assert!(!is_cat(&compile(">,[.,]").unwrap().0));
+    }
+}
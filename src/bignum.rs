@@ -0,0 +1,149 @@
+use crate::wide::{compile_plain, run_plain, Cell, PlainOp};
+use crate::{CompileError, Config, ExecutionError, InputEncoding, Span};
+use num_bigint::BigInt;
+use std::io::{Read, Write};
+
+/// A brainfuck program run on cells holding arbitrary-precision
+/// integers, which never wrap.
+///
+/// `,` stores the byte read and `.` writes the low byte of the cell,
+/// taking negative values in two's complement. Clear loops like `[-]`
+/// count down one step at a time, and never end on a negative cell.
+#[derive(Clone, Debug)]
+pub struct BigProgram {
+    ops: Vec<PlainOp>,
+    spans: Vec<Span>,
+}
+
+/// State of the machine after running a [`BigProgram`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct BigExecutionResult {
+    pub tape: Vec<BigInt>,
+    pub pointer: usize,
+}
+
+impl BigProgram {
+    /// Compiles source, ignoring bytes that aren't commands.
+    pub fn from_source(source: &str) -> Result<Self, CompileError> {
+        let (ops, spans) = compile_plain(source)?;
+        Ok(Self { ops, spans })
+    }
+
+    /// Runs the program on a zeroed tape of `config.tape_size` cells.
+    ///
+    /// Uses the tape size, op limit and limit action, EOF behavior and
+    /// output flushing from the config. Under
+    /// [`EofBehavior::MaxValue`](crate::EofBehavior::MaxValue)
+    /// the cell is set to 255. Other settings only apply to 8-bit cells
+    /// or aren't supported here, and are ignored.
+    pub fn run(
+        &self,
+        config: &Config,
+        input: Option<&mut dyn Read>,
+        output: Option<&mut dyn Write>,
+    ) -> Result<BigExecutionResult, ExecutionError> {
+        let encoding = InputEncoding::Bytes;
+        let (tape, pointer) = run_plain(&self.ops, &self.spans, config, encoding, input, output)?;
+        Ok(BigExecutionResult { tape, pointer })
+    }
+}
+
+impl Cell for BigInt {
+    fn max_value() -> Self {
+        BigInt::from(255)
+    }
+
+    fn add(&mut self, n: i64) {
+        *self += n;
+    }
+
+    fn from_input(value: u32) -> Self {
+        BigInt::from(value)
+    }
+
+    // Negative values are taken in two's complement
+    fn low_byte(&self) -> u8 {
+        u8::try_from(self & BigInt::from(0xff)).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(source: &str, input: &[u8]) -> (BigExecutionResult, Vec<u8>) {
+        let program = BigProgram::from_source(source).unwrap();
+        let config = Config {
+            tape_size: 8,
+            ..Default::default()
+        };
+        let mut input = input;
+        let mut output = Vec::new();
+        let result = program
+            .run(&config, Some(&mut input), Some(&mut output))
+            .unwrap();
+        (result, output)
+    }
+
+    #[test]
+    fn test_no_wrapping() {
+        let (result, output) = run(&"+".repeat(300), b"");
+        assert_eq!(result.tape[0], BigInt::from(300));
+        assert!(output.is_empty());
+
+        let (result, _) = run("-->-", b"");
+        assert_eq!(result.tape[..2], [BigInt::from(-2), BigInt::from(-1)]);
+    }
+
+    #[test]
+    fn test_loops() {
+        // 300 * 300 in cell 2, counting cell 0 down to zero
+        let source = format!("{}[->{}<]>[->+>+<<]>>.", "+".repeat(300), "+".repeat(300));
+        let (result, output) = run(&source, b"");
+        assert_eq!(result.tape[0], BigInt::ZERO);
+        assert_eq!(result.tape[2], BigInt::from(90000));
+        assert_eq!(result.tape[3], BigInt::from(90000));
+        assert_eq!(result.pointer, 3);
+        // Only the low byte is written
+        assert_eq!(output, [(90000 % 256) as u8]);
+    }
+
+    #[test]
+    fn test_io() {
+        let (result, output) = run(",.,.,", b"AB");
+        assert_eq!(output, b"AB");
+        assert_eq!(result.tape[0], BigInt::ZERO);
+
+        // Negative cells write their two's complement low byte
+        let (_, output) = run("-.", b"");
+        assert_eq!(output, [255]);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(matches!(
+            BigProgram::from_source("[+"),
+            Err(CompileError::UnmatchedOpen { .. })
+        ));
+        assert!(matches!(
+            BigProgram::from_source("+]"),
+            Err(CompileError::UnmatchedClose { .. })
+        ));
+
+        let program = BigProgram::from_source("+\n<").unwrap();
+        let result = program.run(&Config::default(), None, None);
+        assert!(matches!(
+            result,
+            Err(ExecutionError::PointerUnderflow { span, .. }) if span.line == 2
+        ));
+
+        // Counting down from -1 never reaches zero
+        let program = BigProgram::from_source("-[-]").unwrap();
+        let config = Config {
+            op_limit: Some(1000),
+            ..Default::default()
+        };
+        let result = program.run(&config, None, None);
+        assert!(matches!(result, Err(ExecutionError::OperationLimit { .. })));
+    }
+}
@@ -0,0 +1,283 @@
+use crate::lines::{LineIndex, SourceRange, Spans};
+use crate::{execute, BytecodeError, ExecutionError, Op, OpOrigin, Program};
+
+// A compiled program is stored as the magic bytes and a version,
+// followed by the op count and, for each op, a tag byte with its
+// operands, its origin and the start and end of its span as u32. Then
+// come the count of source lines and where each starts, as u32, and the
+// count of source fragments and where each starts. Numbers are
+// little-endian, with counts and fragment starts as u64.
+const MAGIC: &[u8; 4] = b"BFBC";
+const VERSION: u8 = 1;
+
+pub(crate) fn to_bytes(program: &Program) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(29 + program.ops.len() * 16);
+    bytes.extend_from_slice(MAGIC);
+    bytes.push(VERSION);
+    bytes.extend_from_slice(&(program.ops.len() as u64).to_le_bytes());
+    // Ops pushed without a span are saved with an empty one where the
+    // op before them ends
+    let mut range = SourceRange { start: 0, end: 0 };
+    for (i, op) in program.ops.iter().enumerate() {
+        range = program.ranges.get(i).copied().unwrap_or(SourceRange {
+            start: range.end,
+            end: range.end,
+        });
+        match *op {
+            Op::Add(n) => bytes.extend_from_slice(&[0, n]),
+            Op::Move(n) => {
+                bytes.push(1);
+                bytes.extend_from_slice(&n.to_le_bytes());
+            }
+            Op::Out => bytes.push(2),
+            Op::In => bytes.push(3),
+            Op::Open(target) => {
+                bytes.push(4);
+                bytes.extend_from_slice(&target.to_le_bytes());
+            }
+            Op::Close(target) => {
+                bytes.push(5);
+                bytes.extend_from_slice(&target.to_le_bytes());
+            }
+            Op::Set(n) => bytes.extend_from_slice(&[6, n]),
+            Op::Mul(offset, factor) => {
+                bytes.push(7);
+                bytes.extend_from_slice(&offset.to_le_bytes());
+                bytes.push(factor);
+            }
+            Op::Scan(step) => {
+                bytes.push(8);
+                bytes.extend_from_slice(&step.to_le_bytes());
+            }
+            Op::Halt => bytes.push(9),
+            Op::Debug => bytes.push(10),
+        }
+        bytes.push(match program.origins.get(i).copied() {
+            None | Some(OpOrigin::Direct) => 0,
+            Some(OpOrigin::MulLoop) => 1,
+            Some(OpOrigin::ScanLoop) => 2,
+            Some(OpOrigin::ClearLoop) => 3,
+        });
+        bytes.extend_from_slice(&range.start.to_le_bytes());
+        bytes.extend_from_slice(&range.end.to_le_bytes());
+    }
+    let line_starts = program.lines.starts();
+    bytes.extend_from_slice(&(line_starts.len() as u64).to_le_bytes());
+    for &start in line_starts {
+        bytes.extend_from_slice(&start.to_le_bytes());
+    }
+    bytes.extend_from_slice(&(program.fragment_starts.len() as u64).to_le_bytes());
+    for &start in &program.fragment_starts {
+        bytes.extend_from_slice(&(start as u64).to_le_bytes());
+    }
+    bytes
+}
+
+// Reads fixed-size fields in order, failing once the bytes run out.
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl Reader<'_> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], BytecodeError> {
+        let field = self
+            .bytes
+            .get(self.offset..self.offset + N)
+            .ok_or(BytecodeError::Truncated)?;
+        self.offset += N;
+        Ok(field.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8, BytecodeError> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    fn i32(&mut self) -> Result<i32, BytecodeError> {
+        Ok(i32::from_le_bytes(self.take()?))
+    }
+
+    fn u32(&mut self) -> Result<u32, BytecodeError> {
+        Ok(u32::from_le_bytes(self.take()?))
+    }
+
+    fn usize(&mut self) -> Result<usize, BytecodeError> {
+        let offset = self.offset;
+        usize::try_from(u64::from_le_bytes(self.take()?))
+            .map_err(|_| BytecodeError::Invalid { offset })
+    }
+}
+
+pub(crate) fn from_bytes(bytes: &[u8]) -> Result<Program, BytecodeError> {
+    if !bytes.starts_with(MAGIC) {
+        return Err(BytecodeError::NotBytecode);
+    }
+    let mut reader = Reader { bytes, offset: 4 };
+    let version = reader.u8()?;
+    if version != VERSION {
+        return Err(BytecodeError::UnsupportedVersion(version));
+    }
+    let count = reader.usize()?;
+    // Every op takes at least 10 bytes, so a count that can't fit is
+    // refused before allocating for it
+    if count > bytes.len() / 10 {
+        return Err(BytecodeError::Truncated);
+    }
+
+    let mut ops = Vec::with_capacity(count);
+    let mut ranges = Vec::with_capacity(count);
+    let mut origins = Vec::with_capacity(count);
+    for _ in 0..count {
+        let offset = reader.offset;
+        let op = match reader.u8()? {
+            0 => Op::Add(reader.u8()?),
+            1 => Op::Move(reader.i32()?),
+            2 => Op::Out,
+            3 => Op::In,
+            4 => Op::Open(reader.u32()?),
+            5 => Op::Close(reader.u32()?),
+            6 => Op::Set(reader.u8()?),
+            7 => Op::Mul(reader.i32()?, reader.u8()?),
+            8 => Op::Scan(reader.i32()?),
+            9 => Op::Halt,
+            10 => Op::Debug,
+            _ => return Err(BytecodeError::Invalid { offset }),
+        };
+        let offset = reader.offset;
+        origins.push(match reader.u8()? {
+            0 => OpOrigin::Direct,
+            1 => OpOrigin::MulLoop,
+            2 => OpOrigin::ScanLoop,
+            3 => OpOrigin::ClearLoop,
+            _ => return Err(BytecodeError::Invalid { offset }),
+        });
+        ops.push(op);
+        ranges.push(SourceRange {
+            start: reader.u32()?,
+            end: reader.u32()?,
+        });
+    }
+    let offset = reader.offset;
+    let line_count = reader.usize()?;
+    if line_count > bytes.len() / 4 {
+        return Err(BytecodeError::Truncated);
+    }
+    let line_starts = (0..line_count)
+        .map(|_| reader.u32())
+        .collect::<Result<Vec<_>, _>>()?;
+    let lines = LineIndex::from_starts(line_starts).ok_or(BytecodeError::Invalid { offset })?;
+    let fragments = reader.usize()?;
+    if fragments > bytes.len() / 8 {
+        return Err(BytecodeError::Truncated);
+    }
+    let fragment_starts = (0..fragments)
+        .map(|_| reader.usize())
+        .collect::<Result<Vec<_>, _>>()?;
+    if reader.offset != bytes.len() {
+        return Err(BytecodeError::Invalid {
+            offset: reader.offset,
+        });
+    }
+    // Loaded ops are run without further checks
+    if let Err(ExecutionError::InvalidOps { index, reason }) =
+        execute::validate(&ops, Spans::Compact(&ranges, &lines))
+    {
+        return Err(BytecodeError::InvalidOps { index, reason });
+    }
+
+    let mut program = Program::from_parts(ops, ranges, lines, Vec::new(), origins);
+    program.fragment_starts = fragment_starts;
+    Ok(program)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let sources = ["", "+++[>+++[>++<-]<-]>>.,[.,]", ",[->+>---<<]>[<]>>[-]#!"];
+        for source in sources {
+            let options = crate::CompileOptions {
+                debug_char: Some(b'#'),
+                halt_char: Some(b'!'),
+                ..Default::default()
+            };
+            let program = Program::from_source_with_options(source, &options).unwrap();
+            let loaded = from_bytes(&to_bytes(&program)).unwrap();
+            assert_eq!(loaded.ops, program.ops);
+            assert!(loaded.spans().eq(program.spans()));
+            assert_eq!(loaded.origins, program.origins);
+        }
+
+        let program = Program::from_fragments(&["+[>]", "<[-]"]).unwrap();
+        let loaded = from_bytes(&to_bytes(&program)).unwrap();
+        assert!(loaded.spans().eq(program.spans()));
+        assert_eq!(loaded.fragment_of(&loaded.spans().last().unwrap()), 1);
+
+        // Ops without a span are kept, with an empty one
+        let mut program = Program::from_source("+[>]\n-").unwrap();
+        program.ops.extend([Op::Move(1), Op::Add(3)]);
+        let loaded = from_bytes(&to_bytes(&program)).unwrap();
+        assert_eq!(loaded.ops, program.ops);
+        let spans: Vec<_> = loaded.spans().map(|span| (span.start, span.end)).collect();
+        assert_eq!(spans, [(0, 1), (1, 4), (5, 6), (6, 6), (6, 6)]);
+        assert_eq!((loaded.span(4).line, loaded.span(4).col), (2, 2));
+    }
+
+    #[test]
+    fn test_invalid_bytes() {
+        let bytes = to_bytes(&Program::from_source("+[>+<-].").unwrap());
+
+        assert_eq!(
+            from_bytes(b"+[>+<-].").unwrap_err(),
+            BytecodeError::NotBytecode
+        );
+        let mut newer = bytes.clone();
+        newer[4] = 2;
+        assert_eq!(
+            from_bytes(&newer).unwrap_err(),
+            BytecodeError::UnsupportedVersion(2)
+        );
+        for len in [5, 13, 20, bytes.len() - 1] {
+            assert_eq!(
+                from_bytes(&bytes[..len]).unwrap_err(),
+                BytecodeError::Truncated
+            );
+        }
+        let mut longer = bytes.clone();
+        longer.push(0);
+        assert_eq!(
+            from_bytes(&longer).unwrap_err(),
+            BytecodeError::Invalid {
+                offset: bytes.len()
+            }
+        );
+        let mut bad_tag = bytes.clone();
+        bad_tag[13] = 99;
+        assert_eq!(
+            from_bytes(&bad_tag).unwrap_err(),
+            BytecodeError::Invalid { offset: 13 }
+        );
+        // The single line of the source starting past 0
+        let lines = bytes.len() - 20;
+        let mut bad_lines = bytes.clone();
+        bad_lines[lines + 8] = 1;
+        assert_eq!(
+            from_bytes(&bad_lines).unwrap_err(),
+            BytecodeError::Invalid { offset: lines }
+        );
+
+        // A loop whose close points elsewhere
+        let mut program = Program::from_source("[-][-]").unwrap();
+        program.ops = vec![Op::Open(1), Op::Close(0), Op::Open(1), Op::Close(2)];
+        program.ranges = vec![program.ranges[0]; 4];
+        assert_eq!(
+            from_bytes(&to_bytes(&program)).unwrap_err(),
+            BytecodeError::InvalidOps {
+                index: 2,
+                reason: "open does not point at a matching close"
+            }
+        );
+    }
+}
@@ -1,4 +1,8 @@
-use crate::{CompileError, Op, Span};
+use crate::lines::{LineIndex, SourceRange, Spans};
+use crate::{
+    CompileError, CompileOptions, Diagnostic, DiagnosticKind, Op, OpOrigin, Program, Span,
+};
+use std::collections::HashMap;
 
 // Appends an op, and "compacts" it with previous ops if possible.
 fn push_and_compact(ops: &mut Vec<Op>, spans: &mut Vec<Span>, op: Op, span: Span) {
@@ -44,6 +48,24 @@ fn push_and_compact(ops: &mut Vec<Op>, spans: &mut Vec<Span>, op: Op, span: Span
             *ops.last_mut().unwrap() = Op::Set(b);
             s.end = span.end;
         }
+        // Scan + Set(0): the scan already stopped on a zero cell
+        (Some(Op::Scan(_)), _, Op::Set(0)) => {}
+        // Set(0) + Scan: the scan stops before it moves
+        (Some(Op::Set(0)), _, Op::Scan(_)) => {}
+        // Scan + Add: the cell is known to be zero
+        (Some(Op::Scan(_)), _, Op::Add(b)) => {
+            ops.push(Op::Set(b));
+            spans.push(span);
+        }
+        // Set overwriting an earlier store
+        (_, _, Op::Set(b)) => {
+            if remove_dead_store(ops, spans) {
+                push_and_compact(ops, spans, Op::Set(b), span);
+            } else {
+                ops.push(Op::Set(b));
+                spans.push(span);
+            }
+        }
         (_, _, op) => {
             ops.push(op);
             spans.push(span);
@@ -51,6 +73,40 @@ fn push_and_compact(ops: &mut Vec<Op>, spans: &mut Vec<Span>, op: Op, span: Span
     }
 }
 
+// Removes a store that a Set about to be pushed would overwrite.
+//
+// Walks back while the pointer position is statically known, i.e. past
+// Moves and stores to other cells. The first Add or Set to the current
+// cell found this way is dead. Anything that may read the cell, I/O,
+// and loops end the search, as does walking past DEAD_STORE_WINDOW ops,
+// which keeps long runs of straight-line code from compiling in
+// quadratic time. The ops after the dead store are pushed again so
+// that they compact with what precedes it.
+const DEAD_STORE_WINDOW: usize = 64;
+
+fn remove_dead_store(ops: &mut Vec<Op>, spans: &mut Vec<Span>) -> bool {
+    let mut rel: i64 = 0;
+    for i in (ops.len().saturating_sub(DEAD_STORE_WINDOW)..ops.len()).rev() {
+        match ops[i] {
+            Op::Move(n) => rel -= n as i64,
+            Op::Add(_) | Op::Set(_) if rel == 0 => {
+                let tail_ops = ops.split_off(i + 1);
+                let tail_spans = spans.split_off(i + 1);
+                ops.pop();
+                spans.pop();
+                for (op, span) in tail_ops.into_iter().zip(tail_spans) {
+                    push_and_compact(ops, spans, op, span);
+                }
+                return true;
+            }
+            Op::Add(_) | Op::Set(_) => {}
+            Op::Mul(offset, _) if rel != 0 && rel + offset as i64 != 0 => {}
+            _ => return false,
+        }
+    }
+    false
+}
+
 // Checks for "multiplication loops".
 //
 // If a loop holds only Move and Add, and it returns pointer to the
@@ -59,7 +115,7 @@ fn push_and_compact(ops: &mut Vec<Op>, spans: &mut Vec<Span>, op: Op, span: Span
 // Set(0).
 fn try_mul_loop(ops: &[Op]) -> Option<Vec<(i32, u8)>> {
     let mut offset: i32 = 0;
-    let mut muls = Vec::new();
+    let mut muls: Vec<(i32, u8)> = Vec::new();
     let mut origin_delta: u8 = 0;
 
     for op in ops {
@@ -67,6 +123,10 @@ fn try_mul_loop(ops: &[Op]) -> Option<Vec<(i32, u8)>> {
             Op::Add(n) => {
                 if offset == 0 {
                     origin_delta = origin_delta.wrapping_add(*n);
+                } else if let Some((_, factor)) = muls.iter_mut().find(|(o, _)| *o == offset) {
+                    // Same target again: one Mul with the summed factor,
+                    // kept where the target was first written
+                    *factor = factor.wrapping_add(*n);
                 } else {
                     muls.push((offset, *n));
                 }
@@ -77,154 +137,420 @@ fn try_mul_loop(ops: &[Op]) -> Option<Vec<(i32, u8)>> {
     }
 
     if offset == 0 && origin_delta == 255 {
+        muls.retain(|&(_, factor)| factor != 0);
         Some(muls)
     } else {
         None
     }
 }
 
-// Skips past a loop. Useful when a dead loop has been found.
-// Returns (new position, lines skipped, final column offset).
-fn skip_loop(source: &[u8], start: usize) -> (usize, usize, usize) {
-    let mut depth = 1;
-    let mut i = start;
-    let mut lines = 0;
-    let mut col = 0;
-    while i < source.len() && depth > 0 {
-        match source[i] {
-            b'[' => depth += 1,
-            b']' => depth -= 1,
-            b'\n' => {
-                lines += 1;
-                col = 0;
-            }
-            _ => {}
+// Returns true if a loop holds only Move and Add, with at least one
+// Add, and doesn't return the pointer to the original cell. Scans and
+// loops doing I/O or containing other loops aren't considered.
+fn is_unbalanced_loop(ops: &[Op]) -> bool {
+    let mut offset: i64 = 0;
+    let mut adds = false;
+    for op in ops {
+        match op {
+            Op::Add(_) => adds = true,
+            Op::Move(n) => offset += *n as i64,
+            _ => return false,
         }
-        col += 1;
-        i += 1;
     }
-    (i, lines, col)
+    adds && offset != 0
 }
 
-pub(crate) fn compile(source: &str) -> Result<(Vec<Op>, Vec<Span>), CompileError> {
-    let mut ops = Vec::new();
-    let mut spans = Vec::new();
-    let mut loop_stack: Vec<(usize, Span)> = Vec::new(); // (ops index, loop start span)
-    let source = source.as_bytes();
-    let mut i = 0;
-    let mut line = 1usize;
-    let mut col = 1usize;
-
-    while i < source.len() {
+// Compiler state. Source is fed one byte at a time, so a program can
+// be compiled incrementally as it is appended to.
+pub(crate) struct Compiler {
+    options: CompileOptions,
+    ops: Vec<Op>,
+    spans: Vec<Span>,
+    loop_stack: Vec<(usize, Span)>, // (ops index, loop start span)
+    // When skipping a dead loop: its start span and current nesting depth.
+    dead_loop: Option<(Span, usize)>,
+    in_comment: bool,
+    // Current run of identical +/- characters: (char, span, length).
+    add_run: Option<(u8, Span, usize)>,
+    diagnostics: Vec<Diagnostic>,
+    // Kind of each loop the optimizer rewrote, keyed by the position of
+    // its `[`. Ops it became carry a span starting there.
+    loop_origins: HashMap<usize, OpOrigin>,
+    lines: LineIndex,
+    pos: usize,
+    line: usize,
+    col: usize,
+}
+
+impl Compiler {
+    pub(crate) fn new(options: &CompileOptions) -> Self {
+        Self {
+            options: options.clone(),
+            ops: Vec::new(),
+            spans: Vec::new(),
+            loop_stack: Vec::new(),
+            dead_loop: None,
+            in_comment: false,
+            add_run: None,
+            diagnostics: Vec::new(),
+            loop_origins: HashMap::new(),
+            lines: LineIndex::default(),
+            pos: 0,
+            line: 1,
+            col: 1,
+        }
+    }
+
+    // Compiles one byte of source. On error the byte is not consumed.
+    pub(crate) fn push_byte(&mut self, byte: u8) -> Result<(), CompileError> {
+        // Programs keep source offsets as u32
+        if self.pos >= u32::MAX as usize {
+            return Err(CompileError::SourceTooLarge {
+                size: self.pos + 1,
+                limit: u32::MAX as usize,
+            });
+        }
         let span = Span {
-            start: i,
-            end: i + 1,
-            line,
-            col,
+            start: self.pos,
+            end: self.pos + 1,
+            line: self.line,
+            col: self.col,
         };
-        match source[i] {
-            b'+' => push_and_compact(&mut ops, &mut spans, Op::Add(1), span),
-            b'-' => push_and_compact(&mut ops, &mut spans, Op::Add(255), span),
-            b'<' => push_and_compact(&mut ops, &mut spans, Op::Move(-1), span),
-            b'>' => push_and_compact(&mut ops, &mut spans, Op::Move(1), span),
-            b'.' => {
-                ops.push(Op::Out);
-                spans.push(span);
+
+        if self.options.strict_chars && !self.in_comment && !self.is_allowed(byte) {
+            return Err(CompileError::UnexpectedChar { span, ch: byte });
+        }
+
+        self.track_add_run(byte, span);
+
+        if self.in_comment {
+            self.in_comment = byte != b'\n';
+        } else if Some(byte) == self.options.line_comment {
+            self.in_comment = true;
+        } else if self.dead_loop.is_none() && Some(byte) == self.options.halt_char {
+            self.ops.push(Op::Halt);
+            self.spans.push(span);
+        } else if self.dead_loop.is_none() && Some(byte) == self.options.debug_char {
+            self.ops.push(Op::Debug);
+            self.spans.push(span);
+        } else if let Some((_, depth)) = &mut self.dead_loop {
+            match byte {
+                b'[' => *depth += 1,
+                b']' => *depth -= 1,
+                _ => {}
             }
-            b',' => {
-                ops.push(Op::In);
-                spans.push(span);
+            if *depth == 0 {
+                self.dead_loop = None;
             }
-            b'[' => {
-                // If previous op is Set(0), Close, or Scan, this loop will
-                // never be entered (current cell is guaranteed to be 0).
-                let is_dead = matches!(
-                    ops.last(),
-                    Some(Op::Set(0)) | Some(Op::Close(_)) | Some(Op::Scan(_))
-                );
-                if is_dead {
-                    let (new_i, lines, new_col) = skip_loop(source, i + 1);
-                    i = new_i;
-                    line += lines;
-                    col = if lines > 0 { new_col } else { col + new_col };
-                    continue;
-                } else {
-                    loop_stack.push((ops.len(), span));
-                    ops.push(Op::Open(0));
+        } else {
+            let ops = &mut self.ops;
+            let spans = &mut self.spans;
+            match byte {
+                b'+' => push_and_compact(ops, spans, Op::Add(1), span),
+                b'-' => push_and_compact(ops, spans, Op::Add(255), span),
+                b'<' => push_and_compact(ops, spans, Op::Move(-1), span),
+                b'>' => push_and_compact(ops, spans, Op::Move(1), span),
+                b'.' => {
+                    ops.push(Op::Out);
                     spans.push(span);
                 }
+                b',' => {
+                    ops.push(Op::In);
+                    spans.push(span);
+                }
+                b'[' => self.open_loop(span),
+                b']' => self.close_loop(span)?,
+                _ => {}
             }
-            b']' => {
-                let Some((start, loop_start_span)) = loop_stack.pop() else {
-                    return Err(CompileError::UnmatchedClose { span });
-                };
-                {
-                    let loop_span = Span {
-                        start: loop_start_span.start,
-                        end: i + 1,
-                        line: loop_start_span.line,
-                        col: loop_start_span.col,
-                    };
-                    let loop_body = &ops[start + 1..];
-                    if let Some(muls) = try_mul_loop(loop_body) {
-                        ops.truncate(start);
-                        spans.truncate(start);
-                        for (offset, factor) in muls {
-                            ops.push(Op::Mul(offset, factor));
-                            spans.push(loop_span);
-                        }
-                        push_and_compact(&mut ops, &mut spans, Op::Set(0), loop_span);
-                        i += 1;
-                        col += 1;
-                        continue;
-                    }
-                    if ops.len() == start + 2 {
-                        if let Some(Op::Move(n)) = ops.last() {
-                            let step = *n;
-                            ops.pop();
-                            ops.pop();
-                            spans.pop();
-                            spans.pop();
-                            ops.push(Op::Scan(step));
-                            spans.push(loop_span);
-                            i += 1;
-                            col += 1;
-                            continue;
-                        }
-                        if let Some(Op::Add(n)) = ops.last() {
-                            if n % 2 == 1 {
-                                ops.pop();
-                                ops.pop();
-                                spans.pop();
-                                spans.pop();
-                                push_and_compact(&mut ops, &mut spans, Op::Set(0), loop_span);
-                                i += 1;
-                                col += 1;
-                                continue;
-                            }
-                        }
-                    }
-                    let end = ops.len();
-                    ops[start] = Op::Open(end as u32);
-                    ops.push(Op::Close(start as u32));
-                    spans.push(loop_span);
+        }
+
+        self.pos += 1;
+        if byte == b'\n' {
+            self.lines.push(self.pos as u32);
+            self.line += 1;
+            self.col = 1;
+        } else {
+            self.col += 1;
+        }
+        Ok(())
+    }
+
+    // Whether a byte may appear outside comments in strict mode.
+    fn is_allowed(&self, byte: u8) -> bool {
+        let options = &self.options;
+        matches!(byte, b'+' | b'-' | b'<' | b'>' | b'.' | b',' | b'[' | b']')
+            || (byte.is_ascii_whitespace() && !options.strict_whitespace)
+            || [options.line_comment, options.halt_char, options.debug_char].contains(&Some(byte))
+    }
+
+    // Warns about runs of + or - that wrap around to no effect. Runs are
+    // ended by any other character.
+    fn track_add_run(&mut self, byte: u8, span: Span) {
+        let counts = !self.in_comment && self.dead_loop.is_none();
+        if let Some((c, run_span, len)) = &mut self.add_run {
+            if counts && *c == byte {
+                *len += 1;
+                run_span.end = span.end;
+                return;
+            }
+        }
+        self.end_add_run();
+        if counts && (byte == b'+' || byte == b'-') {
+            self.add_run = Some((byte, span, 1));
+        }
+    }
+
+    fn end_add_run(&mut self) {
+        if let Some((_, span, len)) = self.add_run.take() {
+            if len % 256 == 0 {
+                self.diagnostics.push(Diagnostic {
+                    kind: DiagnosticKind::NetZeroAdd,
+                    span,
+                });
+            }
+        }
+    }
+
+    fn open_loop(&mut self, span: Span) {
+        // If previous op is Set(0), Close, or Scan, this loop will
+        // never be entered (current cell is guaranteed to be 0). Nor
+        // will one at the start of a program on a zeroed tape.
+        let at_start =
+            self.options.tape_starts_zeroed && self.ops.iter().all(|op| matches!(op, Op::Debug));
+        let is_dead = at_start
+            || matches!(
+                self.ops.last(),
+                Some(Op::Set(0)) | Some(Op::Close(_)) | Some(Op::Scan(_))
+            );
+        if is_dead {
+            self.dead_loop = Some((span, 1));
+        } else {
+            self.loop_stack.push((self.ops.len(), span));
+            self.ops.push(Op::Open(0));
+            self.spans.push(span);
+        }
+    }
+
+    fn close_loop(&mut self, span: Span) -> Result<(), CompileError> {
+        let Some((start, loop_start_span)) = self.loop_stack.pop() else {
+            return Err(CompileError::UnmatchedClose { span });
+        };
+        let ops = &mut self.ops;
+        let spans = &mut self.spans;
+        let loop_span = Span {
+            start: loop_start_span.start,
+            end: span.end,
+            line: loop_start_span.line,
+            col: loop_start_span.col,
+        };
+
+        let loop_body = &ops[start + 1..];
+        if loop_body.is_empty() {
+            self.diagnostics.push(Diagnostic {
+                kind: DiagnosticKind::EmptyLoop,
+                span: loop_start_span,
+            });
+        }
+        if let Some(muls) = try_mul_loop(loop_body) {
+            let origin = if muls.is_empty() {
+                OpOrigin::ClearLoop
+            } else {
+                OpOrigin::MulLoop
+            };
+            self.loop_origins.insert(loop_span.start, origin);
+            ops.truncate(start);
+            spans.truncate(start);
+            for (offset, factor) in muls {
+                ops.push(Op::Mul(offset, factor));
+                spans.push(loop_span);
+            }
+            push_and_compact(ops, spans, Op::Set(0), loop_span);
+            return Ok(());
+        }
+        if ops.len() == start + 2 {
+            if let Some(Op::Move(n)) = ops.last() {
+                let step = *n;
+                self.loop_origins
+                    .insert(loop_span.start, OpOrigin::ScanLoop);
+                ops.truncate(start);
+                spans.truncate(start);
+                push_and_compact(ops, spans, Op::Scan(step), loop_span);
+                return Ok(());
+            }
+            if let Some(Op::Add(n)) = ops.last() {
+                if n % 2 == 1 {
+                    self.loop_origins
+                        .insert(loop_span.start, OpOrigin::ClearLoop);
+                    ops.truncate(start);
+                    spans.truncate(start);
+                    push_and_compact(ops, spans, Op::Set(0), loop_span);
+                    return Ok(());
                 }
+                self.diagnostics.push(Diagnostic {
+                    kind: DiagnosticKind::EvenClearLoop,
+                    span: loop_span,
+                });
             }
-            b'\n' => {
-                line += 1;
-                col = 0; // will be incremented to 1 below
+        }
+        if is_unbalanced_loop(loop_body) {
+            self.diagnostics.push(Diagnostic {
+                kind: DiagnosticKind::UnbalancedLoop,
+                span: loop_span,
+            });
+        }
+        let end = ops.len();
+        ops[start] = Op::Open(end as u32);
+        ops.push(Op::Close(start as u32));
+        spans.push(loop_span);
+        Ok(())
+    }
+
+    // Warns about stores at the end of the program. Moves in between
+    // don't matter, anything else might observe the stores.
+    fn check_trailing_writes(&mut self) {
+        let mut first_write = None;
+        for (i, op) in self.ops.iter().enumerate().rev() {
+            match op {
+                Op::Add(_) | Op::Set(_) | Op::Mul(_, _) => first_write = Some(i),
+                Op::Move(_) => {}
+                _ => break,
             }
-            _ => {}
         }
-        i += 1;
-        col += 1;
+        let Some(first) = first_write else {
+            return;
+        };
+        let last = self.ops[first..]
+            .iter()
+            .rposition(|op| !matches!(op, Op::Move(_)))
+            .unwrap()
+            + first;
+        let (start, end) = (self.spans[first], self.spans[last]);
+        self.diagnostics.push(Diagnostic {
+            kind: DiagnosticKind::TrailingWrites,
+            span: Span {
+                end: end.end,
+                ..start
+            },
+        });
     }
 
-    if let Some((_, span)) = loop_stack.pop() {
-        return Err(CompileError::UnmatchedOpen { span });
+    // Checks that all loops were closed and returns the compiled program.
+    pub(crate) fn finish(mut self) -> Result<Program, CompileError> {
+        if let Some((span, _)) = self.dead_loop {
+            return Err(CompileError::UnmatchedOpen { span });
+        }
+        if let Some((_, span)) = self.loop_stack.pop() {
+            return Err(CompileError::UnmatchedOpen { span });
+        }
+        self.end_add_run();
+        if self.options.tape_unobserved {
+            self.check_trailing_writes();
+        }
+        let origins = self
+            .spans
+            .iter()
+            .map(|span| {
+                let origin = self.loop_origins.get(&span.start);
+                origin.copied().unwrap_or(OpOrigin::Direct)
+            })
+            .collect();
+        let ranges = self.spans.iter().map(SourceRange::of).collect();
+        Ok(Program::from_parts(
+            self.ops,
+            ranges,
+            self.lines,
+            self.diagnostics,
+            origins,
+        ))
+    }
+}
+
+// Compiles each fragment on its own, so that loops can't cross from
+// one to the next, and joins the results. Each compiler carries on
+// from the position the last one reached, so spans are positions in the
+// joined source. Ops aren't compacted across fragment boundaries.
+pub(crate) fn compile_fragments(
+    fragments: &[&str],
+    options: &CompileOptions,
+) -> Result<Program, CompileError> {
+    let mut ops = Vec::new();
+    let mut ranges = Vec::new();
+    let mut diagnostics = Vec::new();
+    let mut origins = Vec::new();
+    let mut fragment_starts = Vec::with_capacity(fragments.len());
+    let mut lines = LineIndex::default();
+    let (mut pos, mut line, mut col) = (0, 1, 1);
+    for (i, fragment) in fragments.iter().enumerate() {
+        let in_fragment = |error| CompileError::InFragment {
+            fragment: i,
+            error: Box::new(error),
+        };
+        let mut compiler = Compiler::new(options);
+        (compiler.pos, compiler.line, compiler.col) = (pos, line, col);
+        compiler.lines = lines;
+        fragment_starts.push(pos);
+        for &byte in fragment.as_bytes() {
+            compiler.push_byte(byte).map_err(in_fragment)?;
+        }
+        (pos, line, col) = (compiler.pos, compiler.line, compiler.col);
+        let compiled = compiler.finish().map_err(in_fragment)?;
+        ops.extend(compiled.ops);
+        ranges.extend(compiled.ranges);
+        diagnostics.extend(compiled.diagnostics);
+        origins.extend(compiled.origins);
+        lines = compiled.lines;
+    }
+    relink_loops(&mut ops, Spans::Compact(&ranges, &lines))?;
+    let mut program = Program::from_parts(ops, ranges, lines, diagnostics, origins);
+    program.fragment_starts = fragment_starts;
+    Ok(program)
+}
+
+// Recomputes Open and Close targets from the bracket structure, ignoring
+// their current values. Ops are left unchanged on error.
+pub(crate) fn relink_loops(ops: &mut [Op], spans: Spans<'_>) -> Result<(), CompileError> {
+    let mut stack = Vec::new();
+    let mut pairs = Vec::new();
+    for (i, op) in ops.iter().enumerate() {
+        match op {
+            Op::Open(_) => stack.push(i),
+            Op::Close(_) => {
+                let Some(open) = stack.pop() else {
+                    return Err(CompileError::UnmatchedClose { span: spans.get(i) });
+                };
+                pairs.push((open, i));
+            }
+            _ => {}
+        }
+    }
+    if let Some(open) = stack.pop() {
+        return Err(CompileError::UnmatchedOpen {
+            span: spans.get(open),
+        });
     }
+    for (open, close) in pairs {
+        ops[open] = Op::Open(close as u32);
+        ops[close] = Op::Close(open as u32);
+    }
+    Ok(())
+}
 
-    Ok((ops, spans))
+#[cfg(test)]
+pub(crate) fn compile(source: &str) -> Result<(Vec<Op>, Vec<Span>), CompileError> {
+    let program = compile_bytes(source.as_bytes(), &CompileOptions::default())?;
+    let spans = program.spans().collect();
+    Ok((program.ops, spans))
+}
+
+pub(crate) fn compile_bytes(
+    source: &[u8],
+    options: &CompileOptions,
+) -> Result<Program, CompileError> {
+    let mut compiler = Compiler::new(options);
+    for &byte in source {
+        compiler.push_byte(byte)?;
+    }
+    compiler.finish()
 }
 
 #[cfg(test)]
@@ -280,13 +606,13 @@ mod tests {
     #[test]
     fn test_add_u8_boundaries() {
         let src = "+".repeat(254)
-            + &">"
+            + ">"
             + &"+".repeat(255)
-            + &">"
+            + ">"
             + &"+".repeat(256)
-            + &">"
+            + ">"
             + &"+".repeat(257)
-            + &">"
+            + ">"
             + &"+".repeat(258);
         let (ops, _) = compile(&src).unwrap();
         assert_eq!(
@@ -302,20 +628,20 @@ mod tests {
             ]
         );
 
-        let src = "-".repeat(1)
-            + &">"
+        let src = "-".to_string()
+            + ">"
             + &"-".repeat(2)
-            + &">"
+            + ">"
             + &"-".repeat(3)
-            + &">"
+            + ">"
             + &"-".repeat(254)
-            + &">"
+            + ">"
             + &"-".repeat(255)
-            + &">"
+            + ">"
             + &"-".repeat(256)
-            + &">"
+            + ">"
             + &"-".repeat(257)
-            + &">"
+            + ">"
             + &"-".repeat(258);
         let (ops, _) = compile(&src).unwrap();
         assert_eq!(
@@ -436,6 +762,140 @@ mod tests {
         assert_eq!(ops, vec![Op::In, Op::Mul(1, 1), Op::Set(0)]);
     }
 
+    #[test]
+    fn test_mul_loop_repeated_target() {
+        let (ops, _) = compile(",[->++>+++<+<]").unwrap();
+        assert_eq!(ops, vec![Op::In, Op::Mul(1, 3), Op::Mul(2, 3), Op::Set(0)]);
+
+        let (ops, _) = compile(",[>>+<+<->>-<<]").unwrap();
+        assert_eq!(ops, vec![Op::In, Op::Mul(1, 1), Op::Set(0)]);
+
+        let (ops, _) = compile(",[>-<<+>->+<]").unwrap();
+        assert_eq!(ops, vec![Op::In, Op::Mul(-1, 1), Op::Set(0)]);
+    }
+
+    #[test]
+    fn test_mul_loop_subtract() {
+        let (ops, _) = compile(",[->-<]").unwrap();
+        assert_eq!(ops, vec![Op::In, Op::Mul(1, 255), Op::Set(0)]);
+
+        let (ops, _) = compile(",[-<<--->-->]").unwrap();
+        assert_eq!(
+            ops,
+            vec![Op::In, Op::Mul(-2, 253), Op::Mul(-1, 254), Op::Set(0)]
+        );
+    }
+
+    // Stores overwritten by a later Set, with balanced moves in between
+    #[test]
+    fn test_dead_store() {
+        let (ops, _) = compile("+++>+<[-]++").unwrap();
+        assert_eq!(ops, vec![Op::Move(1), Op::Add(1), Op::Move(-1), Op::Set(2)]);
+
+        let (ops, _) = compile(",[-]+++++>>+<-<[-]").unwrap();
+        assert_eq!(
+            ops,
+            vec![
+                Op::In,
+                Op::Move(2),
+                Op::Add(1),
+                Op::Move(-1),
+                Op::Add(255),
+                Op::Move(-1),
+                Op::Set(0),
+            ]
+        );
+
+        // Several dead stores to the same cell. Ops around them are
+        // compacted once they're gone.
+        let (ops, _) = compile("+>+<->+<[-]").unwrap();
+        assert_eq!(ops, vec![Op::Move(1), Op::Add(2), Op::Move(-1), Op::Set(0)]);
+
+        // Stores to other cells are kept
+        let (ops, _) = compile(">+>++<-<[-]").unwrap();
+        assert_eq!(
+            ops,
+            vec![
+                Op::Move(1),
+                Op::Add(1),
+                Op::Move(1),
+                Op::Add(2),
+                Op::Move(-1),
+                Op::Add(255),
+                Op::Move(-1),
+                Op::Set(0),
+            ]
+        );
+
+        // Mul targeting other cells doesn't block; mul reading or writing
+        // the cell does
+        let (ops, _) = compile("+>>+[-<+>]<<[-]").unwrap();
+        assert_eq!(
+            ops,
+            vec![
+                Op::Move(2),
+                Op::Add(1),
+                Op::Mul(-1, 1),
+                Op::Set(0),
+                Op::Move(-2),
+                Op::Set(0)
+            ]
+        );
+        let (ops, _) = compile("+>+[-<+>]<[-]").unwrap();
+        assert_eq!(
+            ops,
+            vec![
+                Op::Add(1),
+                Op::Move(1),
+                Op::Add(1),
+                Op::Mul(-1, 1),
+                Op::Set(0),
+                Op::Move(-1),
+                Op::Set(0),
+            ]
+        );
+    }
+
+    // I/O, loops and scans keep earlier stores alive
+    #[test]
+    fn test_dead_store_blocked() {
+        let (ops, _) = compile("+++.>+<[-]").unwrap();
+        assert_eq!(
+            ops,
+            vec![
+                Op::Add(3),
+                Op::Out,
+                Op::Move(1),
+                Op::Add(1),
+                Op::Move(-1),
+                Op::Set(0)
+            ]
+        );
+
+        let (ops, _) = compile("+++>,<[-]").unwrap();
+        assert_eq!(
+            ops,
+            vec![Op::Add(3), Op::Move(1), Op::In, Op::Move(-1), Op::Set(0)]
+        );
+
+        let (ops, _) = compile("+++>[.-]<[-]").unwrap();
+        assert_eq!(ops[0], Op::Add(3));
+
+        let (ops, _) = compile("+++>[>]<[-]").unwrap();
+        assert_eq!(ops[0], Op::Add(3));
+    }
+
+    // The search only looks a bounded distance back, so long runs of
+    // straight-line code don't compile in quadratic time
+    #[test]
+    fn test_dead_store_long_run() {
+        let (ops, _) = compile(&">[-]".repeat(80000)).unwrap();
+        assert_eq!(ops.len(), 160000);
+
+        let (ops, _) = compile(&format!("+{}{}[-]", ">+".repeat(100), "<".repeat(100))).unwrap();
+        assert_eq!(ops[0], Op::Add(1));
+    }
+
     #[test]
     fn test_dead_code_elimination() {
         let (ops, _) = compile(",[-][>>>+>]").unwrap();
@@ -457,6 +917,449 @@ mod tests {
         assert_eq!(ops, vec![Op::In, Op::Scan(2)]);
     }
 
+    #[test]
+    fn test_dead_leading_loop() {
+        let zeroed = |source: &str| {
+            let options = CompileOptions {
+                tape_starts_zeroed: true,
+                debug_char: Some(b'#'),
+                ..Default::default()
+            };
+            compile_bytes(source.as_bytes(), &options).unwrap().ops
+        };
+
+        assert_eq!(zeroed("[->+<]"), vec![]);
+        assert_eq!(zeroed("#[->+<][.]+"), vec![Op::Debug, Op::Add(1)]);
+        assert_eq!(
+            zeroed("+[->+<]"),
+            vec![Op::Add(1), Op::Mul(1, 1), Op::Set(0)]
+        );
+        assert_eq!(zeroed(">[-]"), vec![Op::Move(1), Op::Set(0)]);
+
+        // A supplied tape may not be zeroed, so by default the loop stays
+        let (ops, _) = compile("[->+<]").unwrap();
+        assert_eq!(ops, vec![Op::Mul(1, 1), Op::Set(0)]);
+    }
+
+    // Dead loops must still be closed
+    #[test]
+    fn test_dead_loop_unmatched_open() {
+        let err = compile(",[-]\n [[]").unwrap_err();
+        assert_eq!(
+            err,
+            CompileError::UnmatchedOpen {
+                span: Span {
+                    start: 6,
+                    end: 7,
+                    line: 2,
+                    col: 2
+                }
+            }
+        );
+    }
+
+    // Columns keep counting across a skipped dead loop
+    #[test]
+    fn test_dead_loop_line_column() {
+        let err = compile(",[-][+]]").unwrap_err();
+        assert_eq!(
+            err,
+            CompileError::UnmatchedClose {
+                span: Span {
+                    start: 7,
+                    end: 8,
+                    line: 1,
+                    col: 8
+                }
+            }
+        );
+
+        let err = compile(",[-][\n+]]").unwrap_err();
+        assert_eq!(
+            err,
+            CompileError::UnmatchedClose {
+                span: Span {
+                    start: 8,
+                    end: 9,
+                    line: 2,
+                    col: 3
+                }
+            }
+        );
+    }
+
+    // Line comments hide everything up to the end of the line
+    #[test]
+    fn test_line_comment() {
+        let options = CompileOptions {
+            line_comment: Some(b'#'),
+            ..Default::default()
+        };
+
+        let source = "+. # see [note, -docs-\n-";
+        assert!(compile(source).is_err());
+        let program = compile_bytes(source.as_bytes(), &options).unwrap();
+        assert_eq!(program.ops, vec![Op::Add(1), Op::Out, Op::Add(255)]);
+        assert_eq!(program.span(2).line, 2);
+        assert_eq!(program.span(2).col, 1);
+
+        // Comments are hidden inside loops, including dead ones
+        let source = ",[- # ]\n]\n[-][ # ]\n]";
+        let program = compile_bytes(source.as_bytes(), &options).unwrap();
+        assert_eq!(program.ops, vec![Op::In, Op::Set(0)]);
+
+        // Brackets outside comments still have to match
+        let err = compile_bytes(b"[ # ]", &options).unwrap_err();
+        assert!(matches!(err, CompileError::UnmatchedOpen { .. }));
+    }
+
+    fn diagnostics(source: &str) -> Vec<Diagnostic> {
+        compile_bytes(source.as_bytes(), &CompileOptions::default())
+            .unwrap()
+            .diagnostics
+    }
+
+    // Runs of +/- that wrap around to nothing are reported
+    #[test]
+    fn test_diagnostic_net_zero_add() {
+        let source = ">".to_string() + &"+".repeat(256) + ".";
+        assert_eq!(
+            diagnostics(&source),
+            vec![Diagnostic {
+                kind: DiagnosticKind::NetZeroAdd,
+                span: Span {
+                    start: 1,
+                    end: 257,
+                    line: 1,
+                    col: 2
+                }
+            }]
+        );
+
+        let source = "-".repeat(512) + "\n" + &"+".repeat(256);
+        let found = diagnostics(&source);
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0].span.end, 512);
+        assert_eq!(found[1].span.line, 2);
+        assert_eq!(found[1].span.end - found[1].span.start, 256);
+
+        // Interrupted or partial runs are fine
+        assert!(diagnostics(&"+".repeat(255)).is_empty());
+        assert!(diagnostics(&"+".repeat(257)).is_empty());
+        assert!(diagnostics(&("+".repeat(128) + " " + &"+".repeat(128))).is_empty());
+        assert!(diagnostics(&("+".repeat(128) + "-" + &"+".repeat(127))).is_empty());
+        assert!(diagnostics(&("[-][".to_string() + &"+".repeat(256) + "]")).is_empty());
+    }
+
+    #[test]
+    fn test_diagnostic_unbalanced_loop() {
+        assert_eq!(
+            diagnostics(",[->+>+<]"),
+            vec![Diagnostic {
+                kind: DiagnosticKind::UnbalancedLoop,
+                span: Span {
+                    start: 1,
+                    end: 9,
+                    line: 1,
+                    col: 2
+                }
+            }]
+        );
+        assert_eq!(diagnostics(",[<+<]").len(), 1);
+
+        // Balanced loops, scans, and loops with I/O or inner loops are fine
+        assert!(diagnostics(",[->+>+<<]").is_empty());
+        assert!(diagnostics(",[->+<]").is_empty());
+        assert!(diagnostics(",[>+<--]").is_empty());
+        assert!(diagnostics(",[>>]").is_empty());
+        assert!(diagnostics(",[.>]").is_empty());
+        assert!(diagnostics(",[>,[-]+]").is_empty());
+    }
+
+    #[test]
+    fn test_diagnostic_empty_loop() {
+        assert_eq!(
+            diagnostics("+\n +[]"),
+            vec![Diagnostic {
+                kind: DiagnosticKind::EmptyLoop,
+                span: Span {
+                    start: 4,
+                    end: 5,
+                    line: 2,
+                    col: 3
+                }
+            }]
+        );
+        assert!(diagnostics("[-][]").is_empty());
+        assert!(diagnostics("+[-]>[<]").is_empty());
+
+        let (ops, _) = compile("+[]").unwrap();
+        assert_eq!(ops, vec![Op::Add(1), Op::Open(2), Op::Close(1)]);
+    }
+
+    #[test]
+    fn test_diagnostic_even_clear_loop() {
+        assert_eq!(
+            diagnostics(",[--]"),
+            vec![Diagnostic {
+                kind: DiagnosticKind::EvenClearLoop,
+                span: Span {
+                    start: 1,
+                    end: 5,
+                    line: 1,
+                    col: 2
+                }
+            }]
+        );
+        assert_eq!(
+            diagnostics(",[++++]")[0].kind,
+            DiagnosticKind::EvenClearLoop
+        );
+        assert!(diagnostics(",[-]").is_empty());
+        assert!(diagnostics(",[---]").is_empty());
+        assert!(diagnostics(",[--.]").is_empty());
+
+        // The loop is still run as written
+        let (ops, _) = compile(",[--]").unwrap();
+        assert_eq!(ops, vec![Op::In, Op::Open(3), Op::Add(254), Op::Close(1)]);
+    }
+
+    #[test]
+    fn test_diagnostic_trailing_writes() {
+        let unobserved = |source: &str| {
+            let options = CompileOptions {
+                tape_unobserved: true,
+                ..Default::default()
+            };
+            compile_bytes(source.as_bytes(), &options)
+                .unwrap()
+                .diagnostics
+        };
+
+        assert_eq!(
+            unobserved(",.>+++"),
+            vec![Diagnostic {
+                kind: DiagnosticKind::TrailingWrites,
+                span: Span {
+                    start: 3,
+                    end: 6,
+                    line: 1,
+                    col: 4
+                }
+            }]
+        );
+        let found = unobserved(",.+>[-]<[->+<]>>");
+        assert_eq!(found.len(), 1);
+        assert_eq!((found[0].span.start, found[0].span.end), (2, 14));
+
+        assert!(unobserved(",+.").is_empty());
+        assert!(unobserved(",+.>>").is_empty());
+        assert!(unobserved("").is_empty());
+
+        // Only with the hint, since the tape is usually returned
+        assert!(diagnostics(",.>+++").is_empty());
+    }
+
+    // A scan loop right after a clear never runs, so no Scan is emitted
+    #[test]
+    fn test_scan_after_clear() {
+        let (ops, _) = compile(",[-][>]").unwrap();
+        assert_eq!(ops, vec![Op::In, Op::Set(0)]);
+
+        let (ops, _) = compile(",[-][<<]+").unwrap();
+        assert_eq!(ops, vec![Op::In, Op::Set(1)]);
+
+        let (ops, _) = compile("[-][>]").unwrap();
+        assert_eq!(ops, vec![Op::Set(0)]);
+
+        // Different cell, so the scan stays
+        let (ops, _) = compile(",[-]>[>]").unwrap();
+        assert_eq!(ops, vec![Op::In, Op::Set(0), Op::Move(1), Op::Scan(1)]);
+    }
+
+    #[test]
+    fn test_fold_after_scan() {
+        let (ops, _) = compile(",[>][-]").unwrap();
+        assert_eq!(ops, vec![Op::In, Op::Scan(1)]);
+
+        let (ops, _) = compile(",[>]+").unwrap();
+        assert_eq!(ops, vec![Op::In, Op::Scan(1), Op::Set(1)]);
+
+        let (ops, spans) = compile(",[<]--.").unwrap();
+        assert_eq!(ops, vec![Op::In, Op::Scan(-1), Op::Set(254), Op::Out]);
+        assert_eq!((spans[2].start, spans[2].end), (4, 6));
+
+        // Clears that don't come from a loop are dropped too
+        let span = Span {
+            start: 0,
+            end: 3,
+            line: 1,
+            col: 1,
+        };
+        let mut ops = vec![Op::Scan(1)];
+        let mut spans = vec![span];
+        push_and_compact(&mut ops, &mut spans, Op::Set(0), span);
+        assert_eq!(ops, vec![Op::Scan(1)]);
+        push_and_compact(&mut ops, &mut spans, Op::Set(3), span);
+        assert_eq!(ops, vec![Op::Scan(1), Op::Set(3)]);
+    }
+
+    #[test]
+    fn test_scan_after_set_zero() {
+        let span = Span {
+            start: 0,
+            end: 3,
+            line: 1,
+            col: 1,
+        };
+        let mut ops = vec![Op::In, Op::Set(0)];
+        let mut spans = vec![span; 2];
+        push_and_compact(&mut ops, &mut spans, Op::Scan(1), span);
+        push_and_compact(&mut ops, &mut spans, Op::Scan(-2), span);
+        assert_eq!(ops, vec![Op::In, Op::Set(0)]);
+        assert_eq!(spans.len(), 2);
+
+        // Only a zero cell stops the scan at once
+        let mut ops = vec![Op::Set(1)];
+        let mut spans = vec![span];
+        push_and_compact(&mut ops, &mut spans, Op::Scan(1), span);
+        assert_eq!(ops, vec![Op::Set(1), Op::Scan(1)]);
+    }
+
+    #[test]
+    fn test_halt() {
+        let options = CompileOptions {
+            halt_char: Some(b'@'),
+            ..Default::default()
+        };
+        let compile = |source: &str| compile_bytes(source.as_bytes(), &options).unwrap().ops;
+
+        assert_eq!(compile("+@+"), vec![Op::Add(1), Op::Halt, Op::Add(1)]);
+
+        // Loops holding a halt aren't rewritten
+        assert_eq!(
+            compile(",[-@]"),
+            vec![Op::In, Op::Open(4), Op::Add(255), Op::Halt, Op::Close(1)]
+        );
+        assert_eq!(
+            compile(",[->+<@]"),
+            vec![
+                Op::In,
+                Op::Open(7),
+                Op::Add(255),
+                Op::Move(1),
+                Op::Add(1),
+                Op::Move(-1),
+                Op::Halt,
+                Op::Close(1),
+            ]
+        );
+
+        // Stores before a halt are observable, so not dead
+        assert_eq!(compile("+@[-]"), vec![Op::Add(1), Op::Halt, Op::Set(0)]);
+    }
+
+    #[test]
+    fn test_debug_char() {
+        let options = CompileOptions {
+            debug_char: Some(b'#'),
+            ..Default::default()
+        };
+        let compile = |source: &str| compile_bytes(source.as_bytes(), &options).unwrap().ops;
+
+        assert_eq!(compile("+#+"), vec![Op::Add(1), Op::Debug, Op::Add(1)]);
+
+        // Loops holding a breakpoint aren't rewritten
+        assert_eq!(
+            compile(",[-#]"),
+            vec![Op::In, Op::Open(4), Op::Add(255), Op::Debug, Op::Close(1)]
+        );
+
+        // Nor are stores before one dead
+        assert_eq!(compile("+#[-]"), vec![Op::Add(1), Op::Debug, Op::Set(0)]);
+    }
+
+    #[test]
+    fn test_op_origins() {
+        let program = compile_bytes(b"+[>]+[->++<]>[-]<<,[-->>+<<.]", &Default::default()).unwrap();
+        assert_eq!(
+            program.ops,
+            vec![
+                Op::Add(1),
+                Op::Scan(1),
+                Op::Set(1),
+                Op::Mul(1, 2),
+                Op::Set(0),
+                Op::Move(1),
+                Op::Set(0),
+                Op::Move(-2),
+                Op::In,
+                Op::Open(15),
+                Op::Add(254),
+                Op::Move(2),
+                Op::Add(1),
+                Op::Move(-2),
+                Op::Out,
+                Op::Close(9),
+            ]
+        );
+        use OpOrigin::*;
+        assert_eq!(
+            program.origins,
+            vec![
+                Direct, ScanLoop, Direct, MulLoop, MulLoop, Direct, ClearLoop, Direct, Direct,
+                Direct, Direct, Direct, Direct, Direct, Direct, Direct,
+            ]
+        );
+        assert_eq!(program.span(1).start, 1);
+    }
+
+    #[test]
+    fn test_strict_chars() {
+        let strict = CompileOptions {
+            strict_chars: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            compile_bytes(b",x.", &strict).unwrap_err(),
+            CompileError::UnexpectedChar {
+                span: Span {
+                    start: 1,
+                    end: 2,
+                    line: 1,
+                    col: 2
+                },
+                ch: b'x',
+            }
+        );
+        assert_eq!(
+            compile_bytes(b",x.", &Default::default()).unwrap().ops,
+            vec![Op::In, Op::Out]
+        );
+
+        // Whitespace, option characters and comments are fine
+        let options = CompileOptions {
+            line_comment: Some(b';'),
+            halt_char: Some(b'!'),
+            ..strict.clone()
+        };
+        let program = compile_bytes(b"+ [-]\n\t!; any text [\n.", &options).unwrap();
+        assert_eq!(program.ops, vec![Op::Set(0), Op::Halt, Op::Out]);
+        // Dead loops are checked too
+        assert!(compile_bytes(b"[-][a]", &strict).is_err());
+
+        let options = CompileOptions {
+            strict_whitespace: true,
+            ..strict
+        };
+        assert!(matches!(
+            compile_bytes(b"+ +", &options),
+            Err(CompileError::UnexpectedChar { ch: b' ', .. })
+        ));
+        let err = compile_bytes("+é".as_bytes(), &options).unwrap_err();
+        assert_eq!(err.to_string(), "unexpected byte 0xc3 at line 1, column 2");
+    }
+
     // Scan loop -> Scan
     #[test]
     fn test_scan() {
@@ -476,6 +1379,28 @@ mod tests {
         );
     }
 
+    // Moves in a loop body coalesce first, so the step is the net move
+    #[test]
+    fn test_scan_mixed_moves() {
+        let (ops, spans) = compile(",[>>><],[>><<<],[<<>>>>>]").unwrap();
+        assert_eq!(
+            ops,
+            vec![
+                Op::In,
+                Op::Scan(2),
+                Op::In,
+                Op::Scan(-1),
+                Op::In,
+                Op::Scan(3),
+            ]
+        );
+        assert_eq!((spans[1].start, spans[1].end), (1, 7));
+
+        // Moves netting to zero leave an empty loop, not a scan
+        let (ops, _) = compile(",[><]").unwrap();
+        assert!(!ops.iter().any(|op| matches!(op, Op::Scan(_))));
+    }
+
     // Compilation error on unmatched open
     #[test]
     fn test_unmatched_open() {
@@ -506,6 +1431,21 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_scan_with_side_effects() {
+        // Only a loop holding a single move is a scan
+        let (ops, _) = compile(",[.>]").unwrap();
+        assert_eq!(
+            ops,
+            vec![Op::In, Op::Open(4), Op::Out, Op::Move(1), Op::Close(1)]
+        );
+        let (ops, _) = compile(",[<,]").unwrap();
+        assert_eq!(
+            ops,
+            vec![Op::In, Op::Open(4), Op::Move(-1), Op::In, Op::Close(1)]
+        );
+    }
+
     // Compilation error on unmatched close
     #[test]
     fn test_unmatched_close() {
@@ -579,6 +1519,96 @@ mod tests {
         );
     }
 
+    // Programs keep only offsets, so lines and columns are worked out
+    // again from the line index
+    #[test]
+    fn test_span_line_column() {
+        let line_col = |source: &[u8], start: usize| {
+            let before = &source[..start];
+            let line_start = before
+                .iter()
+                .rposition(|&b| b == b'\n')
+                .map_or(0, |i| i + 1);
+            let lines = before.iter().filter(|&&b| b == b'\n').count();
+            (lines + 1, start - line_start + 1)
+        };
+        let options = CompileOptions {
+            line_comment: Some(b'#'),
+            ..Default::default()
+        };
+        let sources = [
+            "+",
+            "\n\n+\n",
+            "++\r\n>>[-]\r\n,[.,]",
+            "héllo +\n  wörld -\n[>+<-]>.",
+            "# [\n,[>>>]<\n\n\n   [-]+[->++<]\n.# ]\n>",
+            ",[\n[-]\n>]\n[\n<\n]",
+        ];
+        for source in sources {
+            let program = compile_bytes(source.as_bytes(), &options).unwrap();
+            for span in program.spans() {
+                assert_eq!(
+                    (span.line, span.col),
+                    line_col(source.as_bytes(), span.start),
+                    "{:?} at {}",
+                    source,
+                    span.start
+                );
+            }
+        }
+
+        let fragments = ["+\n>", "\n\n[-]<", "", "\n,[\n.,]"];
+        let program = crate::Program::from_fragments(&fragments).unwrap();
+        let joined = fragments.concat();
+        for span in program.spans() {
+            assert_eq!(
+                (span.line, span.col),
+                line_col(joined.as_bytes(), span.start)
+            );
+        }
+
+        // Errors found when loops are linked across fragments
+        let err = crate::Program::from_fragments(&["+\n[", ">\n\n  -"]).unwrap_err();
+        let CompileError::InFragment { fragment, error } = err else {
+            panic!("expected an error in a fragment, got {:?}", err);
+        };
+        assert_eq!(fragment, 0);
+        assert_eq!(
+            *error,
+            CompileError::UnmatchedOpen {
+                span: Span {
+                    start: 2,
+                    end: 3,
+                    line: 2,
+                    col: 1
+                }
+            }
+        );
+    }
+
+    #[test]
+    fn test_relink_loops() {
+        let (mut ops, spans) = compile(",[>,[.-]<-]").unwrap();
+        let linked = ops.clone();
+        for op in ops.iter_mut() {
+            match op {
+                Op::Open(t) | Op::Close(t) => *t = 0,
+                _ => {}
+            }
+        }
+        relink_loops(&mut ops, Spans::Full(&spans)).unwrap();
+        assert_eq!(ops, linked);
+
+        let mut ops = vec![Op::Open(0), Op::Close(0), Op::Close(0)];
+        let err = relink_loops(&mut ops, Spans::Full(&spans[..3])).unwrap_err();
+        assert!(matches!(err, CompileError::UnmatchedClose { .. }));
+        assert_eq!(ops, vec![Op::Open(0), Op::Close(0), Op::Close(0)]);
+
+        let mut ops = vec![Op::Open(0), Op::Open(0), Op::Close(0)];
+        let err = relink_loops(&mut ops, Spans::Full(&spans[..3])).unwrap_err();
+        assert_eq!(err, CompileError::UnmatchedOpen { span: spans[0] });
+    }
+
     #[test]
     fn test_push_and_compact_move_overflow() {
         let mut ops = vec![Op::Move(i32::MAX)];
@@ -624,4 +1654,31 @@ mod tests {
         assert_eq!(ops[0], Op::Move(i32::MIN));
         assert_eq!(ops[1], Op::Move(-1));
     }
+
+    #[test]
+    fn test_push_and_compact_huge_move_run() {
+        // Picks up a run of '>' just short of 2 * i32::MAX + 3
+        let span = Span {
+            start: 0,
+            end: 1,
+            line: 1,
+            col: 1,
+        };
+        let mut ops = vec![Op::Move(i32::MAX), Op::Move(i32::MAX - 1)];
+        let mut spans = vec![span; 2];
+        for _ in 0..3 {
+            push_and_compact(&mut ops, &mut spans, Op::Move(1), span);
+        }
+        assert_eq!(
+            ops,
+            vec![Op::Move(i32::MAX), Op::Move(i32::MAX), Op::Move(2)]
+        );
+        assert_eq!(spans.len(), ops.len());
+
+        // Moving back cancels the tail, then folds into the op before it
+        push_and_compact(&mut ops, &mut spans, Op::Move(-2), span);
+        assert_eq!(ops, vec![Op::Move(i32::MAX), Op::Move(i32::MAX)]);
+        push_and_compact(&mut ops, &mut spans, Op::Move(-1), span);
+        assert_eq!(ops, vec![Op::Move(i32::MAX), Op::Move(i32::MAX - 1)]);
+    }
 }
@@ -1,156 +1,1158 @@
-use crate::{Config, EofBehavior, ExecutionError, ExecutionResult, Op, Span};
+use crate::analysis::{self, Block};
+use crate::lines::Spans;
+use crate::{
+    initial_tape, AccessKind, CellWidth, Config, CostModel, EofBehavior, ExecutionError,
+    ExecutionResult, InputEncoding, NewlineTranslation, Op, OpLimitAction, OutputErrorAction,
+    Program, Span, POLL_OPS,
+};
 use std::io::{Read, Write};
+use std::ops::{DerefMut, Range};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+
+// Checks that ops are safe to execute. The compiler always produces
+// valid ops, so this is only needed for ops from elsewhere.
+pub(crate) fn validate(ops: &[Op], spans: Spans<'_>) -> Result<(), ExecutionError> {
+    if ops.len() != spans.len() {
+        return Err(ExecutionError::InvalidOps {
+            index: ops.len().min(spans.len()),
+            reason: "ops and spans differ in length",
+        });
+    }
+    for (index, op) in ops.iter().enumerate() {
+        let reason = match op {
+            Op::Open(target) => match ops.get(*target as usize) {
+                Some(Op::Close(back)) if *back as usize == index => continue,
+                _ => "open does not point at a matching close",
+            },
+            Op::Close(target) => match ops.get(*target as usize) {
+                Some(Op::Open(back)) if *back as usize == index => continue,
+                _ => "close does not point at a matching open",
+            },
+            Op::Scan(0) => "scan with zero step",
+            _ => continue,
+        };
+        return Err(ExecutionError::InvalidOps { index, reason });
+    }
+    Ok(())
+}
 
 pub(crate) fn execute(
     ops: &[Op],
     spans: &[Span],
     mut tape: Vec<u8>,
-    mut pointer: usize,
+    pointer: usize,
+    config: &Config,
+    input: Option<&mut dyn Read>,
+    output: Option<&mut dyn Write>,
+) -> Result<ExecutionResult, ExecutionError> {
+    let blocks = analysis::straight_line_blocks(ops);
+    let mut state = State {
+        pointer,
+        ..Default::default()
+    };
+    execute_in_place(
+        Code::new(ops, Spans::Full(spans), &blocks),
+        &mut tape,
+        config,
+        input,
+        output,
+        &mut state,
+        &mut NoProbe,
+    )?;
+    Ok(ExecutionResult {
+        tape,
+        pointer: state.pointer,
+        output_truncated: state.output_truncated,
+        ops_executed: state.op_count,
+    })
+}
+
+// Ops to run, with their spans and the straight-line runs found in
+// them by analysis::straight_line_blocks. Blocks that don't line up
+// with the ops are ignored, so everything then runs op by op.
+#[derive(Clone, Copy)]
+pub(crate) struct Code<'a> {
+    pub(crate) ops: &'a [Op],
+    pub(crate) spans: Spans<'a>,
+    pub(crate) blocks: &'a [Option<Block>],
+}
+
+impl<'a> Code<'a> {
+    pub(crate) fn new(ops: &'a [Op], spans: Spans<'a>, blocks: &'a [Option<Block>]) -> Self {
+        let blocks = if blocks.len() == ops.len() {
+            blocks
+        } else {
+            &[]
+        };
+        Self { ops, spans, blocks }
+    }
+}
+
+// Where a run is up to, and the cancellation flag and pause. Execution
+// starts at ip with the pointer and leaves both where it stopped, which
+// is the end of the ops once the program has finished or the failing op
+// on error. The op count, the last output byte, whether input is done,
+// the loop iteration counts and the deadline are picked up and left
+// behind too, so a run can continue where another stopped.
+#[derive(Default)]
+pub(crate) struct State<'a> {
+    pub(crate) ip: usize,
+    pub(crate) pointer: usize,
+    pub(crate) op_count: usize,
+    pub(crate) output_truncated: bool,
+    pub(crate) last_out: u8,
+    pub(crate) input_done: bool,
+    pub(crate) loop_iterations: Option<Vec<usize>>,
+    pub(crate) cancel: Option<&'a AtomicBool>,
+    // Op count at which to stop early, leaving ip at the next op.
+    pub(crate) pause_at: Option<usize>,
+    // When Config::time_limit runs out, set by the first run.
+    pub(crate) deadline: Option<Instant>,
+    // Op count at which to next check for cancellation and the time.
+    pub(crate) next_poll: usize,
+}
+
+// Watches a run. The hooks do nothing by default, so a run with
+// NoProbe is the plain interpreter with nothing to check per op.
+pub(crate) trait Probe {
+    // Whether ops must run one at a time for the probe to see them,
+    // rather than whole blocks at once.
+    fn every_op(&self) -> bool {
+        false
+    }
+
+    // Whether ops are counted, which rules out skipping whole loops.
+    fn counts_ops(&self) -> bool {
+        false
+    }
+
+    // Whether ops other than Debug can run without calling any hooks.
+    fn lean(&self) -> bool {
+        true
+    }
+
+    // Called before op ip runs on its own.
+    fn op(&mut self, _ip: usize, _op: &Op, _pointer: usize, _wrap: bool) {}
+
+    // Called before the ops in ips run as a block that reaches cells up
+    // to max_pointer.
+    fn block(&mut self, _ips: Range<usize>, _max_pointer: usize) {}
+
+    // Called after each op run on its own, with the pointer it left.
+    fn moved(&mut self, _pointer: usize) {}
+
+    // Called when the tape has grown to len cells.
+    fn grown(&mut self, _len: usize) {}
+
+    // Called when a Mul writes to a cell the tape grew to reach.
+    fn mul_grown(&mut self, _target: usize) {}
+
+    // Called after a scan from pointer has taken steps of step.
+    fn scanned(&mut self, _pointer: usize, _step: i32, _steps: usize) {}
+
+    // Called with the pointer and tape at each Debug op.
+    fn debug(&mut self, _pointer: usize, _tape: &[u8]) {}
+
+    // Called with the final pointer once the run has stopped without
+    // error.
+    fn finish(&mut self, _pointer: usize) {}
+}
+
+pub(crate) struct NoProbe;
+
+impl Probe for NoProbe {}
+
+// Measurements taken during execution. Only the ones set to Some
+// before running are taken.
+#[derive(Default)]
+pub(crate) struct Probes {
+    pub(crate) max_pointer: Option<usize>,
+    pub(crate) op_counts: Option<Vec<usize>>,
+    pub(crate) cell_counts: Option<Vec<u64>>,
+    pub(crate) pointer_path: Option<Vec<usize>>,
+}
+
+impl Probe for Probes {
+    // Per-cell counting and the pointer path need every op to be seen
+    fn every_op(&self) -> bool {
+        self.cell_counts.is_some() || self.pointer_path.is_some()
+    }
+
+    fn counts_ops(&self) -> bool {
+        self.op_counts.is_some()
+    }
+
+    fn lean(&self) -> bool {
+        self.max_pointer.is_none()
+            && self.op_counts.is_none()
+            && self.cell_counts.is_none()
+            && self.pointer_path.is_none()
+    }
+
+    fn op(&mut self, ip: usize, op: &Op, pointer: usize, wrap: bool) {
+        if let Some(max_pointer) = &mut self.max_pointer {
+            *max_pointer = (*max_pointer).max(pointer);
+        }
+        if let Some(op_counts) = &mut self.op_counts {
+            op_counts[ip] += 1;
+        }
+        if let Some(cells) = &mut self.cell_counts {
+            count_cell_access(cells, op, pointer, wrap);
+        }
+    }
+
+    fn block(&mut self, ips: Range<usize>, max_pointer: usize) {
+        if let Some(max) = &mut self.max_pointer {
+            *max = (*max).max(max_pointer);
+        }
+        if let Some(op_counts) = &mut self.op_counts {
+            for count in &mut op_counts[ips] {
+                *count += 1;
+            }
+        }
+    }
+
+    fn moved(&mut self, pointer: usize) {
+        if let Some(path) = &mut self.pointer_path {
+            path.push(pointer);
+        }
+    }
+
+    fn grown(&mut self, len: usize) {
+        if let Some(cells) = &mut self.cell_counts {
+            cells.resize(len, 0);
+        }
+    }
+
+    // The target was off the tape when accesses were counted
+    fn mul_grown(&mut self, target: usize) {
+        if let Some(cells) = &mut self.cell_counts {
+            cells[target] += 1;
+        }
+    }
+
+    fn scanned(&mut self, pointer: usize, step: i32, steps: usize) {
+        if let Some(cells) = &mut self.cell_counts {
+            let len = cells.len();
+            for i in 0..=steps {
+                cells[wrapped(pointer, i as i64 * step as i64, len)] += 1;
+            }
+        }
+    }
+
+    fn finish(&mut self, pointer: usize) {
+        if let Some(max_pointer) = &mut self.max_pointer {
+            *max_pointer = (*max_pointer).max(pointer);
+        }
+        if let Some(cells) = &mut self.cell_counts {
+            let used = cells.iter().rposition(|&n| n > 0).map_or(0, |i| i + 1);
+            cells.truncate(used);
+        }
+    }
+}
+
+// Calls the hook with the pointer and tape at each Debug op.
+pub(crate) struct DebugProbe<'a>(pub(crate) &'a mut dyn FnMut(usize, &[u8]));
+
+impl Probe for DebugProbe<'_> {
+    fn debug(&mut self, pointer: usize, tape: &[u8]) {
+        (self.0)(pointer, tape);
+    }
+}
+
+// A tape the executor can run on. Only an owned one can grow.
+pub(crate) trait TapeBuffer: DerefMut<Target = [u8]> {
+    // Extends the tape with zero cells to len cells, if it can.
+    fn grow_to(&mut self, len: usize) -> bool;
+}
+
+impl TapeBuffer for Vec<u8> {
+    fn grow_to(&mut self, len: usize) -> bool {
+        self.resize(len, 0);
+        true
+    }
+}
+
+impl TapeBuffer for &mut [u8] {
+    fn grow_to(&mut self, _len: usize) -> bool {
+        false
+    }
+}
+
+// Runs ops on a borrowed tape, starting from and updating state. The
+// tape only changes length when it grows.
+pub(crate) fn execute_in_place<P: Probe>(
+    code: Code<'_>,
+    tape: &mut impl TapeBuffer,
     config: &Config,
     mut input: Option<&mut dyn Read>,
     mut output: Option<&mut dyn Write>,
-) -> Result<ExecutionResult, ExecutionError> {
-    let mut ip = 0usize;
-    let mut opcount = 0usize;
-    let tape_len = tape.len();
+    state: &mut State<'_>,
+    probe: &mut P,
+) -> Result<(), ExecutionError> {
+    // Ops are compiled for 8-bit cells, so a run can't honor any other
+    // width
+    if config.cell_width != CellWidth::Eight {
+        return Err(ExecutionError::UnsupportedCellWidth {
+            width: config.cell_width,
+        });
+    }
+    let Code { ops, spans, blocks } = code;
+    let mut pointer = state.pointer;
+
+    // An empty program leaves everything as it was. It never writes, so
+    // the output isn't flushed either, and a writer that would fail to
+    // flush goes unnoticed.
+    if ops.is_empty() {
+        probe.finish(pointer);
+        return Ok(());
+    }
+
+    let mut ip = state.ip;
+    let mut opcount = state.op_count;
     let op_limit = config.op_limit.unwrap_or(usize::MAX);
+    // Op count at which to stop: one past the op limit, or where the
+    // caller asked to pause
+    let stop = op_limit
+        .saturating_add(1)
+        .min(state.pause_at.unwrap_or(usize::MAX));
+    let weighted = config.cost_model == CostModel::Weighted;
+    let crlf = config.newline_translation == NewlineTranslation::LfToCrlf;
+    let wrap = config.wrap_pointer;
+    let mut last_out = state.last_out;
+    // Set on EOF when further reads are to be skipped.
+    let mut input_done = state.input_done;
+    // Iterations of each loop currently running, innermost last. Only
+    // kept when loop iterations are limited.
+    let max_loop_iterations = config.max_loop_iterations.unwrap_or(usize::MAX);
+    let mut loop_iterations: Option<Vec<usize>> = state
+        .loop_iterations
+        .take()
+        .or_else(|| config.max_loop_iterations.map(|_| Vec::new()));
+    let cancel = state.cancel;
+    let deadline = config
+        .time_limit
+        .map(|limit| *state.deadline.get_or_insert_with(|| Instant::now() + limit));
+    // Op count at which to next check for cancellation and the time.
+    // Checks are made once due, as a run starts, when the lean loop
+    // stops and at loop back-edges, so nothing is checked per op. A
+    // fresh state is due straight away.
+    let mut next_poll = if cancel.is_some() || deadline.is_some() {
+        state.next_poll
+    } else {
+        usize::MAX
+    };
+    // The last Out run, whose span is reported for a failed final flush,
+    // or None to report the last op's
+    let mut last_out_ip = None;
 
-    while ip < ops.len() {
-        let span = spans[ip];
-        match &ops[ip] {
-            Op::Add(n) => {
-                tape[pointer] = tape[pointer].wrapping_add(*n);
+    // Blocks and [[-]>] sweeps skip the per-op hooks, and the lean loop
+    // all of them
+    let run_blocks = !probe.every_op();
+    let sweeps = run_blocks && !probe.counts_ops() && loop_iterations.is_none();
+    let lean = sweeps && probe.lean() && !weighted;
+
+    // The cat program copies input to output in bulk when nothing needs
+    // to see it run op by op
+    if ip == 0
+        && analysis::is_cat(ops)
+        && config.eof_behavior == EofBehavior::Zero
+        && config.input_encoding == InputEncoding::Bytes
+        && !crlf
+        && config.op_limit.is_none()
+        && state.pause_at.is_none()
+        && config.on_output_error == OutputErrorAction::Fail
+        && sweeps
+        && cancel.is_none()
+        && config.time_limit.is_none()
+    {
+        if let Some(inp) = input.as_mut() {
+            let copied = copy_until_zero(
+                &mut **inp,
+                output.as_mut().map(|o| &mut **o as &mut dyn Write),
+                config.flush_output,
+                spans.get(3),
+                spans.get(2),
+            )?;
+            // The first read, the open, then out, read and close per byte
+            tape[pointer] = 0;
+            opcount += 2 + 3 * copied;
+            last_out_ip = (copied > 0).then_some(2);
+            ip = ops.len();
+        }
+    }
+
+    // Errors leave ip at the op that failed, with the pointer as it was
+    // before it ran. Going over the op limit is only noticed once the
+    // op has run, so that leaves ip after it.
+    let outcome = 'run: {
+        if ip < ops.len() && opcount >= next_poll {
+            if let Err(e) = poll(cancel, deadline, spans.get(ip)) {
+                break 'run Err(e);
             }
-            Op::Move(n) => {
-                let new_ptr = pointer as i64 + *n as i64;
-                if new_ptr < 0 {
-                    return Err(ExecutionError::PointerUnderflow { span });
-                }
-                if new_ptr as usize >= tape_len {
-                    return Err(ExecutionError::PointerOverflow {
-                        span,
-                        pointer: new_ptr as usize,
-                        tape_len,
-                    });
+            next_poll = opcount.saturating_add(POLL_OPS);
+        }
+        while ip < ops.len() {
+            if lean {
+                let bound = op_limit.min(stop).min(next_poll);
+                (ip, pointer, opcount) = run_lean(ops, tape, ip, pointer, opcount, bound);
+                if ip == ops.len() || opcount >= stop {
+                    break;
                 }
-                pointer = new_ptr as usize;
-            }
-            Op::Out => {
-                if let Some(ref mut out) = output {
-                    out.write_all(&[tape[pointer]])
-                        .map_err(|source| ExecutionError::IoError { span, source })?;
-                    if config.flush_output {
-                        out.flush()
-                            .map_err(|source| ExecutionError::IoError { span, source })?;
+                if opcount >= next_poll {
+                    if let Err(e) = poll(cancel, deadline, spans.get(ip)) {
+                        break 'run Err(e);
                     }
+                    next_poll = opcount.saturating_add(POLL_OPS);
                 }
             }
-            Op::In => {
-                if let Some(ref mut inp) = input {
-                    let mut buffer = [0u8; 1];
-                    match inp.read(&mut buffer) {
-                        Ok(0) => {
-                            // EOF reached
-                            match config.eof_behavior {
-                                EofBehavior::Zero => tape[pointer] = 0,
-                                EofBehavior::Unchanged => {}
-                                EofBehavior::MaxValue => tape[pointer] = 255,
-                            }
-                        }
-                        Ok(_) => tape[pointer] = buffer[0],
-                        Err(source) => return Err(ExecutionError::IoError { span, source }),
-                    }
+
+            // Straight-line runs that can skip per-move bounds checks.
+            // Ops edited since the blocks were found no longer match
+            // them, and run op by op.
+            let block = match blocks.get(ip) {
+                Some(&Some(block)) if run_blocks && analysis::block_at(ops, ip) == Some(block) => {
+                    Some(block)
                 }
-            }
-            Op::Open(offset) => {
-                if tape[pointer] == 0 {
-                    ip = *offset as usize;
+                _ => None,
+            };
+            if let Some(block) = block {
+                let len = block.end - ip;
+                if pointer as i64 + block.min_offset >= 0
+                    && pointer as i64 + block.max_offset < tape.len() as i64
+                    && opcount.saturating_add(len) < stop
+                {
+                    probe.block(ip..block.end, (pointer as i64 + block.max_offset) as usize);
+                    pointer = run_block(&ops[ip..block.end], tape, pointer);
+                    opcount += len;
+                    ip = block.end;
+                    continue;
                 }
+                // Otherwise run op by op, which reports any error at the
+                // op that causes it
             }
-            Op::Close(offset) => {
-                if tape[pointer] != 0 {
-                    ip = *offset as usize;
+
+            let at = ip;
+            probe.op(ip, &ops[ip], pointer, wrap);
+            match &ops[ip] {
+                Op::Add(n) => {
+                    tape[pointer] = tape[pointer].wrapping_add(*n);
                 }
-            }
-            Op::Set(n) => {
-                tape[pointer] = *n;
-            }
-            Op::Mul(offset, factor) => {
-                let target = pointer as i64 + *offset as i64;
-                if target < 0 {
-                    return Err(ExecutionError::PointerUnderflow { span });
+                Op::Move(n) => {
+                    let new_ptr = pointer as i64 + *n as i64;
+                    if wrap {
+                        pointer = new_ptr.rem_euclid(tape.len() as i64) as usize;
+                    } else if new_ptr < 0 {
+                        break 'run Err(ExecutionError::PointerUnderflow {
+                            span: spans.get(ip),
+                            kind: AccessKind::Move,
+                        });
+                    } else if new_ptr as usize >= tape.len()
+                        && !grow(tape, new_ptr as usize, config, probe)
+                    {
+                        break 'run Err(ExecutionError::PointerOverflow {
+                            span: spans.get(ip),
+                            pointer: new_ptr as usize,
+                            tape_len: tape.len(),
+                            kind: AccessKind::Move,
+                        });
+                    } else {
+                        pointer = new_ptr as usize;
+                    }
                 }
-                if target as usize >= tape_len {
-                    return Err(ExecutionError::PointerOverflow {
-                        span,
-                        pointer: target as usize,
-                        tape_len,
-                    });
+                Op::Out => {
+                    last_out_ip = Some(ip);
+                    if let Some(ref mut out) = output {
+                        let byte = tape[pointer];
+                        let written = if crlf && byte == b'\n' && last_out != b'\r' {
+                            out.write_all(b"\r\n")
+                        } else {
+                            out.write_all(&[byte])
+                        }
+                        .and_then(|()| {
+                            if config.flush_output {
+                                out.flush()
+                            } else {
+                                Ok(())
+                            }
+                        });
+                        if let Err(source) = written {
+                            match config.on_output_error {
+                                OutputErrorAction::Fail => {
+                                    break 'run Err(ExecutionError::IoError {
+                                        span: spans.get(ip),
+                                        source,
+                                    })
+                                }
+                                OutputErrorAction::Stop => {
+                                    state.output_truncated = true;
+                                    opcount += 1;
+                                    ip = ops.len();
+                                    break;
+                                }
+                            }
+                        }
+                        last_out = byte;
+                    }
                 }
-                let target = target as usize;
-                tape[target] = tape[target].wrapping_add(tape[pointer].wrapping_mul(*factor));
-            }
-            Op::Scan(step) => {
-                let new_ptr = if *step == 1 {
-                    match memchr::memchr(0, &tape[pointer..]) {
-                        Some(i) => pointer + i,
-                        None => {
-                            return Err(ExecutionError::PointerOverflow {
-                                span,
-                                pointer: tape_len,
-                                tape_len,
+                Op::In => {
+                    if let Some(ref mut inp) = input {
+                        let read = if input_done {
+                            Ok(None)
+                        } else {
+                            match config.input_encoding {
+                                InputEncoding::Bytes => {
+                                    read_byte(&mut **inp).map(|b| b.map(u32::from))
+                                }
+                                InputEncoding::Utf8Codepoint => read_codepoint(&mut **inp),
+                            }
+                        };
+                        match read {
+                            Ok(None) => {
+                                // EOF reached
+                                input_done = config.eof_marks_done;
+                                match config.eof_behavior {
+                                    EofBehavior::Zero => tape[pointer] = 0,
+                                    EofBehavior::Unchanged => {}
+                                    EofBehavior::MaxValue => tape[pointer] = 255,
+                                    EofBehavior::Error => {
+                                        break 'run Err(ExecutionError::UnexpectedEof {
+                                            span: spans.get(ip),
+                                        })
+                                    }
+                                }
+                            }
+                            // Cells are 8 bits wide, so keep the low byte
+                            Ok(Some(value)) => tape[pointer] = value as u8,
+                            Err(source) => {
+                                break 'run Err(ExecutionError::IoError {
+                                    span: spans.get(ip),
+                                    source,
+                                })
+                            }
+                        }
+                    }
+                }
+                Op::Open(offset) => {
+                    if tape[pointer] == 0 {
+                        ip = *offset as usize;
+                    } else if let Some(iterations) = &mut loop_iterations {
+                        if max_loop_iterations == 0 {
+                            break 'run Err(ExecutionError::LoopIterationLimit {
+                                span: spans.get(ip),
                             });
                         }
+                        iterations.push(1);
                     }
-                } else if *step == -1 {
-                    match memchr::memrchr(0, &tape[..=pointer]) {
-                        Some(i) => i,
-                        None => {
-                            return Err(ExecutionError::PointerUnderflow { span });
+                }
+                Op::Close(offset) => {
+                    if tape[pointer] == 0 {
+                        if let Some(iterations) = &mut loop_iterations {
+                            iterations.pop();
+                        }
+                    } else {
+                        // Checked before jumping back, so a cancelled run
+                        // resumes at this op
+                        if opcount >= next_poll {
+                            if let Err(e) = poll(cancel, deadline, spans.get(ip)) {
+                                break 'run Err(e);
+                            }
+                            next_poll = opcount.saturating_add(POLL_OPS);
+                        }
+                        // Loops already running when execution started
+                        // mid-program aren't counted
+                        if let Some(count) = loop_iterations.as_mut().and_then(|i| i.last_mut()) {
+                            *count += 1;
+                            if *count > max_loop_iterations {
+                                break 'run Err(ExecutionError::LoopIterationLimit {
+                                    span: spans.get(*offset as usize),
+                                });
+                            }
+                        }
+                        // A [[-]>] loop clears cells up to the next zero
+                        let start = *offset as usize;
+                        let sweep = if sweeps
+                            && ip == start + 3
+                            && ops[start + 1..ip] == [Op::Set(0), Op::Move(1)]
+                        {
+                            memchr::memchr(0, &tape[pointer..])
+                                .filter(|&n| opcount.saturating_add(3 * n + 1) < stop)
+                        } else {
+                            None
+                        };
+                        match sweep {
+                            Some(n) => {
+                                tape[pointer..pointer + n].fill(0);
+                                pointer += n;
+                                opcount += 3 * n;
+                            }
+                            None => ip = start,
                         }
                     }
-                } else if *step > 0 {
-                    let step = *step as usize;
-                    let mut p = pointer;
-                    while p < tape_len && tape[p] != 0 {
-                        p += step;
+                }
+                Op::Set(n) => {
+                    tape[pointer] = *n;
+                }
+                Op::Mul(offset, factor) => {
+                    let target = pointer as i64 + *offset as i64;
+                    let target = if wrap {
+                        target.rem_euclid(tape.len() as i64)
+                    } else if target < 0 {
+                        break 'run Err(ExecutionError::PointerUnderflow {
+                            span: spans.get(ip),
+                            kind: AccessKind::MulTarget,
+                        });
+                    } else if target as usize >= tape.len() {
+                        if !grow(tape, target as usize, config, probe) {
+                            break 'run Err(ExecutionError::PointerOverflow {
+                                span: spans.get(ip),
+                                pointer: target as usize,
+                                tape_len: tape.len(),
+                                kind: AccessKind::MulTarget,
+                            });
+                        }
+                        probe.mul_grown(target as usize);
+                        target
+                    } else {
+                        target
+                    } as usize;
+                    tape[target] = tape[target].wrapping_add(tape[pointer].wrapping_mul(*factor));
+                    if weighted {
+                        opcount += 1;
                     }
-                    if p >= tape_len {
-                        return Err(ExecutionError::PointerOverflow {
-                            span,
-                            pointer: p,
-                            tape_len,
+                }
+                Op::Scan(step) if wrap => {
+                    let Some(steps) = scan_wrapping(tape, pointer, *step, 0) else {
+                        break 'run Err(ExecutionError::EndlessScan {
+                            span: spans.get(ip),
                         });
+                    };
+                    probe.scanned(pointer, *step, steps);
+                    if weighted {
+                        opcount += steps;
                     }
-                    p
-                } else {
-                    let step = (-*step) as usize;
-                    let mut p = pointer;
-                    while tape[p] != 0 {
-                        if p < step {
-                            return Err(ExecutionError::PointerUnderflow { span });
+                    pointer = wrapped(pointer, steps as i64 * *step as i64, tape.len());
+                }
+                Op::Scan(step) => {
+                    let new_ptr = match scan(tape, pointer, *step, 0, || spans.get(ip)) {
+                        Ok(new_ptr) => new_ptr,
+                        // Cells past the end of a growing tape are zero,
+                        // so the scan stops at the first one it reaches
+                        Err(ExecutionError::PointerOverflow { pointer: end, .. })
+                            if grow(tape, end, config, probe) =>
+                        {
+                            end
                         }
-                        p -= step;
+                        Err(e) => break 'run Err(e),
+                    };
+                    let steps = new_ptr.abs_diff(pointer) / step.unsigned_abs() as usize;
+                    probe.scanned(pointer, *step, steps);
+                    if weighted {
+                        opcount += steps;
                     }
-                    p
-                };
-                pointer = new_ptr;
+                    pointer = new_ptr;
+                }
+                Op::Halt => {
+                    ip = ops.len();
+                    break;
+                }
+                Op::Debug => probe.debug(pointer, tape),
+            }
+            ip += 1;
+            opcount += 1;
+            probe.moved(pointer);
+            if opcount >= stop {
+                if opcount > op_limit && config.op_limit_action == OpLimitAction::Error {
+                    break 'run Err(ExecutionError::OperationLimit {
+                        span: spans.get(at),
+                    });
+                }
+                break;
             }
         }
+        Ok(())
+    };
+    state.ip = ip;
+    state.pointer = pointer;
+    state.op_count = opcount;
+    state.next_poll = next_poll;
+    outcome?;
+
+    state.last_out = last_out;
+    state.input_done = input_done;
+    state.loop_iterations = loop_iterations;
+    probe.finish(pointer);
+
+    // Flush once at the end so buffered writers don't hold on to the
+    // tail of the output, whatever flush_output says. Not worth trying
+    // once output has failed.
+    if let Some(out) = output.filter(|_| !state.output_truncated) {
+        if let Err(source) = out.flush() {
+            match config.on_output_error {
+                OutputErrorAction::Fail => {
+                    let span = spans.get(last_out_ip.unwrap_or(ops.len() - 1));
+                    return Err(ExecutionError::IoError { span, source });
+                }
+                OutputErrorAction::Stop => state.output_truncated = true,
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// What [`Machine::step`] left the machine doing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StepOutcome {
+    /// There are more ops to run.
+    Running,
+    /// The program has ended, or was stopped by the op limit under
+    /// [`OpLimitAction::Halt`] or a failed write under
+    /// [`OutputErrorAction::Stop`].
+    Halted,
+}
+
+/// A program being run, which can be advanced one op at a time and
+/// inspected in between.
+///
+/// The op limit counts the ops run over all calls to [`Machine::step`]
+/// and [`Machine::run`], and the time limit runs from the first of
+/// them, including any time spent between calls. Output is flushed at
+/// the end of each call. Debug breakpoints do nothing. After an error
+/// the machine is halted, with the tape, pointer and ip as they were
+/// when the failing op was about to run.
+pub struct Machine<'a> {
+    code: Code<'a>,
+    config: Config,
+    tape: Vec<u8>,
+    state: State<'a>,
+    halted: bool,
+}
+
+impl<'a> Machine<'a> {
+    /// Sets up a machine to run `program` from its first op.
+    ///
+    /// Starts from the supplied tape and pointer if given, like
+    /// [`Program::run`].
+    pub fn new(
+        program: &'a Program,
+        config: &Config,
+        tape: Option<Vec<u8>>,
+        pointer: Option<usize>,
+    ) -> Self {
+        Self {
+            code: program.code(),
+            config: config.clone(),
+            tape: initial_tape(config, tape),
+            state: State {
+                pointer: pointer.unwrap_or(0),
+                ..Default::default()
+            },
+            halted: false,
+        }
+    }
+
+    pub fn tape(&self) -> &[u8] {
+        &self.tape
+    }
+
+    pub fn pointer(&self) -> usize {
+        self.state.pointer
+    }
+
+    /// Index in [`Program::ops`] of the next op to run.
+    pub fn ip(&self) -> usize {
+        self.state.ip
+    }
+
+    /// Number of ops run so far, as counted against the op limit.
+    pub fn op_count(&self) -> usize {
+        self.state.op_count
+    }
+
+    /// Runs the next op.
+    ///
+    /// Returns [`StepOutcome::Halted`] without doing anything once the
+    /// machine has halted.
+    pub fn step(
+        &mut self,
+        input: Option<&mut dyn Read>,
+        output: Option<&mut dyn Write>,
+    ) -> Result<StepOutcome, ExecutionError> {
+        if !self.halted {
+            self.state.pause_at = Some(self.state.op_count + 1);
+            self.execute(input, output)?;
+        }
+        Ok(if self.halted {
+            StepOutcome::Halted
+        } else {
+            StepOutcome::Running
+        })
+    }
+
+    /// Runs until the machine halts.
+    pub fn run(
+        &mut self,
+        input: Option<&mut dyn Read>,
+        output: Option<&mut dyn Write>,
+    ) -> Result<(), ExecutionError> {
+        if !self.halted {
+            self.state.pause_at = None;
+            self.execute(input, output)?;
+        }
+        Ok(())
+    }
+
+    /// Runs until the machine halts and returns a copy of the tape and
+    /// pointer, leaving the machine halted.
+    ///
+    /// This goes through the same executor as [`Machine::step`], only
+    /// without pausing after each op, so running a program to
+    /// completion and stepping through it behave the same.
+    pub fn run_to_completion(
+        &mut self,
+        input: Option<&mut dyn Read>,
+        output: Option<&mut dyn Write>,
+    ) -> Result<ExecutionResult, ExecutionError> {
+        self.run(input, output)?;
+        Ok(ExecutionResult {
+            tape: self.tape.clone(),
+            pointer: self.state.pointer,
+            output_truncated: self.state.output_truncated,
+            ops_executed: self.state.op_count,
+        })
+    }
+
+    /// Ends the run, returning the tape and pointer.
+    pub fn into_result(self) -> ExecutionResult {
+        ExecutionResult {
+            tape: self.tape,
+            pointer: self.state.pointer,
+            output_truncated: self.state.output_truncated,
+            ops_executed: self.state.op_count,
+        }
+    }
+
+    // Runs until the program ends, an error, the op limit or the pause
+    // set in the state, halting the machine unless it paused.
+    fn execute(
+        &mut self,
+        input: Option<&mut dyn Read>,
+        output: Option<&mut dyn Write>,
+    ) -> Result<(), ExecutionError> {
+        let result = execute_in_place(
+            self.code,
+            &mut self.tape,
+            &self.config,
+            input,
+            output,
+            &mut self.state,
+            &mut NoProbe,
+        );
+        self.halted = result.is_err()
+            || self.state.ip >= self.code.ops.len()
+            || self
+                .config
+                .op_limit
+                .is_some_and(|limit| self.state.op_count > limit);
+        result
+    }
+}
+
+// Counts the cells an op reads or writes, except for scans, which are
+// counted once their extent is known.
+fn count_cell_access(cells: &mut [u64], op: &Op, pointer: usize, wrap: bool) {
+    match op {
+        Op::Add(_) | Op::Set(_) | Op::Out | Op::In | Op::Open(_) | Op::Close(_) => {
+            cells[pointer] += 1;
+        }
+        Op::Mul(offset, _) => {
+            cells[pointer] += 1;
+            let target = if wrap {
+                wrapped(pointer, *offset as i64, cells.len()) as i64
+            } else {
+                pointer as i64 + *offset as i64
+            };
+            if let Some(cell) = usize::try_from(target).ok().and_then(|t| cells.get_mut(t)) {
+                *cell += 1;
+            }
+        }
+        Op::Move(_) | Op::Scan(_) | Op::Halt | Op::Debug => {}
+    }
+}
+
+// Runs the common ops from ip, stopping at the end of the ops, once
+// the op count reaches stop, or at an op that needs more than the
+// checks here: anything else, a move or multiplication that leaves the
+// tape, or a [[-]>] loop going round. Nothing is called from the
+// loop, so its state stays in registers. Returns the new ip, pointer
+// and op count.
+fn run_lean(
+    ops: &[Op],
+    tape: &mut [u8],
+    mut ip: usize,
+    mut pointer: usize,
+    mut opcount: usize,
+    stop: usize,
+) -> (usize, usize, usize) {
+    while opcount < stop {
+        let Some(op) = ops.get(ip) else { break };
+        match *op {
+            Op::Add(n) => tape[pointer] = tape[pointer].wrapping_add(n),
+            Op::Set(n) => tape[pointer] = n,
+            Op::Move(n) => match pointer.checked_add_signed(n as isize) {
+                Some(p) if p < tape.len() => pointer = p,
+                _ => break,
+            },
+            Op::Mul(offset, factor) => match pointer.checked_add_signed(offset as isize) {
+                Some(t) if t < tape.len() => {
+                    tape[t] = tape[t].wrapping_add(tape[pointer].wrapping_mul(factor));
+                }
+                _ => break,
+            },
+            Op::Open(end) => {
+                if tape[pointer] == 0 {
+                    ip = end as usize;
+                }
+            }
+            Op::Close(start) => {
+                if tape[pointer] != 0 {
+                    let start = start as usize;
+                    if ip == start + 3 && ops[start + 1..ip] == [Op::Set(0), Op::Move(1)] {
+                        break;
+                    }
+                    ip = start;
+                }
+            }
+            _ => break,
+        }
         ip += 1;
         opcount += 1;
-        if opcount > op_limit {
-            return Err(ExecutionError::OperationLimit { span });
+    }
+    (ip, pointer, opcount)
+}
+
+// Fails with Cancelled once the flag is set, or with TimeLimit once the
+// deadline has passed, reporting the span of the op about to run.
+// Kept out of line so the loops calling it stay tight.
+#[cold]
+#[inline(never)]
+fn poll(
+    cancel: Option<&AtomicBool>,
+    deadline: Option<Instant>,
+    span: Span,
+) -> Result<(), ExecutionError> {
+    if cancel.is_some_and(|c| c.load(Ordering::Relaxed)) {
+        return Err(ExecutionError::Cancelled { span });
+    }
+    if deadline.is_some_and(|d| Instant::now() >= d) {
+        return Err(ExecutionError::TimeLimit { span });
+    }
+    Ok(())
+}
+
+// Runs a straight-line block known to stay on the tape.
+fn run_block(ops: &[Op], tape: &mut [u8], mut pointer: usize) -> usize {
+    for op in ops {
+        match op {
+            Op::Add(n) => tape[pointer] = tape[pointer].wrapping_add(*n),
+            Op::Set(n) => tape[pointer] = *n,
+            Op::Move(n) => pointer = pointer.wrapping_add_signed(*n as isize),
+            _ => unreachable!("blocks hold only Add, Move and Set"),
+        }
+    }
+    pointer
+}
+
+// Copies input to output up to the first zero byte or EOF, like the
+// cat program does, and returns the number of bytes copied. Input is
+// read a byte at a time, as `,` would, so nothing after the zero byte
+// is consumed. Output is written in chunks unless it's flushed after
+// every byte.
+fn copy_until_zero(
+    input: &mut dyn Read,
+    mut output: Option<&mut dyn Write>,
+    flush: bool,
+    read_span: Span,
+    write_span: Span,
+) -> Result<usize, ExecutionError> {
+    let mut buffer = [0u8; 8192];
+    let mut len = 0;
+    let mut copied = 0;
+    let mut write = |chunk: &[u8]| match &mut output {
+        Some(out) if !chunk.is_empty() => out
+            .write_all(chunk)
+            .and_then(|()| if flush { out.flush() } else { Ok(()) })
+            .map_err(|source| ExecutionError::IoError {
+                span: write_span,
+                source,
+            }),
+        _ => Ok(()),
+    };
+    loop {
+        match read_byte(input) {
+            Ok(Some(byte)) if byte != 0 => {
+                buffer[len] = byte;
+                len += 1;
+                copied += 1;
+                if flush || len == buffer.len() {
+                    write(&buffer[..len])?;
+                    len = 0;
+                }
+            }
+            Ok(_) => {
+                write(&buffer[..len])?;
+                return Ok(copied);
+            }
+            // What was read before the error is still written
+            Err(source) => {
+                write(&buffer[..len])?;
+                return Err(ExecutionError::IoError {
+                    span: read_span,
+                    source,
+                });
+            }
+        }
+    }
+}
+
+// Reads one byte, or None at EOF.
+pub(crate) fn read_byte(input: &mut dyn Read) -> std::io::Result<Option<u8>> {
+    let mut buffer = [0u8; 1];
+    match input.read(&mut buffer)? {
+        0 => Ok(None),
+        _ => Ok(Some(buffer[0])),
+    }
+}
+
+// Reads one UTF-8 encoded scalar value and returns its codepoint, or
+// None at EOF.
+pub(crate) fn read_codepoint(input: &mut dyn Read) -> std::io::Result<Option<u32>> {
+    let invalid = || std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid UTF-8 in input");
+    let Some(first) = read_byte(input)? else {
+        return Ok(None);
+    };
+    let len = match first {
+        0x00..=0x7f => 1,
+        0xc0..=0xdf => 2,
+        0xe0..=0xef => 3,
+        0xf0..=0xf7 => 4,
+        _ => return Err(invalid()),
+    };
+    let mut buffer = [first, 0, 0, 0];
+    input.read_exact(&mut buffer[1..len])?;
+    let decoded = std::str::from_utf8(&buffer[..len]).map_err(|_| invalid())?;
+    Ok(decoded.chars().next().map(u32::from))
+}
+
+// Finds the first cell holding the sentinel, moving by step from
+// pointer. Returns the cell's index. Uses memchr for unit steps. The
+// span is only looked up if the scan runs off the tape.
+fn scan(
+    tape: &[u8],
+    pointer: usize,
+    step: i32,
+    sentinel: u8,
+    span: impl FnOnce() -> Span,
+) -> Result<usize, ExecutionError> {
+    let tape_len = tape.len();
+    let found = if step == 1 {
+        match memchr::memchr(sentinel, &tape[pointer..]) {
+            Some(i) => pointer + i,
+            None => {
+                return Err(ExecutionError::PointerOverflow {
+                    span: span(),
+                    pointer: tape_len,
+                    tape_len,
+                    kind: AccessKind::ScanTarget,
+                });
+            }
+        }
+    } else if step == -1 {
+        match memchr::memrchr(sentinel, &tape[..=pointer]) {
+            Some(i) => i,
+            None => {
+                return Err(ExecutionError::PointerUnderflow {
+                    span: span(),
+                    kind: AccessKind::ScanTarget,
+                });
+            }
+        }
+    } else if step > 0 {
+        let step = step as usize;
+        let mut p = pointer;
+        while p < tape_len && tape[p] != sentinel {
+            p += step;
+        }
+        if p >= tape_len {
+            return Err(ExecutionError::PointerOverflow {
+                span: span(),
+                pointer: p,
+                tape_len,
+                kind: AccessKind::ScanTarget,
+            });
+        }
+        p
+    } else {
+        let step = step.unsigned_abs() as usize;
+        let mut p = pointer;
+        while tape[p] != sentinel {
+            if p < step {
+                return Err(ExecutionError::PointerUnderflow {
+                    span: span(),
+                    kind: AccessKind::ScanTarget,
+                });
+            }
+            p -= step;
         }
+        p
+    };
+    Ok(found)
+}
+
+// Extends a growing tape with zero cells up to and including index,
+// telling the probe. Returns false if the tape can't grow that far.
+fn grow(tape: &mut impl TapeBuffer, index: usize, config: &Config, probe: &mut impl Probe) -> bool {
+    if !config.grow_tape || config.wrap_pointer || index >= config.max_tape_size {
+        return false;
     }
+    if !tape.grow_to(index + 1) {
+        return false;
+    }
+    probe.grown(index + 1);
+    true
+}
 
-    Ok(ExecutionResult { tape, pointer })
+// The cell offset from pointer on a tape whose ends wrap around.
+fn wrapped(pointer: usize, offset: i64, tape_len: usize) -> usize {
+    (pointer as i64 + offset).rem_euclid(tape_len as i64) as usize
+}
+
+// Like scan, on a tape whose ends wrap around, returning the number of
+// steps taken to reach the sentinel. Within tape_len steps the pointer
+// is back where it started, so None means it never reaches one.
+fn scan_wrapping(tape: &[u8], pointer: usize, step: i32, sentinel: u8) -> Option<usize> {
+    let tape_len = tape.len();
+    if step == 1 {
+        return memchr::memchr(sentinel, &tape[pointer..]).or_else(|| {
+            memchr::memchr(sentinel, &tape[..pointer]).map(|i| tape_len - pointer + i)
+        });
+    }
+    if step == -1 {
+        return memchr::memrchr(sentinel, &tape[..=pointer])
+            .map(|i| pointer - i)
+            .or_else(|| memchr::memrchr(sentinel, &tape[pointer + 1..]).map(|i| tape_len - 1 - i));
+    }
+    (0..tape_len)
+        .find(|&steps| tape[wrapped(pointer, steps as i64 * step as i64, tape_len)] == sentinel)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::Config;
+    use std::time::Duration;
 
     const S: Span = Span {
         start: 0,
@@ -167,6 +1169,213 @@ mod tests {
         Config::default()
     }
 
+    fn wrap_cfg(tape_size: usize) -> Config {
+        Config {
+            tape_size,
+            wrap_pointer: true,
+            ..cfg()
+        }
+    }
+
+    #[test]
+    fn test_time_limit() {
+        let (ops, sp) = crate::compile::compile("+[]").unwrap();
+        let config = Config {
+            time_limit: Some(Duration::from_millis(50)),
+            ..cfg()
+        };
+        let start = Instant::now();
+        let result = execute(&ops, &sp, vec![0; 4], 0, &config, None, None);
+        assert_eq!(result, Err(ExecutionError::TimeLimit { span: sp[2] }));
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        // Without the lean loop, the clock is read as loops jump back
+        let weighted = Config {
+            cost_model: CostModel::Weighted,
+            ..config.clone()
+        };
+        let result = execute(&ops, &sp, vec![0; 4], 0, &weighted, None, None);
+        assert_eq!(result, Err(ExecutionError::TimeLimit { span: sp[2] }));
+
+        // Whichever limit is reached first applies
+        let config = Config {
+            op_limit: Some(1000),
+            ..config
+        };
+        let result = execute(&ops, &sp, vec![0; 4], 0, &config, None, None);
+        assert!(matches!(result, Err(ExecutionError::OperationLimit { .. })));
+
+        // Programs that end in time are unaffected
+        let config = Config {
+            time_limit: Some(Duration::from_secs(60)),
+            ..cfg()
+        };
+        let result = execute(&[Op::Add(1)], &spans(1), vec![0; 4], 0, &config, None, None);
+        assert_eq!(result.unwrap().tape, vec![1, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_wrap_move() {
+        let config = wrap_cfg(10);
+        let ops = vec![Op::Move(-1), Op::Add(1)];
+        let result = execute(&ops, &spans(2), vec![0; 10], 0, &config, None, None).unwrap();
+        assert_eq!(result.pointer, 9);
+        assert_eq!(result.tape[9], 1);
+
+        let ops = vec![Op::Move(25), Op::Add(1), Op::Move(-1000)];
+        let result = execute(&ops, &spans(3), vec![0; 10], 3, &config, None, None).unwrap();
+        assert_eq!(result.tape[8], 1);
+        assert_eq!(result.pointer, 8);
+
+        // Still an error without wrapping
+        let result = execute(&ops, &spans(3), vec![0; 10], 3, &cfg(), None, None);
+        assert!(matches!(
+            result,
+            Err(ExecutionError::PointerOverflow { .. })
+        ));
+    }
+
+    #[test]
+    fn test_wrap_mul() {
+        let ops = vec![Op::Mul(-2, 3), Op::Mul(13, 1)];
+        let result = execute(
+            &ops,
+            &spans(2),
+            vec![5, 0, 0, 0],
+            0,
+            &wrap_cfg(4),
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(result.tape, vec![5, 5, 15, 0]);
+        assert_eq!(result.pointer, 0);
+    }
+
+    #[test]
+    fn test_wrap_scan() {
+        let config = wrap_cfg(6);
+        let run = |step: i32, tape: Vec<u8>, pointer: usize| {
+            execute(
+                &[Op::Scan(step)],
+                &spans(1),
+                tape,
+                pointer,
+                &config,
+                None,
+                None,
+            )
+            .map(|r| r.pointer)
+        };
+        assert_eq!(run(1, vec![0, 1, 1, 1, 1, 1], 3), Ok(0));
+        assert_eq!(run(1, vec![1, 0, 1, 1, 1, 1], 3), Ok(1));
+        assert_eq!(run(1, vec![1, 1, 1, 1, 0, 1], 3), Ok(4));
+        assert_eq!(run(-1, vec![1, 1, 1, 1, 0, 1], 2), Ok(4));
+        assert_eq!(run(-1, vec![0, 1, 1, 1, 1, 1], 2), Ok(0));
+        assert_eq!(run(2, vec![1, 0, 1, 1, 1, 1], 3), Ok(1));
+        assert_eq!(run(-4, vec![1, 1, 1, 1, 0, 1], 2), Ok(4));
+
+        // Odd cells are never visited from an even one
+        assert_eq!(
+            run(2, vec![1, 0, 1, 0, 1, 0], 0),
+            Err(ExecutionError::EndlessScan { span: S })
+        );
+        assert_eq!(
+            run(-1, vec![1; 6], 2),
+            Err(ExecutionError::EndlessScan { span: S })
+        );
+
+        // Weighted cost and cell counts follow the pointer round
+        let config = Config {
+            cost_model: CostModel::Weighted,
+            ..config.clone()
+        };
+        let mut probes = Probes {
+            cell_counts: Some(vec![0; 6]),
+            ..Default::default()
+        };
+        let mut state = State {
+            pointer: 4,
+            ..Default::default()
+        };
+        let mut tape = vec![1, 0, 1, 1, 1, 1];
+        execute_in_place(
+            Code::new(&[Op::Scan(1)], Spans::Full(&spans(1)), &[]),
+            &mut tape,
+            &config,
+            None,
+            None,
+            &mut state,
+            &mut probes,
+        )
+        .unwrap();
+        assert_eq!(state.op_count, 4);
+        assert_eq!(probes.cell_counts, Some(vec![1, 1, 0, 0, 1, 1]));
+    }
+
+    #[test]
+    fn test_grow_tape() {
+        let config = Config {
+            tape_size: 2,
+            grow_tape: true,
+            ..cfg()
+        };
+        let ops = vec![Op::Mul(3, 2), Op::Move(2), Op::Add(1), Op::Move(1)];
+        let result = execute(&ops, &spans(4), vec![5, 0], 0, &config, None, None).unwrap();
+        assert_eq!(result.tape, vec![5, 0, 1, 10]);
+        assert_eq!(result.pointer, 3);
+
+        // A scan off the end stops at the first new cell
+        let result = execute(
+            &[Op::Scan(3)],
+            &spans(1),
+            vec![1, 1],
+            1,
+            &config,
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(result.tape, vec![1, 1, 0, 0, 0]);
+        assert_eq!(result.pointer, 4);
+
+        // Cell counts grow along with the tape
+        let mut probes = Probes {
+            cell_counts: Some(vec![0; 2]),
+            ..Default::default()
+        };
+        let mut tape = vec![1, 0];
+        execute_in_place(
+            Code::new(&ops, Spans::Full(&spans(4)), &[]),
+            &mut tape,
+            &config,
+            None,
+            None,
+            &mut State::default(),
+            &mut probes,
+        )
+        .unwrap();
+        assert_eq!(probes.cell_counts, Some(vec![1, 0, 1, 1]));
+
+        // Wrapping takes precedence
+        let config = Config {
+            wrap_pointer: true,
+            ..config
+        };
+        let result = execute(
+            &[Op::Move(3)],
+            &spans(1),
+            vec![0, 0],
+            0,
+            &config,
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(result.tape.len(), 2);
+        assert_eq!(result.pointer, 1);
+    }
+
     // Scans with stride 1
     #[test]
     fn test_scan_stride_1() {
@@ -259,33 +1468,15 @@ mod tests {
     #[test]
     fn test_set() {
         let sp = spans(1);
-        let result = execute(
-            &vec![Op::Set(42)],
-            &sp,
-            vec![100, 0, 0],
-            0,
-            &cfg(),
-            None,
-            None,
-        )
-        .unwrap();
+        let result = execute(&[Op::Set(42)], &sp, vec![100, 0, 0], 0, &cfg(), None, None).unwrap();
         assert_eq!(result.tape, vec![42, 0, 0]);
 
-        let result = execute(
-            &vec![Op::Set(0)],
-            &sp,
-            vec![0, 255, 0],
-            1,
-            &cfg(),
-            None,
-            None,
-        )
-        .unwrap();
+        let result = execute(&[Op::Set(0)], &sp, vec![0, 255, 0], 1, &cfg(), None, None).unwrap();
         assert_eq!(result.tape, vec![0, 0, 0]);
 
         let sp = spans(3);
         let result = execute(
-            &vec![Op::Set(10), Op::Set(20), Op::Set(30)],
+            &[Op::Set(10), Op::Set(20), Op::Set(30)],
             &sp,
             vec![0],
             0,
@@ -319,6 +1510,7 @@ mod tests {
                 },
                 pointer: 10,
                 tape_len: 5,
+                kind: AccessKind::Move,
             })
         );
     }
@@ -343,10 +1535,46 @@ mod tests {
                     line: 1,
                     col: 10
                 },
+                kind: AccessKind::Move,
             })
         );
     }
 
+    #[test]
+    fn test_error_mul_target() {
+        // The pointer stays put but the multiplication writes off the tape
+        let ops = vec![Op::Mul(3, 2)];
+        let sp = spans(1);
+        let result = execute(&ops, &sp, vec![1, 0, 0], 0, &cfg(), None, None);
+        let err = result.unwrap_err();
+        assert_eq!(
+            err,
+            ExecutionError::PointerOverflow {
+                span: S,
+                pointer: 3,
+                tape_len: 3,
+                kind: AccessKind::MulTarget,
+            }
+        );
+        assert!(err
+            .to_string()
+            .starts_with("pointer overflow: multiplication target 3 exceeds tape length 3"));
+
+        let ops = vec![Op::Mul(-2, 2)];
+        let result = execute(&ops, &sp, vec![1, 0, 0], 1, &cfg(), None, None);
+        let err = result.unwrap_err();
+        assert_eq!(
+            err,
+            ExecutionError::PointerUnderflow {
+                span: S,
+                kind: AccessKind::MulTarget,
+            }
+        );
+        assert!(err
+            .to_string()
+            .starts_with("pointer underflow: multiplication target out of bounds"));
+    }
+
     #[test]
     fn test_error_mul_out_of_bounds() {
         // Mul target beyond tape
@@ -378,6 +1606,367 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn test_input_encoding() {
+        let ops = vec![
+            Op::In,
+            Op::Move(1),
+            Op::In,
+            Op::Move(1),
+            Op::In,
+            Op::Move(1),
+            Op::In,
+        ];
+        let sp = spans(ops.len());
+        let input = "Aé€".as_bytes();
+
+        let result = execute(
+            &ops,
+            &sp,
+            vec![0; 4],
+            0,
+            &cfg(),
+            Some(&mut &input[..]),
+            None,
+        )
+        .unwrap();
+        assert_eq!(result.tape, [b'A', 0xc3, 0xa9, 0xe2]);
+
+        // Codepoints are truncated to the low byte
+        let config = Config {
+            input_encoding: InputEncoding::Utf8Codepoint,
+            ..Default::default()
+        };
+        let result = execute(
+            &ops,
+            &sp,
+            vec![9; 4],
+            0,
+            &config,
+            Some(&mut &input[..]),
+            None,
+        )
+        .unwrap();
+        assert_eq!(result.tape, [b'A', 0xe9, 0xac, 0]);
+
+        let mut invalid: &[u8] = &[0xff];
+        let result = execute(&ops, &sp, vec![0; 4], 0, &config, Some(&mut invalid), None);
+        assert!(matches!(result, Err(ExecutionError::IoError { .. })));
+
+        let mut truncated: &[u8] = &[0xe2, 0x82];
+        let result = execute(
+            &ops,
+            &sp,
+            vec![0; 4],
+            0,
+            &config,
+            Some(&mut truncated),
+            None,
+        );
+        assert!(matches!(result, Err(ExecutionError::IoError { .. })));
+    }
+
+    // Reports EOF once, then has more data.
+    struct TransientEof {
+        eof_sent: bool,
+    }
+    impl std::io::Read for TransientEof {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if !self.eof_sent {
+                self.eof_sent = true;
+                return Ok(0);
+            }
+            buf[0] = 7;
+            Ok(1)
+        }
+    }
+
+    #[test]
+    fn test_eof_error() {
+        let ops = vec![Op::In, Op::Move(1), Op::In];
+        let sp = spans(ops.len());
+        let config = Config {
+            eof_behavior: EofBehavior::Error,
+            ..Default::default()
+        };
+        let mut input: &[u8] = b"A";
+        let result = execute(&ops, &sp, vec![9; 2], 0, &config, Some(&mut input), None);
+        assert_eq!(result, Err(ExecutionError::UnexpectedEof { span: sp[2] }));
+
+        let mut input: &[u8] = b"";
+        let result = execute(&ops, &sp, vec![9; 2], 0, &config, Some(&mut input), None);
+        assert_eq!(result, Err(ExecutionError::UnexpectedEof { span: sp[0] }));
+
+        // Without input, reads do nothing and can't fail
+        let result = execute(&ops, &sp, vec![9; 2], 0, &config, None, None);
+        assert_eq!(result.unwrap().tape, [9, 9]);
+    }
+
+    #[test]
+    fn test_eof_marks_done() {
+        let ops = vec![Op::In, Op::Move(1), Op::In, Op::Move(1), Op::In];
+        let sp = spans(ops.len());
+        let run = |eof_behavior, eof_marks_done| {
+            let config = Config {
+                eof_behavior,
+                eof_marks_done,
+                ..Default::default()
+            };
+            let mut input = TransientEof { eof_sent: false };
+            execute(&ops, &sp, vec![9; 3], 0, &config, Some(&mut input), None)
+                .unwrap()
+                .tape
+        };
+
+        assert_eq!(run(EofBehavior::Unchanged, false), [9, 7, 7]);
+        assert_eq!(run(EofBehavior::Unchanged, true), [9, 9, 9]);
+        assert_eq!(run(EofBehavior::Zero, false), [0, 7, 7]);
+        assert_eq!(run(EofBehavior::Zero, true), [0, 0, 0]);
+        assert_eq!(run(EofBehavior::MaxValue, true), [255, 255, 255]);
+
+        // Reads after a real EOF give zero again either way
+        let mut input: &[u8] = b"A";
+        let config = Config {
+            eof_marks_done: true,
+            ..Default::default()
+        };
+        let result = execute(&ops, &sp, vec![9; 3], 0, &config, Some(&mut input), None).unwrap();
+        assert_eq!(result.tape, [b'A', 0, 0]);
+    }
+
+    #[test]
+    fn test_op_limit_halt() {
+        // +[>+++.<]
+        let ops = vec![
+            Op::Add(1),
+            Op::Open(6),
+            Op::Move(1),
+            Op::Add(3),
+            Op::Out,
+            Op::Move(-1),
+            Op::Close(1),
+        ];
+        let sp = spans(ops.len());
+        let config = Config {
+            op_limit: Some(12),
+            op_limit_action: OpLimitAction::Halt,
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        let result = execute(&ops, &sp, vec![0; 4], 0, &config, None, Some(&mut output)).unwrap();
+        assert_eq!(output, [3, 6]);
+        // Stops right after the op that went over the limit, like the
+        // error does
+        assert_eq!(result.tape, [1, 6, 0, 0]);
+        assert_eq!(result.pointer, 1);
+
+        let config = Config {
+            op_limit_action: OpLimitAction::Error,
+            ..config
+        };
+        let result = execute(&ops, &sp, vec![0; 4], 0, &config, None, None);
+        assert!(matches!(result, Err(ExecutionError::OperationLimit { .. })));
+    }
+
+    #[test]
+    fn test_empty_loop() {
+        let ops = vec![Op::Open(1), Op::Close(0)];
+        let sp = spans(2);
+        let config = Config {
+            op_limit: Some(1000),
+            ..Default::default()
+        };
+
+        // Skipped on a zero cell, spins forever otherwise
+        let result = execute(&ops, &sp, vec![0], 0, &config, None, None);
+        assert!(result.is_ok());
+        let result = execute(&ops, &sp, vec![1], 0, &config, None, None);
+        assert!(matches!(result, Err(ExecutionError::OperationLimit { .. })));
+    }
+
+    #[test]
+    fn test_straight_line_block() {
+        // .>+>>-<<<<.
+        let ops = vec![
+            Op::Out,
+            Op::Move(1),
+            Op::Add(1),
+            Op::Move(2),
+            Op::Add(255),
+            Op::Move(-4),
+            Op::Out,
+        ];
+        let sp: Vec<Span> = (0..ops.len())
+            .map(|i| Span {
+                start: i,
+                end: i + 1,
+                line: 1,
+                col: i + 1,
+            })
+            .collect();
+
+        let mut output = Vec::new();
+        let result = execute(&ops, &sp, vec![7; 5], 1, &cfg(), None, Some(&mut output)).unwrap();
+        assert_eq!(result.tape, [7, 7, 8, 7, 6]);
+        assert_eq!(result.pointer, 0);
+        assert_eq!(output, [7, 7]);
+
+        // Leaving the tape fails at the move that does it, after the
+        // ops before it have run
+        let mut output = Vec::new();
+        let result = execute(&ops, &sp, vec![7; 4], 1, &cfg(), None, Some(&mut output));
+        assert_eq!(
+            result.unwrap_err(),
+            ExecutionError::PointerOverflow {
+                span: sp[3],
+                pointer: 4,
+                tape_len: 4,
+                kind: AccessKind::Move,
+            }
+        );
+        assert_eq!(output, [7]);
+
+        let result = execute(&ops, &sp, vec![7; 5], 0, &cfg(), None, None);
+        assert_eq!(
+            result.unwrap_err(),
+            ExecutionError::PointerUnderflow {
+                span: sp[5],
+                kind: AccessKind::Move,
+            }
+        );
+
+        // The op limit still applies within a block
+        let config = Config {
+            op_limit: Some(3),
+            ..Default::default()
+        };
+        let result = execute(&ops, &sp, vec![7; 5], 1, &config, None, None);
+        assert_eq!(
+            result.unwrap_err(),
+            ExecutionError::OperationLimit { span: sp[3] }
+        );
+    }
+
+    #[test]
+    fn test_clear_sweep() {
+        // +[[-]>]
+        let ops = vec![
+            Op::Add(1),
+            Op::Open(4),
+            Op::Set(0),
+            Op::Move(1),
+            Op::Close(1),
+        ];
+        let sp: Vec<Span> = (0..ops.len())
+            .map(|i| Span {
+                start: i,
+                end: i + 1,
+                line: 1,
+                col: i + 1,
+            })
+            .collect();
+
+        let tape = vec![0, 3, 4, 5, 0, 6];
+        let result = execute(&ops, &sp, tape, 0, &cfg(), None, None).unwrap();
+        assert_eq!(result.tape, [0, 0, 0, 0, 0, 6]);
+        assert_eq!(result.pointer, 4);
+
+        // Op counts match running the loop op by op
+        let config = Config {
+            op_limit: Some(14),
+            ..Default::default()
+        };
+        let result = execute(&ops, &sp, vec![0, 3, 4, 5, 0], 0, &config, None, None);
+        assert!(result.is_ok());
+        let config = Config {
+            op_limit: Some(13),
+            ..Default::default()
+        };
+        let result = execute(&ops, &sp, vec![0, 3, 4, 5, 0], 0, &config, None, None);
+        assert!(matches!(result, Err(ExecutionError::OperationLimit { .. })));
+
+        // Running off the end fails at the move
+        let result = execute(&ops, &sp, vec![0, 3, 4], 0, &cfg(), None, None);
+        assert_eq!(
+            result.unwrap_err(),
+            ExecutionError::PointerOverflow {
+                span: sp[3],
+                pointer: 3,
+                tape_len: 3,
+                kind: AccessKind::Move,
+            }
+        );
+    }
+
+    #[test]
+    fn test_error_huge_moves() {
+        // Consecutive maximal moves fail on the first one, with the
+        // pointer computed without wrapping
+        let ops = vec![Op::Move(i32::MAX), Op::Move(i32::MAX)];
+        let sp = spans(2);
+        let result = execute(&ops, &sp, vec![0; 10], 5, &cfg(), None, None);
+        assert_eq!(
+            result.unwrap_err(),
+            ExecutionError::PointerOverflow {
+                span: S,
+                pointer: i32::MAX as usize + 5,
+                tape_len: 10,
+                kind: AccessKind::Move,
+            }
+        );
+
+        let ops = vec![Op::Move(i32::MIN), Op::Move(i32::MIN)];
+        let result = execute(&ops, &sp, vec![0; 10], 9, &cfg(), None, None);
+        assert_eq!(
+            result.unwrap_err(),
+            ExecutionError::PointerUnderflow {
+                span: S,
+                kind: AccessKind::Move,
+            }
+        );
+
+        // Large moves that stay on the tape are fine
+        let ops = vec![Op::Move(4), Op::Move(-9), Op::Move(5)];
+        let result = execute(&ops, &spans(3), vec![0; 10], 5, &cfg(), None, None).unwrap();
+        assert_eq!(result.pointer, 5);
+    }
+
+    #[test]
+    fn test_scan_sentinel() {
+        let tape = [7, 3, 9, 3, 5, 3];
+        assert_eq!(scan(&tape, 0, 1, 3, || S), Ok(1));
+        assert_eq!(scan(&tape, 2, 1, 3, || S), Ok(3));
+        assert_eq!(scan(&tape, 5, -1, 7, || S), Ok(0));
+        assert_eq!(scan(&tape, 0, 2, 5, || S), Ok(4));
+        assert_eq!(scan(&tape, 5, -2, 3, || S), Ok(5));
+        assert_eq!(scan(&tape, 4, -3, 3, || S), Ok(1));
+        assert_eq!(
+            scan(&tape, 0, 1, 0, || S),
+            Err(ExecutionError::PointerOverflow {
+                span: S,
+                pointer: 6,
+                tape_len: 6,
+                kind: AccessKind::ScanTarget,
+            })
+        );
+        assert_eq!(
+            scan(&tape, 5, -1, 8, || S),
+            Err(ExecutionError::PointerUnderflow {
+                span: S,
+                kind: AccessKind::ScanTarget,
+            })
+        );
+        assert_eq!(
+            scan(&tape, 0, 2, 3, || S),
+            Err(ExecutionError::PointerOverflow {
+                span: S,
+                pointer: 6,
+                tape_len: 6,
+                kind: AccessKind::ScanTarget,
+            })
+        );
+    }
+
     #[test]
     fn test_error_scan_overflow() {
         // Forward scan with no zero
@@ -394,7 +1983,9 @@ mod tests {
                 span,
                 pointer,
                 tape_len,
+                kind,
             }) => {
+                assert_eq!(kind, AccessKind::ScanTarget);
                 assert_eq!(span.line, 3);
                 assert_eq!(span.col, 7);
                 assert_eq!(pointer, 3);
@@ -419,67 +2010,370 @@ mod tests {
             })
         ));
 
-        // Same move succeeds with larger tape
-        let result = execute(&ops, &sp, vec![0; 10], 0, &cfg(), None, None);
+        // Same move succeeds with larger tape
+        let result = execute(&ops, &sp, vec![0; 10], 0, &cfg(), None, None);
+        assert!(result.is_ok());
+        assert_eq!(result.unwrap().pointer, 5);
+    }
+
+    #[test]
+    fn test_custom_tape_size_underflow() {
+        // Move before tape start
+        let ops = vec![Op::Move(-3)];
+        let sp = spans(1);
+        let result = execute(&ops, &sp, vec![0; 5], 2, &cfg(), None, None);
+        assert!(matches!(
+            result,
+            Err(ExecutionError::PointerUnderflow { .. })
+        ));
+
+        // Same move succeeds with different starting pointer
+        let result = execute(&ops, &sp, vec![0; 5], 4, &cfg(), None, None);
+        assert!(result.is_ok());
+        assert_eq!(result.unwrap().pointer, 1);
+    }
+
+    #[test]
+    fn test_op_limit_respected() {
+        // A simple loop that runs many iterations
+        // [->+<] copies cell 0 to cell 1, running 3 ops per iteration
+        let ops = vec![
+            Op::Open(5),
+            Op::Add(255), // decrement
+            Op::Move(1),
+            Op::Add(1),
+            Op::Move(-1),
+            Op::Close(0),
+        ];
+        let sp = spans(6);
+
+        // With cell 0 = 10, loop runs 10 times = 50 ops (5 ops * 10 iterations)
+        // Plus final Open check = 51 ops total
+        let config_limited = Config {
+            op_limit: Some(30),
+            ..Default::default()
+        };
+        let result = execute(&ops, &sp, vec![10, 0], 0, &config_limited, None, None);
+        assert!(matches!(result, Err(ExecutionError::OperationLimit { .. })));
+
+        // With higher limit, it succeeds
+        let config_ok = Config {
+            op_limit: Some(100),
+            ..Default::default()
+        };
+        let result = execute(&ops, &sp, vec![10, 0], 0, &config_ok, None, None);
+        assert!(result.is_ok());
+
+        // With no limit (None), it succeeds
+        let config_unlimited = Config {
+            op_limit: None,
+            ..Default::default()
+        };
+        let result = execute(&ops, &sp, vec![10, 0], 0, &config_unlimited, None, None);
+        assert!(result.is_ok());
+    }
+
+    // Probes that watch every op keep execution off the lean loop, so
+    // both ways through the executor have to agree
+    #[test]
+    fn test_lean_loop_matches_probed() {
+        let config = Config {
+            tape_size: 8,
+            grow_tape: true,
+            max_tape_size: 32,
+            ..cfg()
+        };
+        let sources = [
+            "++[>+++[>++<-]<-]>>",
+            "+>+>+<<[[-]>]>+",
+            "++++[>++<-]>[>+>+<<-]>>[-<+>]",
+            "+>>+>>+<<<<[>>]<-[<<]",
+            "+[>+]",
+            "+[<+]",
+        ];
+        fn run(
+            code: Code<'_>,
+            config: &Config,
+            probe: &mut impl Probe,
+        ) -> (Result<(), ExecutionError>, Vec<u8>, [usize; 3]) {
+            let mut tape = vec![0; 8];
+            let mut state = State::default();
+            let result = execute_in_place(code, &mut tape, config, None, None, &mut state, probe);
+            (result, tape, [state.ip, state.pointer, state.op_count])
+        }
+        for source in sources {
+            let (ops, sp) = crate::compile::compile(source).unwrap();
+            let blocks = analysis::straight_line_blocks(&ops);
+            let code = Code::new(&ops, Spans::Full(&sp), &blocks);
+            let mut probes = Probes {
+                pointer_path: Some(Vec::new()),
+                ..Default::default()
+            };
+            assert_eq!(
+                run(code, &config, &mut NoProbe),
+                run(code, &config, &mut probes),
+                "{}",
+                source
+            );
+        }
+    }
+
+    // Hands out data in chunks, counting the reads
+    struct CountingReads<'a> {
+        data: &'a [u8],
+        reads: usize,
+    }
+
+    impl Read for CountingReads<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.reads += 1;
+            self.data.read(buf)
+        }
+    }
+
+    #[test]
+    fn test_cat_fast_path() {
+        let (ops, sp) = crate::compile::compile(",[.,]").unwrap();
+        let data: Vec<u8> = (0..100_000).map(|i| (i % 255 + 1) as u8).collect();
+
+        let run = |config: &Config, data: &[u8]| {
+            let mut input = CountingReads { data, reads: 0 };
+            let mut output = Vec::new();
+            let mut tape = vec![7; 4];
+            let mut state = State {
+                pointer: 1,
+                ..Default::default()
+            };
+            execute_in_place(
+                Code::new(&ops, Spans::Full(&sp), &[]),
+                &mut tape,
+                config,
+                Some(&mut input),
+                Some(&mut output),
+                &mut state,
+                &mut NoProbe,
+            )
+            .unwrap();
+            let rest = input.data.to_vec();
+            (
+                output,
+                tape,
+                state.pointer,
+                state.op_count,
+                input.reads,
+                rest,
+            )
+        };
+
+        // Input is read a byte at a time, as it is op by op
+        let (output, tape, pointer, op_count, reads, _) = run(&cfg(), &data);
+        assert_eq!(output, data);
+        assert_eq!(tape, [7, 0, 7, 7]);
+        assert_eq!(pointer, 1);
+        assert_eq!(reads, data.len() + 1);
+
+        // Same result as running op by op
+        let slow = Config {
+            op_limit: Some(usize::MAX),
+            ..cfg()
+        };
+        let (slow_output, slow_tape, _, slow_op_count, slow_reads, _) = run(&slow, &data);
+        assert_eq!(slow_reads, data.len() + 1);
+        assert_eq!(output, slow_output);
+        assert_eq!(tape, slow_tape);
+        assert_eq!(op_count, slow_op_count);
+
+        // A zero byte ends the program, and what follows it is left
+        // for whoever reads the input next
+        let (output, tape, _, op_count, _, rest) = run(&cfg(), b"ab\0cd");
+        assert_eq!(output, b"ab");
+        assert_eq!(tape, [7, 0, 7, 7]);
+        assert_eq!(rest, b"cd");
+        assert_eq!(op_count, run(&slow, b"ab\0cd").3);
+        assert_eq!(run(&slow, b"ab\0cd").5, b"cd");
+        let (output, _, _, op_count, _, _) = run(&cfg(), b"");
+        assert!(output.is_empty());
+        assert_eq!(op_count, 2);
+
+        // Without EOF as zero the program doesn't end at EOF, so it
+        // isn't a plain copy
+        let config = Config {
+            eof_behavior: EofBehavior::MaxValue,
+            op_limit: Some(20),
+            op_limit_action: OpLimitAction::Halt,
+            ..cfg()
+        };
+        let (output, _, _, _, _, _) = run(&config, b"ab");
+        assert_eq!(&output[..3], b"ab\xff");
+    }
+
+    #[test]
+    fn test_max_loop_iterations() {
+        // ,[,] iterates once per input byte
+        let ops = vec![Op::In, Op::Open(3), Op::In, Op::Close(1)];
+        let sp: Vec<Span> = (0..ops.len())
+            .map(|i| Span {
+                start: i,
+                end: i + 1,
+                line: 1,
+                col: i + 1,
+            })
+            .collect();
+        let input = vec![1u8; 1000];
+
+        let config = Config {
+            max_loop_iterations: Some(500),
+            ..Default::default()
+        };
+        let result = execute(&ops, &sp, vec![0], 0, &config, Some(&mut &input[..]), None);
+        assert_eq!(
+            result.unwrap_err(),
+            ExecutionError::LoopIterationLimit { span: sp[1] }
+        );
+
+        let config = Config {
+            max_loop_iterations: Some(1000),
+            ..Default::default()
+        };
+        let result = execute(&ops, &sp, vec![0], 0, &config, Some(&mut &input[..]), None);
         assert!(result.is_ok());
-        assert_eq!(result.unwrap().pointer, 5);
     }
 
     #[test]
-    fn test_custom_tape_size_underflow() {
-        // Move before tape start
-        let ops = vec![Op::Move(-3)];
-        let sp = spans(1);
-        let result = execute(&ops, &sp, vec![0; 5], 2, &cfg(), None, None);
-        assert!(matches!(
-            result,
-            Err(ExecutionError::PointerUnderflow { .. })
-        ));
+    fn test_max_loop_iterations_nested() {
+        // The inner loop's count starts over each time it is entered
+        let ops = vec![
+            Op::Open(8),
+            Op::Move(1),
+            Op::Add(3),
+            Op::Open(5),
+            Op::Add(255),
+            Op::Close(3),
+            Op::Move(-1),
+            Op::Add(255),
+            Op::Close(0),
+        ];
+        let sp: Vec<Span> = (0..ops.len())
+            .map(|i| Span {
+                start: i,
+                end: i + 1,
+                line: 1,
+                col: i + 1,
+            })
+            .collect();
 
-        // Same move succeeds with different starting pointer
-        let result = execute(&ops, &sp, vec![0; 5], 4, &cfg(), None, None);
-        assert!(result.is_ok());
-        assert_eq!(result.unwrap().pointer, 1);
+        let config = Config {
+            max_loop_iterations: Some(3),
+            ..Default::default()
+        };
+        let result = execute(&ops, &sp, vec![3, 0], 0, &config, None, None).unwrap();
+        assert_eq!(result.tape, [0, 0]);
+
+        let config = Config {
+            max_loop_iterations: Some(2),
+            ..Default::default()
+        };
+        let result = execute(&ops, &sp, vec![3, 0], 0, &config, None, None);
+        assert_eq!(
+            result.unwrap_err(),
+            ExecutionError::LoopIterationLimit { span: sp[3] }
+        );
+
+        // A zero cap rejects entering any loop
+        let config = Config {
+            max_loop_iterations: Some(0),
+            ..Default::default()
+        };
+        let result = execute(&ops, &sp, vec![3, 0], 0, &config, None, None);
+        assert_eq!(
+            result.unwrap_err(),
+            ExecutionError::LoopIterationLimit { span: sp[0] }
+        );
     }
 
     #[test]
-    fn test_op_limit_respected() {
-        // A simple loop that runs many iterations
-        // [->+<] copies cell 0 to cell 1, running 3 ops per iteration
+    fn test_op_limit_cost_model() {
+        // [[>]<[-]<]
         let ops = vec![
-            Op::Open(5),
-            Op::Add(255), // decrement
-            Op::Move(1),
-            Op::Add(1),
+            Op::Open(6),
+            Op::Scan(1),
+            Op::Move(-1),
+            Op::Set(0),
             Op::Move(-1),
             Op::Close(0),
         ];
         let sp = spans(6);
+        let tape = vec![0, 1, 1, 1, 1, 0];
 
-        // With cell 0 = 10, loop runs 10 times = 50 ops (5 ops * 10 iterations)
-        // Plus final Open check = 51 ops total
-        let config_limited = Config {
-            op_limit: Some(30),
+        // Uniform: 5 ops per iteration, 4 iterations, plus the final Open
+        let uniform = |limit| Config {
+            op_limit: Some(limit),
             ..Default::default()
         };
-        let result = execute(&ops, &sp, vec![10, 0], 0, &config_limited, None, None);
-        assert!(matches!(result, Err(ExecutionError::OperationLimit { .. })));
+        assert!(execute(&ops, &sp, tape.clone(), 4, &uniform(21), None, None).is_ok());
+        assert!(matches!(
+            execute(&ops, &sp, tape.clone(), 4, &uniform(20), None, None),
+            Err(ExecutionError::OperationLimit { .. })
+        ));
 
-        // With higher limit, it succeeds
-        let config_ok = Config {
-            op_limit: Some(100),
+        // Weighted: each scan also moves past one cell
+        let weighted = |limit| Config {
+            op_limit: Some(limit),
+            cost_model: CostModel::Weighted,
             ..Default::default()
         };
-        let result = execute(&ops, &sp, vec![10, 0], 0, &config_ok, None, None);
-        assert!(result.is_ok());
+        assert!(execute(&ops, &sp, tape.clone(), 4, &weighted(25), None, None).is_ok());
+        assert!(matches!(
+            execute(&ops, &sp, tape.clone(), 4, &weighted(24), None, None),
+            Err(ExecutionError::OperationLimit { .. })
+        ));
 
-        // With no limit (None), it succeeds
-        let config_unlimited = Config {
-            op_limit: None,
+        // A single long scan is one op, or 1 + 4 weighted
+        let ops = vec![Op::Scan(1)];
+        let sp = spans(1);
+        let tape = vec![1, 1, 1, 1, 0];
+        assert!(execute(&ops, &sp, tape.clone(), 0, &uniform(1), None, None).is_ok());
+        assert!(execute(&ops, &sp, tape.clone(), 0, &weighted(5), None, None).is_ok());
+        assert!(matches!(
+            execute(&ops, &sp, tape.clone(), 0, &weighted(4), None, None),
+            Err(ExecutionError::OperationLimit { .. })
+        ));
+
+        let ops = vec![Op::Scan(-2)];
+        assert!(matches!(
+            execute(&ops, &sp, vec![0, 1, 1, 1, 1], 4, &weighted(2), None, None),
+            Err(ExecutionError::OperationLimit { .. })
+        ));
+        assert!(execute(&ops, &sp, vec![0, 1, 1, 1, 1], 4, &weighted(3), None, None).is_ok());
+
+        // Two steps of 2 move 4 cells but only cost 1 + 2
+        let ops = vec![Op::Scan(2)];
+        let tape = vec![1, 1, 1, 1, 0];
+        assert!(execute(&ops, &sp, tape.clone(), 0, &weighted(3), None, None).is_ok());
+        assert!(matches!(
+            execute(&ops, &sp, tape, 0, &weighted(2), None, None),
+            Err(ExecutionError::OperationLimit { .. })
+        ));
+    }
+
+    #[test]
+    fn test_op_limit_cost_model_mul() {
+        let ops = vec![Op::Mul(1, 2), Op::Mul(2, 3)];
+        let sp = spans(2);
+        let config = Config {
+            op_limit: Some(3),
+            cost_model: CostModel::Weighted,
             ..Default::default()
         };
-        let result = execute(&ops, &sp, vec![10, 0], 0, &config_unlimited, None, None);
-        assert!(result.is_ok());
+        assert!(matches!(
+            execute(&ops, &sp, vec![1, 0, 0], 0, &config, None, None),
+            Err(ExecutionError::OperationLimit { .. })
+        ));
+        let config = Config {
+            op_limit: Some(4),
+            ..config
+        };
+        assert!(execute(&ops, &sp, vec![1, 0, 0], 0, &config, None, None).is_ok());
     }
 
     #[test]
@@ -543,29 +2437,50 @@ mod tests {
         assert_eq!(result.tape[1], 0); // EOF -> Zero
     }
 
+    #[test]
+    fn test_newline_translation() {
+        // Writes cell 0, then cell 1, and so on
+        let ops = vec![
+            Op::Out,
+            Op::Move(1),
+            Op::Out,
+            Op::Move(1),
+            Op::Out,
+            Op::Move(1),
+            Op::Out,
+            Op::Move(1),
+            Op::Out,
+        ];
+        let sp = spans(ops.len());
+        let tape = b"A\nB\r\n".to_vec();
+
+        let mut output = Vec::new();
+        execute(&ops, &sp, tape.clone(), 0, &cfg(), None, Some(&mut output)).unwrap();
+        assert_eq!(output, b"A\nB\r\n");
+
+        let config = Config {
+            newline_translation: NewlineTranslation::LfToCrlf,
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        execute(&ops, &sp, tape, 0, &config, None, Some(&mut output)).unwrap();
+        assert_eq!(output, b"A\r\nB\r\n");
+    }
+
     struct FailingWriter;
     impl std::io::Write for FailingWriter {
         fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
-            Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "write failed",
-            ))
+            Err(std::io::Error::other("write failed"))
         }
         fn flush(&mut self) -> std::io::Result<()> {
-            Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "flush failed",
-            ))
+            Err(std::io::Error::other("flush failed"))
         }
     }
 
     struct FailingReader;
     impl std::io::Read for FailingReader {
         fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
-            Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "read failed",
-            ))
+            Err(std::io::Error::other("read failed"))
         }
     }
 
@@ -579,10 +2494,7 @@ mod tests {
         }
         fn flush(&mut self) -> std::io::Result<()> {
             if self.written {
-                Err(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    "flush failed",
-                ))
+                Err(std::io::Error::other("flush failed"))
             } else {
                 Ok(())
             }
@@ -609,6 +2521,417 @@ mod tests {
         let mut writer = WriteOkFlushFails { written: false };
         let result = execute(&ops, &sp, vec![65], 0, &config, None, Some(&mut writer));
         assert!(matches!(result, Err(ExecutionError::IoError { .. })));
+
+        // The final flush failing is reported at the last output
+        let ops = vec![Op::Out, Op::Add(1), Op::Move(1)];
+        let sp: Vec<Span> = (0..3).map(|i| Span { start: i, ..S }).collect();
+        let config = Config {
+            flush_output: false,
+            ..Default::default()
+        };
+        let mut writer = WriteOkFlushFails { written: false };
+        let result = execute(&ops, &sp, vec![65, 0], 0, &config, None, Some(&mut writer));
+        assert!(matches!(result, Err(ExecutionError::IoError { span, .. }) if span == sp[0]));
+    }
+
+    // Holds everything written until flushed, like a BufWriter with an
+    // unbounded buffer.
+    struct BufferingWriter {
+        buffer: Vec<u8>,
+        flushed: Vec<u8>,
+    }
+    impl std::io::Write for BufferingWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.buffer.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.flushed.append(&mut self.buffer);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_final_flush() {
+        let ops = vec![Op::Out, Op::Add(1), Op::Out];
+        let sp = spans(ops.len());
+        let config = Config {
+            flush_output: false,
+            ..Default::default()
+        };
+        let mut writer = BufferingWriter {
+            buffer: Vec::new(),
+            flushed: Vec::new(),
+        };
+        execute(&ops, &sp, vec![65], 0, &config, None, Some(&mut writer)).unwrap();
+        assert_eq!(writer.flushed, b"AB");
+        assert!(writer.buffer.is_empty());
+    }
+
+    // Accepts a number of bytes, then fails like a full disk.
+    struct FullWriter {
+        written: Vec<u8>,
+        space: usize,
+    }
+    impl std::io::Write for FullWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if self.written.len() + buf.len() > self.space {
+                return Err(std::io::Error::from(std::io::ErrorKind::StorageFull));
+            }
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_output_error_stop() {
+        // Prints and increments cell 0 until it wraps, counting in cell 1
+        let ops = vec![
+            Op::Add(1),
+            Op::Open(7),
+            Op::Out,
+            Op::Add(1),
+            Op::Move(1),
+            Op::Add(1),
+            Op::Move(-1),
+            Op::Close(1),
+        ];
+        let sp = spans(ops.len());
+        let config = Config {
+            on_output_error: OutputErrorAction::Stop,
+            ..cfg()
+        };
+        let mut writer = FullWriter {
+            written: Vec::new(),
+            space: 3,
+        };
+        let result = execute(&ops, &sp, vec![0; 2], 0, &config, None, Some(&mut writer)).unwrap();
+        assert_eq!(writer.written, vec![1, 2, 3]);
+        assert!(result.output_truncated);
+        // Stopped at the fourth Out, before incrementing anything
+        assert_eq!(result.tape, vec![4, 3]);
+        assert_eq!(result.pointer, 0);
+
+        let mut writer = FullWriter {
+            written: Vec::new(),
+            space: 3,
+        };
+        let result = execute(&ops, &sp, vec![0; 2], 0, &cfg(), None, Some(&mut writer));
+        assert!(matches!(result, Err(ExecutionError::IoError { .. })));
+
+        // Output that fits isn't truncated
+        let ops = vec![Op::Add(65), Op::Out];
+        let mut writer = FullWriter {
+            written: Vec::new(),
+            space: 3,
+        };
+        let result = execute(
+            &ops,
+            &spans(2),
+            vec![0],
+            0,
+            &config,
+            None,
+            Some(&mut writer),
+        )
+        .unwrap();
+        assert_eq!(writer.written, b"A");
+        assert!(!result.output_truncated);
+    }
+
+    #[test]
+    fn test_output_error_stop_final_flush() {
+        let ops = vec![Op::Add(65), Op::Out];
+        let sp = spans(ops.len());
+        let config = Config {
+            flush_output: false,
+            on_output_error: OutputErrorAction::Stop,
+            ..cfg()
+        };
+        let mut writer = WriteOkFlushFails { written: false };
+        let result = execute(&ops, &sp, vec![0], 0, &config, None, Some(&mut writer)).unwrap();
+        assert!(result.output_truncated);
+        assert_eq!(result.tape, vec![65]);
+    }
+
+    // Steps a program to the end, returning the machine's state and
+    // output and the number of steps taken
+    fn step_all(
+        program: &Program,
+        config: &Config,
+        input: &[u8],
+    ) -> (ExecutionResult, Vec<u8>, usize) {
+        let mut machine = Machine::new(program, config, None, None);
+        let mut input = input;
+        let mut output = Vec::new();
+        let mut steps = 0;
+        while machine.step(Some(&mut input), Some(&mut output)).unwrap() == StepOutcome::Running {
+            steps += 1;
+        }
+        (machine.into_result(), output, steps)
+    }
+
+    #[test]
+    fn test_machine_step() {
+        let program = Program::from_source(",[->+>++<<]>>[-<.>]>,[.,]").unwrap();
+        let config = Config {
+            tape_size: 8,
+            ..cfg()
+        };
+        let mut input: &[u8] = b"\x03xyz";
+        let mut output = Vec::new();
+        let expected = program
+            .run(&config, None, None, Some(&mut input), Some(&mut output))
+            .unwrap();
+
+        let (result, stepped_output, steps) = step_all(&program, &config, b"\x03xyz");
+        assert_eq!(result, expected);
+        assert_eq!(stepped_output, output);
+        assert!(steps > program.ops.len());
+    }
+
+    #[test]
+    fn test_machine_run_to_completion() {
+        let programs: &[(&str, &[u8])] = &[
+            (include_str!("../tests/programs/factor.b"), b"360\n"),
+            (
+                include_str!("../tests/programs/basicops.b"),
+                include_bytes!("../tests/programs/basicops.in"),
+            ),
+            (include_str!("../tests/programs/endoffile.b"), b""),
+            (",[.,]", b"cat"),
+            ("+>+>+>+<<<[[-]>]++++[>++++<-]>[>+>++<<-]>>.", b""),
+        ];
+        let configs = [
+            cfg(),
+            Config {
+                cost_model: CostModel::Weighted,
+                ..cfg()
+            },
+            Config {
+                tape_size: 4,
+                grow_tape: true,
+                ..cfg()
+            },
+        ];
+        for (source, input) in programs {
+            let program = Program::from_source(source).unwrap();
+            for config in &configs {
+                let mut expected_output = Vec::new();
+                let expected = execute(
+                    &program.ops,
+                    &program.spans().collect::<Vec<_>>(),
+                    initial_tape(config, None),
+                    0,
+                    config,
+                    Some(&mut &input[..]),
+                    Some(&mut expected_output),
+                );
+
+                let mut machine = Machine::new(&program, config, None, None);
+                let mut output = Vec::new();
+                let result = machine.run_to_completion(Some(&mut &input[..]), Some(&mut output));
+                assert_eq!(result, expected, "{}", source);
+                assert_eq!(output, expected_output, "{}", source);
+                assert_eq!(machine.step(None, None).unwrap(), StepOutcome::Halted);
+            }
+        }
+    }
+
+    #[test]
+    fn test_machine_inspect() {
+        let program = Program::from_source("++>+++<[->+<]").unwrap();
+        let mut machine = Machine::new(&program, &cfg(), None, None);
+        assert_eq!(
+            (machine.ip(), machine.pointer(), machine.op_count()),
+            (0, 0, 0)
+        );
+
+        assert_eq!(machine.step(None, None).unwrap(), StepOutcome::Running);
+        assert_eq!(machine.tape()[0], 2);
+        assert_eq!(machine.step(None, None).unwrap(), StepOutcome::Running);
+        assert_eq!((machine.ip(), machine.pointer()), (2, 1));
+
+        // Running picks up where stepping left off
+        machine.run(None, None).unwrap();
+        assert_eq!(machine.ip(), program.ops.len());
+        assert_eq!(machine.op_count(), program.ops.len());
+        assert_eq!(machine.step(None, None).unwrap(), StepOutcome::Halted);
+        assert_eq!(machine.into_result().tape[..2], [0, 5]);
+    }
+
+    #[test]
+    fn test_machine_step_one_op() {
+        // Blocks and [[-]>] sweeps are still run one op per step
+        let program = Program::from_source("+>+>+>+<<<[[-]>]>>+>+<<").unwrap();
+        let mut machine = Machine::new(&program, &cfg(), None, None);
+        let mut steps = 0;
+        while machine.step(None, None).unwrap() == StepOutcome::Running {
+            steps += 1;
+            assert_eq!(machine.op_count(), steps);
+        }
+        assert_eq!(
+            machine.into_result(),
+            program.run(&cfg(), None, None, None, None).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_machine_time_limit() {
+        // The clock starts with the first step, not with each one
+        let program = Program::from_source("+[]").unwrap();
+        let config = Config {
+            time_limit: Some(Duration::from_millis(50)),
+            ..cfg()
+        };
+        let mut machine = Machine::new(&program, &config, None, None);
+        machine.step(None, None).unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+        let mut steps = 0;
+        let result = loop {
+            steps += 1;
+            match machine.step(None, None) {
+                Ok(StepOutcome::Running) if steps <= 2 * POLL_OPS => {}
+                other => break other,
+            }
+        };
+        assert_eq!(
+            result,
+            Err(ExecutionError::TimeLimit {
+                span: program.span(2)
+            })
+        );
+    }
+
+    #[test]
+    fn test_machine_op_limit() {
+        let program = Program::from_source("+[]").unwrap();
+        let config = Config {
+            op_limit: Some(4),
+            ..cfg()
+        };
+        let mut machine = Machine::new(&program, &config, None, None);
+        for _ in 0..4 {
+            assert_eq!(machine.step(None, None).unwrap(), StepOutcome::Running);
+        }
+        assert!(matches!(
+            machine.step(None, None),
+            Err(ExecutionError::OperationLimit { .. })
+        ));
+
+        let config = Config {
+            op_limit: Some(4),
+            op_limit_action: OpLimitAction::Halt,
+            ..cfg()
+        };
+        let (result, _, steps) = step_all(&program, &config, b"");
+        assert_eq!(steps, 4);
+        assert_eq!(result.tape[0], 1);
+
+        // The limit carries over from steps to run
+        let mut machine = Machine::new(&program, &config, None, None);
+        machine.step(None, None).unwrap();
+        machine.step(None, None).unwrap();
+        machine.run(None, None).unwrap();
+        assert_eq!(machine.op_count(), 5);
+    }
+
+    #[test]
+    fn test_machine_state_across_steps() {
+        // Loop iterations are counted over separate steps
+        let program = Program::from_source("+++[-.]").unwrap();
+        let config = Config {
+            max_loop_iterations: Some(2),
+            ..cfg()
+        };
+        let mut machine = Machine::new(&program, &config, None, None);
+        let result = loop {
+            match machine.step(None, None) {
+                Ok(StepOutcome::Running) => {}
+                other => break other,
+            }
+        };
+        assert!(matches!(
+            result,
+            Err(ExecutionError::LoopIterationLimit { .. })
+        ));
+
+        // As is hitting EOF
+        let program = Program::from_source(",>,").unwrap();
+        let config = Config {
+            eof_behavior: EofBehavior::Unchanged,
+            eof_marks_done: true,
+            ..cfg()
+        };
+        let mut machine = Machine::new(&program, &config, Some(vec![7, 7]), None);
+        let mut input = CountingReads {
+            data: b"",
+            reads: 0,
+        };
+        while machine.step(Some(&mut input), None).unwrap() == StepOutcome::Running {}
+        assert_eq!(input.reads, 1);
+        assert_eq!(machine.tape()[..2], [7, 7]);
+    }
+
+    #[test]
+    fn test_machine_error_state() {
+        let program = Program::from_source("++[>+<-]>>>.<<<<").unwrap();
+        let config = Config {
+            tape_size: 4,
+            ..cfg()
+        };
+        let mut machine = Machine::new(&program, &config, None, None);
+        let error = loop {
+            if let Err(e) = machine.step(None, None) {
+                break e;
+            }
+        };
+        assert!(matches!(error, ExecutionError::PointerUnderflow { .. }));
+        assert_eq!(machine.ip(), program.ops.len() - 1);
+        assert_eq!(machine.pointer(), 3);
+        assert_eq!(machine.tape(), [0, 2, 0, 0]);
+        assert_eq!(machine.step(None, None).unwrap(), StepOutcome::Halted);
+    }
+
+    #[test]
+    fn test_empty_program() {
+        let config = Config {
+            tape_size: 4,
+            ..Default::default()
+        };
+        let mut tape = vec![1, 2, 3, 4];
+        let mut probes = Probes {
+            max_pointer: Some(0),
+            cell_counts: Some(vec![0; 4]),
+            pointer_path: Some(Vec::new()),
+            ..Default::default()
+        };
+        let mut state = State {
+            pointer: 2,
+            ..Default::default()
+        };
+        let mut writer = FailingWriter;
+        let mut reader = FailingReader;
+        execute_in_place(
+            Code::new(&[], Spans::Full(&[]), &[]),
+            &mut tape,
+            &config,
+            Some(&mut reader),
+            Some(&mut writer),
+            &mut state,
+            &mut probes,
+        )
+        .unwrap();
+        assert_eq!(state.pointer, 2);
+        assert_eq!(tape, vec![1, 2, 3, 4]);
+        assert_eq!(state.ip, 0);
+        assert_eq!(state.op_count, 0);
+        assert_eq!(probes.max_pointer, Some(2));
+        assert_eq!(probes.cell_counts, Some(vec![]));
+        assert_eq!(probes.pointer_path, Some(vec![]));
     }
 
     #[test]
@@ -621,7 +2944,7 @@ mod tests {
         // Use == to exercise PartialEq (compares span and error kind)
         let expected = ExecutionError::IoError {
             span: S,
-            source: std::io::Error::new(std::io::ErrorKind::Other, "different msg ok"),
+            source: std::io::Error::other("different msg ok"),
         };
         assert_eq!(result.unwrap_err(), expected);
     }
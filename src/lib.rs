@@ -1,48 +1,531 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
 use std::io::{Read, Write};
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::Duration;
 
+mod adapters;
+mod analysis;
+#[cfg(feature = "bignum")]
+mod bignum;
+mod bytecode;
 mod compile;
 mod execute;
+mod lines;
+mod transpile;
+mod wide;
+
+pub use adapters::{Fnv1aHasher, HashingWriter, RepeatReader, TeeWriter};
+#[cfg(feature = "bignum")]
+pub use bignum::{BigExecutionResult, BigProgram};
+pub use execute::{Machine, StepOutcome};
+pub use wide::{WideExecutionResult, WideProgram};
 
 pub const DEFAULT_TAPE_SIZE: usize = 30000;
+/// Ops run between checks of the flag passed to
+/// [`Program::run_cancellable`] and of [`Config::time_limit`]. Once
+/// this many have run, the check waits for the next time a loop jumps
+/// back to its start.
+pub const POLL_OPS: usize = 65536;
+/// Default for [`Config::max_tape_size`].
+pub const DEFAULT_MAX_TAPE_SIZE: usize = 1 << 24;
 
 /// Behavior when input reaches EOF.
-#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum EofBehavior {
     /// Set the cell to 0.
     #[default]
     Zero,
     /// Leave the cell unchanged.
     Unchanged,
-    /// Set the cell to 255.
+    /// Set the cell to its largest value, 255 for 8-bit cells.
+    #[serde(alias = "max")]
     MaxValue,
+    /// Fail with [`ExecutionError::UnexpectedEof`].
+    Error,
+}
+
+/// Translation applied to newlines in program output.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NewlineTranslation {
+    /// Output bytes are written as is.
+    #[default]
+    None,
+    /// A `\r` is written before each `\n`, unless the program itself
+    /// just wrote one.
+    LfToCrlf,
+}
+
+/// How input is decoded by `,`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InputEncoding {
+    /// Each `,` reads one byte.
+    #[default]
+    Bytes,
+    /// Each `,` reads one UTF-8 encoded scalar value and stores its
+    /// codepoint. With 8-bit cells only the low byte of the codepoint
+    /// is kept. Invalid or truncated UTF-8 is an I/O error.
+    Utf8Codepoint,
+}
+
+/// Number of bits in a tape cell.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CellWidth {
+    #[default]
+    Eight,
+    Sixteen,
+    ThirtyTwo,
+}
+
+/// How executed ops are counted against the operation limit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CostModel {
+    /// Every op costs 1.
+    #[default]
+    Uniform,
+    /// Ops cost roughly the work they do. A scan costs 1 plus the number
+    /// of steps it takes, so a scan with step 2 is charged once per cell
+    /// it checks rather than per cell it moves past. A multiplication
+    /// costs 2, and all other ops cost 1.
+    Weighted,
+}
+
+/// What happens when the operation limit is exceeded.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OpLimitAction {
+    /// Execution fails with [`ExecutionError::OperationLimit`].
+    #[default]
+    Error,
+    /// Execution stops and the tape and pointer are returned as they
+    /// are, as if the program had ended.
+    Halt,
+}
+
+/// What happens when writing program output fails, say because the
+/// disk is full.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputErrorAction {
+    /// Execution fails with [`ExecutionError::IoError`].
+    #[default]
+    Fail,
+    /// Execution stops and the tape and pointer are returned as they
+    /// are, with [`ExecutionResult::output_truncated`] set. The op that
+    /// failed to write counts as run.
+    Stop,
+}
+
+/// Which fields [`Program::run_with_report`] fills in. Everything is
+/// off by default.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReportOptions {
+    /// Count executed ops.
+    pub op_count: bool,
+    /// Track the highest cell visited.
+    pub max_pointer: bool,
+    /// Count input and output bytes.
+    pub io_bytes: bool,
+    /// Count executions of each op.
+    pub op_counts: bool,
+    /// Count reads and writes of each cell. Turns off the executor's
+    /// fast paths, so expect a slower run.
+    pub cell_profile: bool,
+    /// Record the pointer after each op. Turns off the executor's fast
+    /// paths, and the path grows with every op run, so it is best kept
+    /// to short runs.
+    pub pointer_path: bool,
 }
 
 /// Configuration for program execution.
-#[derive(Clone, Debug, PartialEq, Eq)]
+///
+/// Can be (de)serialized, with fields missing from the input taking
+/// their default values.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct Config {
-    /// Size of the memory tape. Default: 30000.
+    /// Size of the memory tape. A supplied tape shorter than this is
+    /// padded with zeros. Default: 30000.
     pub tape_size: usize,
+    /// Wrap the pointer around at the ends of the tape instead of
+    /// failing with a pointer underflow or overflow. A scan that finds
+    /// no zero cell on its way round fails with
+    /// [`ExecutionError::EndlessScan`]. Default: false.
+    pub wrap_pointer: bool,
+    /// Extend the tape with zero cells when a move, multiplication or
+    /// scan goes past its end, instead of failing with a pointer
+    /// overflow. Moving below cell 0 still fails. Ignored when
+    /// `wrap_pointer` is set, and by [`Program::run_in_place`], whose
+    /// tape is borrowed. Default: false.
+    pub grow_tape: bool,
+    /// Most cells a growing tape can have. Going past it is a pointer
+    /// overflow. Default: [`DEFAULT_MAX_TAPE_SIZE`].
+    pub max_tape_size: usize,
+    /// Width of the tape cells, which arithmetic wraps at. Only
+    /// [`WideProgram`] runs cells wider than 8 bits; [`Program`] folds
+    /// arithmetic modulo 256 when compiling, and its runs fail with
+    /// [`ExecutionError::UnsupportedCellWidth`] for any other width.
+    /// Default: Eight.
+    pub cell_width: CellWidth,
     /// Maximum number of operations before aborting. None = unlimited.
     pub op_limit: Option<usize>,
+    /// Longest a run may take before failing with
+    /// [`ExecutionError::TimeLimit`], measured from when it starts.
+    /// The clock is read about every [`POLL_OPS`] ops, so a run can go
+    /// over by that many ops, and a blocking read or write isn't
+    /// interrupted. Applies alongside `op_limit`, whichever is reached
+    /// first. None = unlimited.
+    pub time_limit: Option<Duration>,
     /// Behavior when input reaches EOF. Default: Zero.
     pub eof_behavior: EofBehavior,
+    /// Once input has reached EOF, treat every later `,` as hitting EOF
+    /// without reading again. With `Zero` and `MaxValue` the cell gets
+    /// the same value it would anyway, but a source whose EOF is
+    /// transient, like a terminal after Ctrl-D, can't block or supply
+    /// more input. With `Unchanged` the cell is left as is, so a read
+    /// loop needs another way out. Default: false.
+    pub eof_marks_done: bool,
+    /// How input is decoded. Default: Bytes.
+    pub input_encoding: InputEncoding,
     /// Whether to flush output after each write. Default: true.
     pub flush_output: bool,
+    /// How ops are counted against `op_limit`. Default: Uniform.
+    pub cost_model: CostModel,
+    /// What to do when `op_limit` is exceeded. Default: Error.
+    pub op_limit_action: OpLimitAction,
+    /// What to do when writing output fails. Default: Fail.
+    pub on_output_error: OutputErrorAction,
+    /// Maximum number of times a loop may iterate each time it is
+    /// entered, counting the first pass. Loops the compiler turns into
+    /// single ops, like clears, scans and multiplications, aren't
+    /// counted. None = unlimited.
+    pub max_loop_iterations: Option<usize>,
+    /// Translation applied to newlines in output. Default: None.
+    pub newline_translation: NewlineTranslation,
+    /// Fills the tape with pseudo-random nonzero bytes from this seed
+    /// instead of zeros, to flush out programs that rely on unwritten
+    /// cells being zero. A supplied tape keeps its contents and only
+    /// the padding is filled. Default: None.
+    pub poison_tape: Option<u64>,
+    /// What [`Program::run_with_report`] reports. Default: nothing.
+    pub report: ReportOptions,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
             tape_size: DEFAULT_TAPE_SIZE,
+            wrap_pointer: false,
+            grow_tape: false,
+            max_tape_size: DEFAULT_MAX_TAPE_SIZE,
+            cell_width: CellWidth::Eight,
             op_limit: None,
+            time_limit: None,
             eof_behavior: EofBehavior::Zero,
+            eof_marks_done: false,
+            input_encoding: InputEncoding::Bytes,
             flush_output: true,
+            cost_model: CostModel::Uniform,
+            op_limit_action: OpLimitAction::Error,
+            on_output_error: OutputErrorAction::Fail,
+            max_loop_iterations: None,
+            newline_translation: NewlineTranslation::None,
+            poison_tape: None,
+            report: ReportOptions::default(),
+        }
+    }
+}
+
+// The name a unit enum variant has in serialized configs
+fn variant_name<T: Serialize>(value: &T) -> String {
+    match serde_json::to_value(value) {
+        Ok(serde_json::Value::String(s)) => s,
+        _ => unreachable!("not a unit variant"),
+    }
+}
+
+impl Config {
+    /// Describes every setting on one line, for logging what a run
+    /// used, e.g. `tape=30000 op_limit=none eof=zero ...`. Enum values
+    /// are named as in serialized configs.
+    pub fn summary(&self) -> String {
+        fn opt<T: std::fmt::Display>(value: Option<T>) -> String {
+            value.map_or_else(|| "none".to_string(), |v| v.to_string())
+        }
+        fn on_off(value: bool) -> &'static str {
+            if value {
+                "on"
+            } else {
+                "off"
+            }
+        }
+
+        let r = &self.report;
+        let report: Vec<&str> = [
+            (r.op_count, "op_count"),
+            (r.max_pointer, "max_pointer"),
+            (r.io_bytes, "io_bytes"),
+            (r.op_counts, "op_counts"),
+            (r.cell_profile, "cell_profile"),
+            (r.pointer_path, "pointer_path"),
+        ]
+        .iter()
+        .filter(|(on, _)| *on)
+        .map(|(_, name)| *name)
+        .collect();
+
+        format!(
+            "tape={} wrap={} grow={} max_tape={} cells={} op_limit={} time_limit={} \
+             eof={} eof_marks_done={} encoding={} flush={} cost={} on_limit={} \
+             on_output_error={} loop_limit={} newlines={} poison={} report={}",
+            self.tape_size,
+            on_off(self.wrap_pointer),
+            on_off(self.grow_tape),
+            self.max_tape_size,
+            variant_name(&self.cell_width),
+            opt(self.op_limit),
+            opt(self
+                .time_limit
+                .map(|limit| format!("{}ms", limit.as_millis()))),
+            variant_name(&self.eof_behavior),
+            on_off(self.eof_marks_done),
+            variant_name(&self.input_encoding),
+            on_off(self.flush_output),
+            variant_name(&self.cost_model),
+            variant_name(&self.op_limit_action),
+            variant_name(&self.on_output_error),
+            opt(self.max_loop_iterations),
+            variant_name(&self.newline_translation),
+            opt(self.poison_tape),
+            if report.is_empty() {
+                "none".to_string()
+            } else {
+                report.join(",")
+            },
+        )
+    }
+
+    /// Estimates the most memory in bytes that running `program` with
+    /// this config allocates, to turn runs away before they run out.
+    ///
+    /// Counts the tape, the executor's per-op tables and the buffers
+    /// of enabled reports, rounding up where they can grow. The program
+    /// itself and I/O buffers aren't included. A supplied tape longer
+    /// than `tape_size` takes more. A growing tape counts as
+    /// `max_tape_size` cells. Recording the pointer path without an
+    /// `op_limit` has no bound, giving `usize::MAX`.
+    pub fn estimated_memory(&self, program: &Program) -> usize {
+        use std::mem::size_of;
+
+        let ops = program.ops.len();
+        let loops = program
+            .ops
+            .iter()
+            .filter(|op| matches!(op, Op::Open(_)))
+            .count();
+        let cells = if self.grow_tape && !self.wrap_pointer {
+            self.tape_size.max(self.max_tape_size)
+        } else {
+            self.tape_size
+        };
+        // A grown tape may have room for twice its cells
+        let tape = if cells > self.tape_size {
+            cells.saturating_mul(2)
+        } else {
+            cells
+        };
+        let mut total =
+            tape.saturating_add(ops.saturating_mul(size_of::<Option<analysis::Block>>()));
+        if self.max_loop_iterations.is_some() {
+            total = total.saturating_add(loops.saturating_mul(size_of::<usize>()));
+        }
+        if self.report.op_counts {
+            total = total.saturating_add(ops.saturating_mul(size_of::<usize>()));
+        }
+        if self.report.cell_profile {
+            total = total.saturating_add(cells.saturating_mul(size_of::<u64>()));
+        }
+        if self.report.pointer_path {
+            // One entry per op run, with room for the vector to double
+            let path = match self.op_limit {
+                Some(limit) => limit.saturating_mul(2 * size_of::<usize>()),
+                None => usize::MAX,
+            };
+            total = total.saturating_add(path);
         }
+        total
+    }
+}
+
+/// Builds a [`Config`] by changing only the settings that differ from
+/// the defaults.
+#[derive(Clone, Debug, Default)]
+pub struct ConfigBuilder {
+    config: Config,
+}
+
+impl ConfigBuilder {
+    /// Starts from [`Config::default`].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets [`Config::tape_size`].
+    pub fn tape_size(mut self, tape_size: usize) -> Self {
+        self.config.tape_size = tape_size;
+        self
+    }
+
+    /// Sets [`Config::wrap_pointer`].
+    pub fn wrap_pointer(mut self, wrap_pointer: bool) -> Self {
+        self.config.wrap_pointer = wrap_pointer;
+        self
+    }
+
+    /// Sets [`Config::grow_tape`].
+    pub fn grow_tape(mut self, grow_tape: bool) -> Self {
+        self.config.grow_tape = grow_tape;
+        self
+    }
+
+    /// Sets [`Config::max_tape_size`].
+    pub fn max_tape_size(mut self, max_tape_size: usize) -> Self {
+        self.config.max_tape_size = max_tape_size;
+        self
+    }
+
+    /// Sets [`Config::cell_width`].
+    pub fn cell_width(mut self, cell_width: CellWidth) -> Self {
+        self.config.cell_width = cell_width;
+        self
+    }
+
+    /// Sets [`Config::op_limit`].
+    pub fn op_limit(mut self, op_limit: Option<usize>) -> Self {
+        self.config.op_limit = op_limit;
+        self
+    }
+
+    /// Sets [`Config::eof_behavior`].
+    pub fn eof_behavior(mut self, eof_behavior: EofBehavior) -> Self {
+        self.config.eof_behavior = eof_behavior;
+        self
+    }
+
+    /// Sets [`Config::eof_marks_done`].
+    pub fn eof_marks_done(mut self, eof_marks_done: bool) -> Self {
+        self.config.eof_marks_done = eof_marks_done;
+        self
+    }
+
+    /// Sets [`Config::input_encoding`].
+    pub fn input_encoding(mut self, input_encoding: InputEncoding) -> Self {
+        self.config.input_encoding = input_encoding;
+        self
+    }
+
+    /// Sets [`Config::flush_output`].
+    pub fn flush_output(mut self, flush_output: bool) -> Self {
+        self.config.flush_output = flush_output;
+        self
+    }
+
+    /// Sets [`Config::cost_model`].
+    pub fn cost_model(mut self, cost_model: CostModel) -> Self {
+        self.config.cost_model = cost_model;
+        self
+    }
+
+    /// Sets [`Config::op_limit_action`].
+    pub fn op_limit_action(mut self, op_limit_action: OpLimitAction) -> Self {
+        self.config.op_limit_action = op_limit_action;
+        self
+    }
+
+    /// Sets [`Config::on_output_error`].
+    pub fn on_output_error(mut self, on_output_error: OutputErrorAction) -> Self {
+        self.config.on_output_error = on_output_error;
+        self
+    }
+
+    /// Sets [`Config::time_limit`].
+    pub fn time_limit(mut self, time_limit: Option<Duration>) -> Self {
+        self.config.time_limit = time_limit;
+        self
+    }
+
+    /// Sets [`Config::max_loop_iterations`].
+    pub fn max_loop_iterations(mut self, max_loop_iterations: Option<usize>) -> Self {
+        self.config.max_loop_iterations = max_loop_iterations;
+        self
+    }
+
+    /// Sets [`Config::newline_translation`].
+    pub fn newline_translation(mut self, newline_translation: NewlineTranslation) -> Self {
+        self.config.newline_translation = newline_translation;
+        self
+    }
+
+    /// Sets [`Config::poison_tape`].
+    pub fn poison_tape(mut self, poison_tape: Option<u64>) -> Self {
+        self.config.poison_tape = poison_tape;
+        self
+    }
+
+    /// Sets [`Config::report`].
+    pub fn report(mut self, report: ReportOptions) -> Self {
+        self.config.report = report;
+        self
+    }
+
+    /// Returns the config.
+    pub fn build(self) -> Config {
+        self.config
     }
 }
 
+/// Configuration for compilation.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CompileOptions {
+    /// Starts a comment running to the end of the line. Everything in
+    /// the comment is ignored, including brackets, so prose containing
+    /// `[` or `]` can't unbalance the program. Default: None.
+    pub line_comment: Option<u8>,
+    /// Compiles to a halt instruction, which ends the program
+    /// immediately, even inside a loop. Default: None.
+    pub halt_char: Option<u8>,
+    /// Compiles to a debug breakpoint, which calls the callback passed
+    /// to [`Program::run_with_debug`] and does nothing otherwise.
+    /// Usually `#`. Default: None.
+    pub debug_char: Option<u8>,
+    /// Hints that the final tape won't be looked at, as with
+    /// [`Program::run_discard_state`]. Enables a diagnostic for writes
+    /// at the end of the program that nothing can observe. Default:
+    /// false.
+    pub tape_unobserved: bool,
+    /// Hints that the program starts on a zeroed tape, so a loop
+    /// before anything that can change the first cell is never entered
+    /// and is dropped. Not for programs run on a supplied tape or with
+    /// [`Config::poison_tape`]. Default: false.
+    pub tape_starts_zeroed: bool,
+    /// Rejects bytes that aren't commands with
+    /// [`CompileError::UnexpectedChar`], instead of ignoring them as
+    /// comments. Whitespace, the characters set in these options, and
+    /// text in line comments are still allowed. Default: false.
+    pub strict_chars: bool,
+    /// With `strict_chars`, rejects whitespace too. Default: false.
+    pub strict_whitespace: bool,
+}
+
 /// References a location in source code.
-#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Span {
     pub start: usize,
     pub end: usize,
@@ -50,49 +533,116 @@ pub struct Span {
     pub col: usize,
 }
 
+/// What the pointer was doing when it left the tape
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind {
+    /// The pointer itself moved out of bounds
+    Move,
+    /// A scan loop ran off the tape looking for its sentinel
+    ScanTarget,
+    /// A multiplication loop wrote to a cell outside the tape
+    MulTarget,
+}
+
 /// Runtime error
 #[derive(Debug)]
 pub enum ExecutionError {
     PointerUnderflow {
         span: Span,
+        kind: AccessKind,
     },
     PointerOverflow {
         span: Span,
         pointer: usize,
         tape_len: usize,
+        kind: AccessKind,
     },
     OperationLimit {
         span: Span,
     },
+    /// A loop iterated more than `Config::max_loop_iterations` times.
+    /// The span is that of the loop's `[`.
+    LoopIterationLimit {
+        span: Span,
+    },
+    /// A scan on a wrapping tape found no zero cell anywhere it can
+    /// reach, so its loop would never end.
+    EndlessScan {
+        span: Span,
+    },
+    /// The run was cancelled through the flag passed to
+    /// [`Program::run_cancellable`]. The span is that of the next op
+    /// that would have run.
+    Cancelled {
+        span: Span,
+    },
+    /// A `,` reached EOF under [`EofBehavior::Error`].
+    UnexpectedEof {
+        span: Span,
+    },
+    /// The run took longer than [`Config::time_limit`]. The span is
+    /// that of the next op that would have run.
+    TimeLimit {
+        span: Span,
+    },
     IoError {
         span: Span,
         source: std::io::Error,
     },
+    /// Ops passed to [`run_ops`] failed validation.
+    InvalidOps {
+        index: usize,
+        reason: &'static str,
+    },
+    /// [`Config::cell_width`] isn't [`CellWidth::Eight`]. Only
+    /// [`WideProgram`] runs wider cells.
+    UnsupportedCellWidth {
+        width: CellWidth,
+    },
 }
 
 impl PartialEq for ExecutionError {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
             (
-                ExecutionError::PointerUnderflow { span: a },
-                ExecutionError::PointerUnderflow { span: b },
-            ) => a == b,
+                ExecutionError::PointerUnderflow { span: a, kind: ka },
+                ExecutionError::PointerUnderflow { span: b, kind: kb },
+            ) => a == b && ka == kb,
             (
                 ExecutionError::PointerOverflow {
                     span: a,
                     pointer: pa,
                     tape_len: ta,
+                    kind: ka,
                 },
                 ExecutionError::PointerOverflow {
                     span: b,
                     pointer: pb,
                     tape_len: tb,
+                    kind: kb,
                 },
-            ) => a == b && pa == pb && ta == tb,
+            ) => a == b && pa == pb && ta == tb && ka == kb,
             (
                 ExecutionError::OperationLimit { span: a },
                 ExecutionError::OperationLimit { span: b },
             ) => a == b,
+            (
+                ExecutionError::LoopIterationLimit { span: a },
+                ExecutionError::LoopIterationLimit { span: b },
+            ) => a == b,
+            (ExecutionError::EndlessScan { span: a }, ExecutionError::EndlessScan { span: b }) => {
+                a == b
+            }
+            (ExecutionError::Cancelled { span: a }, ExecutionError::Cancelled { span: b }) => {
+                a == b
+            }
+            (ExecutionError::TimeLimit { span: a }, ExecutionError::TimeLimit { span: b }) => {
+                a == b
+            }
+            (
+                ExecutionError::UnexpectedEof { span: a },
+                ExecutionError::UnexpectedEof { span: b },
+            ) => a == b,
             (
                 ExecutionError::IoError {
                     span: a,
@@ -103,6 +653,20 @@ impl PartialEq for ExecutionError {
                     source: sb,
                 },
             ) => a == b && sa.kind() == sb.kind(),
+            (
+                ExecutionError::InvalidOps {
+                    index: a,
+                    reason: ra,
+                },
+                ExecutionError::InvalidOps {
+                    index: b,
+                    reason: rb,
+                },
+            ) => a == b && ra == rb,
+            (
+                ExecutionError::UnsupportedCellWidth { width: a },
+                ExecutionError::UnsupportedCellWidth { width: b },
+            ) => a == b,
             _ => false,
         }
     }
@@ -111,22 +675,33 @@ impl PartialEq for ExecutionError {
 impl std::fmt::Display for ExecutionError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            ExecutionError::PointerUnderflow { span } => {
+            ExecutionError::PointerUnderflow { span, kind } => {
+                let what = match kind {
+                    AccessKind::Move => "pointer moved out of bounds",
+                    AccessKind::ScanTarget => "scan ran past the start of the tape",
+                    AccessKind::MulTarget => "multiplication target out of bounds",
+                };
                 write!(
                     f,
-                    "pointer underflow at line {}, column {}",
-                    span.line, span.col
+                    "pointer underflow: {} at line {}, column {}",
+                    what, span.line, span.col
                 )
             }
             ExecutionError::PointerOverflow {
                 span,
                 pointer,
                 tape_len,
+                kind,
             } => {
+                let what = match kind {
+                    AccessKind::Move => "position",
+                    AccessKind::ScanTarget => "scan position",
+                    AccessKind::MulTarget => "multiplication target",
+                };
                 write!(
                     f,
-                    "pointer overflow: position {} exceeds tape length {} (at line {}, column {})",
-                    pointer, tape_len, span.line, span.col
+                    "pointer overflow: {} {} exceeds tape length {} (at line {}, column {})",
+                    what, pointer, tape_len, span.line, span.col
                 )
             }
             ExecutionError::OperationLimit { span } => {
@@ -136,6 +711,37 @@ impl std::fmt::Display for ExecutionError {
                     span.line, span.col
                 )
             }
+            ExecutionError::LoopIterationLimit { span } => {
+                write!(
+                    f,
+                    "loop iteration limit exceeded for loop at line {}, column {}",
+                    span.line, span.col
+                )
+            }
+            ExecutionError::EndlessScan { span } => {
+                write!(
+                    f,
+                    "endless loop: scan finds no zero cell at line {}, column {}",
+                    span.line, span.col
+                )
+            }
+            ExecutionError::Cancelled { span } => {
+                write!(f, "cancelled at line {}, column {}", span.line, span.col)
+            }
+            ExecutionError::UnexpectedEof { span } => {
+                write!(
+                    f,
+                    "unexpected end of input at line {}, column {}",
+                    span.line, span.col
+                )
+            }
+            ExecutionError::TimeLimit { span } => {
+                write!(
+                    f,
+                    "time limit exceeded at line {}, column {}",
+                    span.line, span.col
+                )
+            }
             ExecutionError::IoError { span, source } => {
                 write!(
                     f,
@@ -143,6 +749,12 @@ impl std::fmt::Display for ExecutionError {
                     span.line, span.col, source
                 )
             }
+            ExecutionError::InvalidOps { index, reason } => {
+                write!(f, "invalid op at index {}: {}", index, reason)
+            }
+            ExecutionError::UnsupportedCellWidth { width } => {
+                write!(f, "unsupported cell width: {}", variant_name(width))
+            }
         }
     }
 }
@@ -159,8 +771,33 @@ impl std::error::Error for ExecutionError {
 /// Compilation error
 #[derive(Debug, PartialEq)]
 pub enum CompileError {
-    UnmatchedOpen { span: Span },
-    UnmatchedClose { span: Span },
+    UnmatchedOpen {
+        span: Span,
+    },
+    UnmatchedClose {
+        span: Span,
+    },
+    SourceTooLarge {
+        size: usize,
+        limit: usize,
+    },
+    /// A byte that isn't a command, under [`CompileOptions::strict_chars`].
+    UnexpectedChar {
+        span: Span,
+        ch: u8,
+    },
+    /// Ops from [`Program::map_ops`] fail the checks described in
+    /// [`ExecutionError::InvalidOps`].
+    InvalidOps {
+        index: usize,
+        reason: &'static str,
+    },
+    /// A fragment passed to [`Program::from_fragments`] failed to
+    /// compile. Spans in `error` are positions in the joined source.
+    InFragment {
+        fragment: usize,
+        error: Box<CompileError>,
+    },
 }
 
 impl std::fmt::Display for CompileError {
@@ -180,14 +817,185 @@ impl std::fmt::Display for CompileError {
                     span.line, span.col
                 )
             }
+            CompileError::SourceTooLarge { size, limit } => {
+                write!(
+                    f,
+                    "source too large: {} bytes (limit {} bytes)",
+                    size, limit
+                )
+            }
+            CompileError::UnexpectedChar { span, ch } => {
+                if ch.is_ascii_graphic() {
+                    write!(f, "unexpected character '{}'", *ch as char)?;
+                } else {
+                    write!(f, "unexpected byte 0x{:02x}", ch)?;
+                }
+                write!(f, " at line {}, column {}", span.line, span.col)
+            }
+            CompileError::InvalidOps { index, reason } => {
+                write!(f, "invalid op at index {}: {}", index, reason)
+            }
+            CompileError::InFragment { fragment, error } => {
+                write!(f, "fragment {}: {}", fragment, error)
+            }
         }
     }
 }
 
-impl std::error::Error for CompileError {}
+impl std::error::Error for CompileError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CompileError::InFragment { error, .. } => Some(&**error),
+            _ => None,
+        }
+    }
+}
 
-/// Bytecode instruction.
+/// Error from running a program stored on the tape.
+#[derive(Debug, PartialEq)]
+pub enum TapeProgramError {
+    /// The host program failed.
+    Host(ExecutionError),
+    /// The source found on the tape failed to compile.
+    Compile(CompileError),
+    /// The program found on the tape failed.
+    Execution(ExecutionError),
+}
+
+impl std::fmt::Display for TapeProgramError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TapeProgramError::Host(e) => write!(f, "host program: {}", e),
+            TapeProgramError::Compile(e) => write!(f, "tape program: {}", e),
+            TapeProgramError::Execution(e) => write!(f, "tape program: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for TapeProgramError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TapeProgramError::Host(e) | TapeProgramError::Execution(e) => Some(e),
+            TapeProgramError::Compile(e) => Some(e),
+        }
+    }
+}
+
+/// Error from loading a program with [`Program::from_compiled_bytes`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BytecodeError {
+    /// The bytes don't start like a compiled program.
+    NotBytecode,
+    /// The program was saved in a format version this build can't
+    /// read.
+    UnsupportedVersion(u8),
+    /// The bytes end partway through the program.
+    Truncated,
+    /// A malformed field at the given byte offset, or bytes left over
+    /// after the program.
+    Invalid { offset: usize },
+    /// The ops fail the checks described in
+    /// [`ExecutionError::InvalidOps`].
+    InvalidOps { index: usize, reason: &'static str },
+}
+
+impl std::fmt::Display for BytecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BytecodeError::NotBytecode => write!(f, "not a compiled program"),
+            BytecodeError::UnsupportedVersion(version) => {
+                write!(f, "unsupported compiled program version {}", version)
+            }
+            BytecodeError::Truncated => write!(f, "compiled program is truncated"),
+            BytecodeError::Invalid { offset } => {
+                write!(f, "invalid compiled program at byte {}", offset)
+            }
+            BytecodeError::InvalidOps { index, reason } => {
+                write!(f, "invalid op at index {}: {}", index, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for BytecodeError {}
+
+/// Kind of problem reported by a [`Diagnostic`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiagnosticKind {
+    /// A run of `+` or `-` whose length is a multiple of 256, which
+    /// wraps around to no effect.
+    NetZeroAdd,
+    /// A loop of only `+`, `-`, `<` and `>` that doesn't return to the
+    /// cell it started on, which often means a `<` or `>` is missing.
+    UnbalancedLoop,
+    /// A `[]` that isn't known to be skipped, which never terminates if
+    /// entered.
+    EmptyLoop,
+    /// Writes at the end of the program that no output follows. Only
+    /// reported with [`CompileOptions::tape_unobserved`].
+    TrailingWrites,
+    /// A loop holding only an even run of `+` or `-`, like `[--]`,
+    /// which wraps past zero and never terminates on some values.
+    EvenClearLoop,
+}
+
+/// How serious a [`Diagnostic`] is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    /// Often a mistake, but fine in some programs.
+    Warning,
+    /// Always a bug if the code is reached.
+    Error,
+}
+
+impl DiagnosticKind {
+    /// How serious this kind of diagnostic is. Only an empty loop is
+    /// an error, since it can never finish once entered.
+    pub fn severity(self) -> Severity {
+        match self {
+            DiagnosticKind::EmptyLoop => Severity::Error,
+            DiagnosticKind::NetZeroAdd
+            | DiagnosticKind::UnbalancedLoop
+            | DiagnosticKind::TrailingWrites
+            | DiagnosticKind::EvenClearLoop => Severity::Warning,
+        }
+    }
+}
+
+/// A likely mistake found in source code that doesn't prevent it from
+/// compiling.
 #[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub kind: DiagnosticKind,
+    pub span: Span,
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let message = match self.kind {
+            DiagnosticKind::NetZeroAdd => "net-zero add: run of +/- wraps around to no effect",
+            DiagnosticKind::UnbalancedLoop => {
+                "unbalanced loop: loop body has nonzero net pointer movement"
+            }
+            DiagnosticKind::EmptyLoop => "empty loop may not terminate",
+            DiagnosticKind::TrailingWrites => "trailing writes with no output may be dead",
+            DiagnosticKind::EvenClearLoop => "clear loop with even decrement may not terminate",
+        };
+        write!(
+            f,
+            "{} at line {}, column {}",
+            message, self.span.line, self.span.col
+        )
+    }
+}
+
+/// Bytecode instruction.
+///
+/// Serializes as `{"op": "<name>", "args": <operands>}`, where `args` is
+/// a single number, an array for ops with several operands, or absent
+/// for ops without operands.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", content = "args")]
 pub enum Op {
     Add(u8),
     Move(i32),
@@ -198,49 +1006,1000 @@ pub enum Op {
     Set(u8),
     Mul(i32, u8),
     Scan(i32),
+    Halt,
+    Debug,
+}
+
+/// The source construct an op was compiled from, as listed by
+/// [`Program::op_origins`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OpOrigin {
+    /// Commands written out in the source.
+    Direct,
+    /// A multiplication loop like `[->++<]`.
+    MulLoop,
+    /// A scan loop like `[>]`.
+    ScanLoop,
+    /// A clear loop like `[-]`.
+    ClearLoop,
 }
 
 /// A compiled brainfuck program ready for execution.
+#[derive(Clone, Debug)]
 pub struct Program {
+    /// The compiled ops. After editing them directly, call
+    /// [`Program::relink_loops`] before running the program, which also
+    /// updates what the program has worked out about them. Spans stay
+    /// with the indices they were at, so ops added or moved this way
+    /// are better rewritten with [`Program::map_ops`].
     pub ops: Vec<Op>,
-    pub spans: Vec<Span>,
+    /// Warnings found while compiling.
+    pub diagnostics: Vec<Diagnostic>,
+    // Where each op came from, turned into a Span through the line
+    // index only when one is asked for
+    ranges: Vec<lines::SourceRange>,
+    lines: lines::LineIndex,
+    origins: Vec<OpOrigin>,
+    // Straight-line runs in ops, found once so runs don't redo it
+    blocks: Vec<Option<analysis::Block>>,
+    // Where each fragment starts in the joined source, for programs
+    // built with from_fragments. Empty otherwise.
+    fragment_starts: Vec<usize>,
+}
+
+/// Compiles a program incrementally as source is appended.
+///
+/// Useful for editors that would otherwise recompile the whole program
+/// on every keystroke. Source can only be appended, since the optimizer
+/// may already have rewritten ops produced by earlier source.
+pub struct ProgramBuilder {
+    compiler: compile::Compiler,
+}
+
+impl Default for ProgramBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ProgramBuilder {
+    /// Creates a builder with no source.
+    pub fn new() -> Self {
+        Self::with_options(&CompileOptions::default())
+    }
+
+    /// Creates a builder with no source and the given options.
+    pub fn with_options(options: &CompileOptions) -> Self {
+        Self {
+            compiler: compile::Compiler::new(options),
+        }
+    }
+
+    /// Appends a single character. On error the character is not appended.
+    pub fn push_char(&mut self, c: char) -> Result<(), CompileError> {
+        let mut buf = [0u8; 4];
+        self.push_str(c.encode_utf8(&mut buf))
+    }
+
+    /// Appends a string. On error, the string is appended up to but
+    /// not including the offending character.
+    pub fn push_str(&mut self, s: &str) -> Result<(), CompileError> {
+        for &byte in s.as_bytes() {
+            self.compiler.push_byte(byte)?;
+        }
+        Ok(())
+    }
+
+    /// Finishes compilation, checking that all loops are closed.
+    pub fn build(self) -> Result<Program, CompileError> {
+        self.compiler.finish()
+    }
 }
 
 /// State of the machine after execution.
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct ExecutionResult {
     pub tape: Vec<u8>,
     pub pointer: usize,
+    /// Whether execution stopped early because output couldn't be
+    /// written, under [`OutputErrorAction::Stop`].
+    pub output_truncated: bool,
+    /// Number of ops run. These are the ops of the compiled program,
+    /// after optimization, not brainfuck commands: a clear loop run
+    /// once counts as one op. Counted the way `op_limit` counts them,
+    /// so scans and multiplications count extra under
+    /// [`CostModel::Weighted`].
+    pub ops_executed: usize,
 }
 
-impl Program {
-    /// Compiles source code into a program.
-    pub fn from_source(source: &str) -> Result<Self, CompileError> {
-        let (ops, spans) = compile::compile(source)?;
-        Ok(Self { ops, spans })
+/// Cells of one of the widths in [`CellWidth`], as left by a
+/// [`WideProgram`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Tape {
+    Eight(Vec<u8>),
+    Sixteen(Vec<u16>),
+    ThirtyTwo(Vec<u32>),
+}
+
+impl Tape {
+    pub fn width(&self) -> CellWidth {
+        match self {
+            Tape::Eight(_) => CellWidth::Eight,
+            Tape::Sixteen(_) => CellWidth::Sixteen,
+            Tape::ThirtyTwo(_) => CellWidth::ThirtyTwo,
+        }
     }
 
-    /// Runs the program with the given configuration.
-    pub fn run(
-        &self,
-        config: &Config,
-        tape: Option<Vec<u8>>,
-        pointer: Option<usize>,
-        input: Option<&mut dyn Read>,
-        output: Option<&mut dyn Write>,
-    ) -> Result<ExecutionResult, ExecutionError> {
-        let tape = tape.unwrap_or_else(|| vec![0u8; config.tape_size]);
-        let pointer = pointer.unwrap_or(0);
-        execute::execute(&self.ops, &self.spans, tape, pointer, config, input, output)
+    pub fn len(&self) -> usize {
+        match self {
+            Tape::Eight(cells) => cells.len(),
+            Tape::Sixteen(cells) => cells.len(),
+            Tape::ThirtyTwo(cells) => cells.len(),
+        }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 
-    #[test]
-    fn test_hello_world() {
+    /// The value of the cell at `index`, or None past the end.
+    pub fn get(&self, index: usize) -> Option<u32> {
+        match self {
+            Tape::Eight(cells) => cells.get(index).map(|&c| c.into()),
+            Tape::Sixteen(cells) => cells.get(index).map(|&c| c.into()),
+            Tape::ThirtyTwo(cells) => cells.get(index).copied(),
+        }
+    }
+}
+
+/// Measurements from [`Program::run_with_report`]. Fields not enabled
+/// in [`ReportOptions`] are None.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RunReport {
+    /// Ops executed, counted the way `op_limit` counts them.
+    pub op_count: Option<usize>,
+    /// Highest cell the pointer visited.
+    pub max_pointer: Option<usize>,
+    /// Bytes read from input.
+    pub input_bytes: Option<usize>,
+    /// Bytes written to output, after newline translation.
+    pub output_bytes: Option<usize>,
+    /// Number of times each op was executed, indexed like
+    /// [`Program::ops`].
+    pub op_counts: Option<Vec<usize>>,
+    /// Number of times each cell was read or written, indexed by cell
+    /// and cut off after the last cell accessed. An op reading and
+    /// writing the same cell, like `+`, counts once.
+    pub cell_profile: Option<Vec<u64>>,
+    /// Pointer position after each executed op, in order. Ops are the
+    /// compiled ones, so runs of moves show up as single steps.
+    pub pointer_path: Option<Vec<usize>>,
+}
+
+/// Tape cells touched by the loop-free prefix of a program.
+///
+/// Offsets are relative to the pointer position when execution starts.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CellUsage {
+    /// Lowest offset the pointer reaches or an op accesses.
+    pub min_offset: i32,
+    /// Highest offset the pointer reaches or an op accesses.
+    pub max_offset: i32,
+    /// Offsets of cells that are written.
+    pub written: BTreeSet<i32>,
+}
+
+/// Direction of an I/O op, as listed by [`Program::io_ops`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IoKind {
+    In,
+    Out,
+}
+
+/// A loop in the compiled program, as listed by [`Program::loops`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LoopInfo {
+    /// Index of the loop's `Open` op.
+    pub open: usize,
+    /// Index of the matching `Close` op.
+    pub close: usize,
+    /// Number of ops between the two.
+    pub body_len: usize,
+    /// Number of loops this one is inside, 0 at the top level.
+    pub depth: usize,
+    /// The loop's source, brackets included.
+    pub span: Span,
+}
+
+// Pads a supplied tape up to the configured size, or creates one.
+fn initial_tape(config: &Config, tape: Option<Vec<u8>>) -> Vec<u8> {
+    let mut tape = tape.unwrap_or_default();
+    let supplied = tape.len();
+    if supplied < config.tape_size {
+        tape.resize(config.tape_size, 0);
+        if let Some(seed) = config.poison_tape {
+            poison(&mut tape[supplied..], seed);
+        }
+    }
+    tape
+}
+
+// Fills cells with nonzero bytes from a splitmix64 sequence.
+fn poison(cells: &mut [u8], seed: u64) {
+    let mut state = seed;
+    for cell in cells {
+        state = state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^= z >> 31;
+        *cell = (z % 255) as u8 + 1;
+    }
+}
+
+/// Runs ops that weren't necessarily produced by the compiler.
+///
+/// Useful for front-ends and tools that generate or transform ops
+/// themselves. The ops are checked before running: there must be one
+/// span per op, every `Open` and `Close` must point at its matching
+/// counterpart, and scans must have a nonzero step. Otherwise behaves
+/// like [`Program::run`].
+pub fn run_ops(
+    ops: &[Op],
+    spans: &[Span],
+    config: &Config,
+    tape: Option<Vec<u8>>,
+    pointer: Option<usize>,
+    input: Option<&mut dyn Read>,
+    output: Option<&mut dyn Write>,
+) -> Result<ExecutionResult, ExecutionError> {
+    execute::validate(ops, lines::Spans::Full(spans))?;
+    let tape = initial_tape(config, tape);
+    let pointer = pointer.unwrap_or(0);
+    execute::execute(ops, spans, tape, pointer, config, input, output)
+}
+
+impl Program {
+    // Builds a program from compiled parts.
+    pub(crate) fn from_parts(
+        ops: Vec<Op>,
+        ranges: Vec<lines::SourceRange>,
+        lines: lines::LineIndex,
+        diagnostics: Vec<Diagnostic>,
+        origins: Vec<OpOrigin>,
+    ) -> Self {
+        let blocks = analysis::straight_line_blocks(&ops);
+        Self {
+            ops,
+            diagnostics,
+            ranges,
+            lines,
+            origins,
+            blocks,
+            fragment_starts: Vec::new(),
+        }
+    }
+
+    // The ops as the executor takes them.
+    pub(crate) fn code(&self) -> execute::Code<'_> {
+        execute::Code::new(&self.ops, self.span_table(), &self.blocks)
+    }
+
+    fn span_table(&self) -> lines::Spans<'_> {
+        lines::Spans::Compact(&self.ranges, &self.lines)
+    }
+
+    /// The span of the op at `index` in [`Program::ops`].
+    ///
+    /// Programs keep only the start and end of each span, and work out
+    /// the line and column from where the source lines start. Panics if
+    /// there is no op at `index`.
+    pub fn span(&self, index: usize) -> Span {
+        self.span_table().get(index)
+    }
+
+    /// The spans of all ops, in order.
+    pub fn spans(&self) -> impl ExactSizeIterator<Item = Span> + '_ {
+        self.ranges.iter().map(|&range| self.lines.span(range))
+    }
+
+    /// Compiles source code into a program.
+    pub fn from_source(source: &str) -> Result<Self, CompileError> {
+        Self::from_source_with_options(source, &CompileOptions::default())
+    }
+
+    /// Compiles source code into a program, refusing sources longer
+    /// than `max_source_bytes`.
+    pub fn from_source_limited(
+        source: &str,
+        max_source_bytes: usize,
+    ) -> Result<Self, CompileError> {
+        if source.len() > max_source_bytes {
+            return Err(CompileError::SourceTooLarge {
+                size: source.len(),
+                limit: max_source_bytes,
+            });
+        }
+        Self::from_source(source)
+    }
+
+    /// Compiles several source fragments into one program, as if they
+    /// were concatenated.
+    ///
+    /// Spans are positions in the joined source, and
+    /// [`Program::fragment_of`] tells which fragment one is in. Each
+    /// fragment must have balanced brackets on its own; an error is
+    /// reported as [`CompileError::InFragment`] with the fragment it is
+    /// in.
+    pub fn from_fragments(fragments: &[&str]) -> Result<Self, CompileError> {
+        compile::compile_fragments(fragments, &CompileOptions::default())
+    }
+
+    /// Index of the fragment passed to [`Program::from_fragments`] that
+    /// `span` starts in. Always 0 for programs built any other way.
+    pub fn fragment_of(&self, span: &Span) -> usize {
+        self.fragment_starts
+            .partition_point(|&start| start <= span.start)
+            .saturating_sub(1)
+    }
+
+    /// Compiles source code into a program using the given options.
+    pub fn from_source_with_options(
+        source: &str,
+        options: &CompileOptions,
+    ) -> Result<Self, CompileError> {
+        compile::compile_bytes(source.as_bytes(), options)
+    }
+
+    /// Runs the program on a caller-owned tape, updating it and the
+    /// pointer in place.
+    ///
+    /// Avoids allocating a tape per run when the same buffer is reused
+    /// across many runs. The tape is used as is, whatever its length;
+    /// `config.tape_size` is ignored. A borrowed tape can't grow, so
+    /// going past its end is a pointer overflow even with
+    /// [`Config::grow_tape`] set; see [`Program::run_in_place_growable`].
+    /// On error, the tape holds whatever the program wrote before
+    /// failing and the pointer is left unchanged.
+    pub fn run_in_place(
+        &self,
+        config: &Config,
+        mut tape: &mut [u8],
+        pointer: &mut usize,
+        input: Option<&mut dyn Read>,
+        output: Option<&mut dyn Write>,
+    ) -> Result<(), ExecutionError> {
+        self.run_on(config, &mut tape, pointer, input, output)
+    }
+
+    /// Like [`Program::run_in_place`], on a tape that is extended with
+    /// zero cells when [`Config::grow_tape`] is set.
+    pub fn run_in_place_growable(
+        &self,
+        config: &Config,
+        tape: &mut Vec<u8>,
+        pointer: &mut usize,
+        input: Option<&mut dyn Read>,
+        output: Option<&mut dyn Write>,
+    ) -> Result<(), ExecutionError> {
+        self.run_on(config, tape, pointer, input, output)
+    }
+
+    fn run_on(
+        &self,
+        config: &Config,
+        tape: &mut impl execute::TapeBuffer,
+        pointer: &mut usize,
+        input: Option<&mut dyn Read>,
+        output: Option<&mut dyn Write>,
+    ) -> Result<(), ExecutionError> {
+        let mut state = execute::State {
+            pointer: *pointer,
+            ..Default::default()
+        };
+        execute::execute_in_place(
+            self.code(),
+            tape,
+            config,
+            input,
+            output,
+            &mut state,
+            &mut execute::NoProbe,
+        )?;
+        *pointer = state.pointer;
+        Ok(())
+    }
+
+    /// Runs the program like [`Program::run`], also returning a report
+    /// on the run.
+    ///
+    /// Only the fields enabled in `config.report` are filled in; the
+    /// rest are left as None. Fields that aren't enabled aren't
+    /// tracked.
+    pub fn run_with_report(
+        &self,
+        config: &Config,
+        tape: Option<Vec<u8>>,
+        pointer: Option<usize>,
+        input: Option<&mut dyn Read>,
+        output: Option<&mut dyn Write>,
+    ) -> Result<(ExecutionResult, RunReport), ExecutionError> {
+        let mut tape = initial_tape(config, tape);
+        let pointer = pointer.unwrap_or(0);
+        let options = &config.report;
+
+        // Only pay for counting I/O when it's asked for
+        let (mut counting_input, mut input) = match (options.io_bytes, input) {
+            (true, Some(inner)) => (Some(adapters::CountingReader { inner, count: 0 }), None),
+            (_, input) => (None, input),
+        };
+        let (mut counting_output, mut output) = match (options.io_bytes, output) {
+            (true, Some(inner)) => (Some(adapters::CountingWriter { inner, count: 0 }), None),
+            (_, output) => (None, output),
+        };
+        let input = match &mut counting_input {
+            Some(reader) => Some(reader as &mut dyn Read),
+            None => input.as_mut().map(|i| &mut **i as &mut dyn Read),
+        };
+        let output = match &mut counting_output {
+            Some(writer) => Some(writer as &mut dyn Write),
+            None => output.as_mut().map(|o| &mut **o as &mut dyn Write),
+        };
+
+        let mut probes = execute::Probes {
+            max_pointer: options.max_pointer.then_some(pointer),
+            op_counts: options.op_counts.then(|| vec![0; self.ops.len()]),
+            cell_counts: options.cell_profile.then(|| vec![0; tape.len()]),
+            pointer_path: options.pointer_path.then(Vec::new),
+        };
+        let mut state = execute::State {
+            pointer,
+            ..Default::default()
+        };
+        execute::execute_in_place(
+            self.code(),
+            &mut tape,
+            config,
+            input,
+            output,
+            &mut state,
+            &mut probes,
+        )?;
+
+        let report = RunReport {
+            op_count: options.op_count.then_some(state.op_count),
+            max_pointer: probes.max_pointer,
+            input_bytes: options
+                .io_bytes
+                .then(|| counting_input.map_or(0, |r| r.count)),
+            output_bytes: options
+                .io_bytes
+                .then(|| counting_output.map_or(0, |w| w.count)),
+            op_counts: probes.op_counts,
+            cell_profile: probes.cell_counts,
+            pointer_path: probes.pointer_path,
+        };
+        let result = ExecutionResult {
+            tape,
+            pointer: state.pointer,
+            output_truncated: state.output_truncated,
+            ops_executed: state.op_count,
+        };
+        Ok((result, report))
+    }
+
+    /// Runs the program with the given configuration.
+    ///
+    /// Starts from the supplied tape and pointer if given, or a zeroed
+    /// tape of `config.tape_size` cells and pointer 0 otherwise. A
+    /// supplied tape shorter than `config.tape_size` is padded with
+    /// zeros; a longer one is used as is.
+    pub fn run(
+        &self,
+        config: &Config,
+        tape: Option<Vec<u8>>,
+        pointer: Option<usize>,
+        input: Option<&mut dyn Read>,
+        output: Option<&mut dyn Write>,
+    ) -> Result<ExecutionResult, ExecutionError> {
+        let mut machine = Machine::new(self, config, tape, pointer);
+        machine.run(input, output)?;
+        Ok(machine.into_result())
+    }
+
+    /// Runs the program like [`Program::run`], but also returns the
+    /// machine state when execution fails.
+    ///
+    /// On error the tape and pointer are as they were just before the
+    /// failing op ran, which helps to see why it failed. For
+    /// [`ExecutionError::OperationLimit`], the op that went over the
+    /// limit has run.
+    pub fn run_with_state(
+        &self,
+        config: &Config,
+        tape: Option<Vec<u8>>,
+        pointer: Option<usize>,
+        input: Option<&mut dyn Read>,
+        output: Option<&mut dyn Write>,
+    ) -> Result<ExecutionResult, (ExecutionError, ExecutionResult)> {
+        let mut machine = Machine::new(self, config, tape, pointer);
+        match machine.run(input, output) {
+            Ok(()) => Ok(machine.into_result()),
+            Err(e) => Err((e, machine.into_result())),
+        }
+    }
+
+    /// Runs the program like [`Program::run`], failing with
+    /// [`ExecutionError::Cancelled`] once `cancel` is set.
+    ///
+    /// The flag is checked before the first op and then about every
+    /// [`POLL_OPS`] ops, counted as for `op_limit`, at the point where
+    /// a loop jumps back to its start. A run can go on for that many
+    /// ops after it's set, and a blocking read or write isn't
+    /// interrupted.
+    pub fn run_cancellable(
+        &self,
+        config: &Config,
+        tape: Option<Vec<u8>>,
+        pointer: Option<usize>,
+        input: Option<&mut dyn Read>,
+        output: Option<&mut dyn Write>,
+        cancel: Arc<AtomicBool>,
+    ) -> Result<ExecutionResult, ExecutionError> {
+        let mut tape = initial_tape(config, tape);
+        let mut state = execute::State {
+            pointer: pointer.unwrap_or(0),
+            cancel: Some(&cancel),
+            ..Default::default()
+        };
+        execute::execute_in_place(
+            self.code(),
+            &mut tape,
+            config,
+            input,
+            output,
+            &mut state,
+            &mut execute::NoProbe,
+        )?;
+        Ok(ExecutionResult {
+            tape,
+            pointer: state.pointer,
+            output_truncated: state.output_truncated,
+            ops_executed: state.op_count,
+        })
+    }
+
+    /// Runs the program like [`Program::run`], calling `debug` with the
+    /// pointer and tape at each debug breakpoint.
+    ///
+    /// Breakpoints are only compiled in when
+    /// [`CompileOptions::debug_char`] is set.
+    pub fn run_with_debug(
+        &self,
+        config: &Config,
+        tape: Option<Vec<u8>>,
+        pointer: Option<usize>,
+        input: Option<&mut dyn Read>,
+        output: Option<&mut dyn Write>,
+        debug: &mut dyn FnMut(usize, &[u8]),
+    ) -> Result<ExecutionResult, ExecutionError> {
+        let mut tape = initial_tape(config, tape);
+        let mut state = execute::State {
+            pointer: pointer.unwrap_or(0),
+            ..Default::default()
+        };
+        execute::execute_in_place(
+            self.code(),
+            &mut tape,
+            config,
+            input,
+            output,
+            &mut state,
+            &mut execute::DebugProbe(debug),
+        )?;
+        Ok(ExecutionResult {
+            tape,
+            pointer: state.pointer,
+            output_truncated: state.output_truncated,
+            ops_executed: state.op_count,
+        })
+    }
+
+    /// Runs at most `max_ops` ops, starting at op `from_ip` with the
+    /// tape and pointer in `state`.
+    ///
+    /// Returns the new state, the index of the next op to run and
+    /// whether the program has finished. Passing these back in continues
+    /// where the segment stopped, so a program can be run in pieces with
+    /// the tape inspected or changed in between. Start at ip 0 with a
+    /// tape from [`Program::run`] or built by hand.
+    ///
+    /// The tape is used as is. `max_ops` takes the place of
+    /// `config.op_limit` and is counted by `config.cost_model`. Loops
+    /// already running at `from_ip` aren't counted against
+    /// `config.max_loop_iterations`.
+    pub fn run_segment(
+        &self,
+        config: &Config,
+        state: ExecutionResult,
+        from_ip: usize,
+        max_ops: usize,
+        input: Option<&mut dyn Read>,
+        output: Option<&mut dyn Write>,
+    ) -> Result<(ExecutionResult, usize, bool), ExecutionError> {
+        let ExecutionResult {
+            mut tape,
+            mut pointer,
+            mut output_truncated,
+            mut ops_executed,
+        } = state;
+        let mut next_ip = from_ip;
+        if max_ops > 0 {
+            // An op limit of n stops after op n + 1
+            let config = Config {
+                op_limit: Some(max_ops - 1),
+                op_limit_action: OpLimitAction::Halt,
+                ..config.clone()
+            };
+            let mut state = execute::State {
+                ip: from_ip,
+                pointer,
+                ..Default::default()
+            };
+            execute::execute_in_place(
+                self.code(),
+                &mut tape,
+                &config,
+                input,
+                output,
+                &mut state,
+                &mut execute::NoProbe,
+            )?;
+            pointer = state.pointer;
+            next_ip = state.ip;
+            output_truncated |= state.output_truncated;
+            ops_executed += state.op_count;
+        }
+        let finished = next_ip >= self.ops.len();
+        let state = ExecutionResult {
+            tape,
+            pointer,
+            output_truncated,
+            ops_executed,
+        };
+        Ok((state, next_ip, finished))
+    }
+
+    /// Runs the program on a fresh tape for its output only.
+    ///
+    /// Like [`Program::run`] without a supplied tape or pointer, but
+    /// the tape is dropped as soon as the program ends instead of being
+    /// returned.
+    pub fn run_discard_state(
+        &self,
+        config: &Config,
+        input: Option<&mut dyn Read>,
+        output: Option<&mut dyn Write>,
+    ) -> Result<(), ExecutionError> {
+        let mut tape = initial_tape(config, None);
+        execute::execute_in_place(
+            self.code(),
+            &mut tape,
+            config,
+            input,
+            output,
+            &mut execute::State::default(),
+            &mut execute::NoProbe,
+        )?;
+        Ok(())
+    }
+
+    /// Runs the program, then runs a second program whose source is
+    /// read from the resulting tape.
+    ///
+    /// The source starts at cell `source_start` and extends up to (not
+    /// including) the next zero cell. It is compiled once and run on the
+    /// tape and pointer left behind by this program, so spans in its
+    /// errors are relative to `source_start`.
+    pub fn run_with_tape_program(
+        &self,
+        config: &Config,
+        tape: Option<Vec<u8>>,
+        pointer: Option<usize>,
+        source_start: usize,
+        mut input: Option<&mut dyn Read>,
+        mut output: Option<&mut dyn Write>,
+    ) -> Result<ExecutionResult, TapeProgramError> {
+        let result = self
+            .run(
+                config,
+                tape,
+                pointer,
+                input.as_mut().map(|i| &mut **i as &mut dyn Read),
+                output.as_mut().map(|o| &mut **o as &mut dyn Write),
+            )
+            .map_err(TapeProgramError::Host)?;
+
+        let region = result.tape.get(source_start..).unwrap_or_default();
+        let len = memchr::memchr(0, region).unwrap_or(region.len());
+        let program = compile::compile_bytes(&region[..len], &CompileOptions::default())
+            .map_err(TapeProgramError::Compile)?;
+
+        program
+            .run(
+                config,
+                Some(result.tape),
+                Some(result.pointer),
+                input,
+                output,
+            )
+            .map_err(TapeProgramError::Execution)
+    }
+
+    /// Runs the program and returns its output as text.
+    ///
+    /// Output that isn't valid UTF-8 is converted lossily.
+    pub fn run_to_string(
+        &self,
+        config: &Config,
+        input: Option<&mut dyn Read>,
+    ) -> Result<String, ExecutionError> {
+        let mut output = Vec::new();
+        self.run(config, None, None, input, Some(&mut output))?;
+        Ok(String::from_utf8_lossy(&output).into_owned())
+    }
+
+    /// Runs the program and returns a hash of its output.
+    ///
+    /// Output is hashed as it is produced rather than collected, so
+    /// large outputs can be compared cheaply. The hash is FNV-1a, so it
+    /// can be stored and compared across builds. See [`HashingWriter`].
+    pub fn run_output_hash(
+        &self,
+        config: &Config,
+        input: Option<&mut dyn Read>,
+    ) -> Result<u64, ExecutionError> {
+        let mut output = HashingWriter::new();
+        self.run(config, None, None, input, Some(&mut output))?;
+        Ok(output.finish())
+    }
+
+    /// Checks whether two programs compiled to the same ops.
+    ///
+    /// Spans are intentionally ignored: they only record where in the
+    /// source an op came from, so differently formatted sources with the
+    /// same behavior compare equal.
+    pub fn ops_eq(&self, other: &Program) -> bool {
+        self.ops == other.ops
+    }
+
+    /// Reports which cells the program touches before its first loop.
+    ///
+    /// Analysis stops at the first op whose behavior depends on tape
+    /// contents, i.e. a loop or a scan.
+    pub fn cell_usage_map(&self) -> CellUsage {
+        analysis::cell_usage(&self.ops)
+    }
+
+    /// Works out the smallest tape the program can run on without
+    /// overflowing, starting from pointer 0.
+    ///
+    /// Returns None if the program has a scan or a loop that moves the
+    /// pointer on each iteration, since those can reach any cell. The
+    /// result may be larger than needed when some ops never run.
+    pub fn min_tape_size(&self) -> Option<usize> {
+        analysis::max_offset(&self.ops).map(|max| max as usize + 1)
+    }
+
+    /// Rewrites the program op by op.
+    ///
+    /// `f` is called with each op and its span, and returns the ops
+    /// and spans to replace it with; returning an empty vector drops
+    /// the op. `Open` and `Close` targets in the result don't need to
+    /// be right, since loops are relinked afterwards with
+    /// [`Program::relink_loops`]. The result is then checked like ops
+    /// passed to [`run_ops`]. If the rewritten brackets don't balance or
+    /// an op is invalid, an error is returned and the program is left
+    /// unchanged. Only the start and end of the returned spans are
+    /// kept, as for [`Program::span`].
+    pub fn map_ops(
+        &mut self,
+        mut f: impl FnMut(&Op, &Span) -> Vec<(Op, Span)>,
+    ) -> Result<(), CompileError> {
+        let mut ops = Vec::with_capacity(self.ops.len());
+        let mut spans = Vec::with_capacity(self.ranges.len());
+        let mut origins = Vec::with_capacity(self.origins.len());
+        for (i, (op, span)) in self.ops.iter().zip(self.spans()).enumerate() {
+            let origin = self.origins.get(i).copied().unwrap_or(OpOrigin::Direct);
+            for (op, span) in f(op, &span) {
+                ops.push(op);
+                spans.push(span);
+                origins.push(origin);
+            }
+        }
+        let span_table = lines::Spans::Full(&spans);
+        compile::relink_loops(&mut ops, span_table)?;
+        if let Err(ExecutionError::InvalidOps { index, reason }) =
+            execute::validate(&ops, span_table)
+        {
+            return Err(CompileError::InvalidOps { index, reason });
+        }
+        self.blocks = analysis::straight_line_blocks(&ops);
+        self.ops = ops;
+        self.ranges = spans.iter().map(lines::SourceRange::of).collect();
+        self.origins = origins;
+        Ok(())
+    }
+
+    /// Recomputes the targets of all `Open` and `Close` ops from the
+    /// bracket structure.
+    ///
+    /// Needed after inserting or removing ops, which shifts indices.
+    /// Fails if the brackets don't balance, leaving the ops unchanged.
+    pub fn relink_loops(&mut self) -> Result<(), CompileError> {
+        let spans = lines::Spans::Compact(&self.ranges, &self.lines);
+        compile::relink_loops(&mut self.ops, spans)?;
+        self.blocks = analysis::straight_line_blocks(&self.ops);
+        Ok(())
+    }
+
+    /// Translates the program to a WebAssembly text module.
+    ///
+    /// The module imports `env.input`, returning the next input byte
+    /// or a negative value at EOF (leaving the cell unchanged), and
+    /// `env.output`, taking the byte to write. It exports a `run`
+    /// function and its `memory`, whose first 65536 bytes are the tape
+    /// with the pointer starting at 0. Moving off the page isn't
+    /// checked. Assemble it with e.g. `wat2wasm`.
+    pub fn to_wat(&self) -> String {
+        transpile::to_wat(&self.ops)
+    }
+
+    /// Translates the program to Rust source for a standalone binary.
+    ///
+    /// The binary reads stdin and writes stdout, with a tape of
+    /// `config.tape_size` cells and the pointer starting at 0. EOF
+    /// follows `config.eof_behavior`, with [`EofBehavior::Error`]
+    /// failing with an I/O error; other settings don't apply. Moving
+    /// off the tape panics. Build it with e.g. `rustc -O`.
+    pub fn to_rust(&self, config: &Config) -> String {
+        transpile::to_rust(&self.ops, config)
+    }
+
+    /// Saves the compiled program, to be loaded again with
+    /// [`Program::from_compiled_bytes`] without compiling the source.
+    ///
+    /// Keeps the ops, spans and op origins. Diagnostics aren't kept.
+    /// Ops added to [`Program::ops`] without a span are saved with an
+    /// empty span where the op before them ends.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bytecode::to_bytes(self)
+    }
+
+    /// Loads a program saved with [`Program::to_bytes`].
+    ///
+    /// The ops are checked like those passed to [`run_ops`], so a
+    /// loaded program is safe to run. It has no diagnostics.
+    pub fn from_compiled_bytes(bytes: &[u8]) -> Result<Self, BytecodeError> {
+        bytecode::from_bytes(bytes)
+    }
+
+    /// Lists the ops one per line, for seeing what the optimizer made
+    /// of the source.
+    ///
+    /// Each line has the op's index, its mnemonic and operands, and
+    /// where in the source it came from, e.g. `0003  MUL +1 x4    (line
+    /// 2, col 5)`. Loop brackets give the index of their partner.
+    pub fn disassemble(&self) -> String {
+        let spans: Vec<Span> = self.spans().collect();
+        transpile::disassemble(&self.ops, &spans)
+    }
+
+    /// Translates the program to C source for a standalone binary.
+    ///
+    /// The binary reads stdin and writes stdout, with a tape of
+    /// `config.tape_size` cells set in a `#define` and the pointer
+    /// starting at 0. EOF follows `config.eof_behavior`, with
+    /// [`EofBehavior::Error`] exiting with status 1; other settings
+    /// don't apply. Moving off the tape isn't checked. Build it with
+    /// e.g. `cc -O2`.
+    pub fn to_c(&self, config: &Config) -> String {
+        transpile::to_c(&self.ops, config)
+    }
+
+    /// Says for each op whether it was written out in the source or
+    /// is what the optimizer made of a loop.
+    ///
+    /// Parallel to [`Program::ops`] as compiled. [`Program::map_ops`]
+    /// keeps it in step, with each new op taking the origin of the op
+    /// it replaces; ops edited directly aren't tracked.
+    pub fn op_origins(&self) -> &[OpOrigin] {
+        &self.origins
+    }
+
+    /// Whether the op at `index` was synthesized by the optimizer
+    /// rather than spelled out in the source.
+    ///
+    /// The span of a synthesized op covers the whole loop it came
+    /// from, and several ops may share it, so tooling pointing at the
+    /// source may want to show it differently. False for an index past
+    /// the end, and follows [`Program::op_origins`] otherwise.
+    pub fn is_synthetic(&self, index: usize) -> bool {
+        self.origins
+            .get(index)
+            .is_some_and(|origin| *origin != OpOrigin::Direct)
+    }
+
+    /// Counts the compiled ops by kind, keyed on the [`Op`] variant
+    /// name. Kinds that don't occur are left out.
+    pub fn op_histogram(&self) -> BTreeMap<&'static str, usize> {
+        analysis::op_histogram(&self.ops)
+    }
+
+    /// Lists the program's input and output ops in order, with their
+    /// index in [`Program::ops`] and their span.
+    pub fn io_ops(&self) -> Vec<(usize, IoKind, Span)> {
+        self.ops
+            .iter()
+            .enumerate()
+            .filter_map(|(i, op)| match op {
+                Op::In => Some((i, IoKind::In, self.span(i))),
+                Op::Out => Some((i, IoKind::Out, self.span(i))),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Lists the loops left in the compiled program, ordered by their
+    /// `Open` op. Loops the optimizer turned into single ops, like
+    /// clears and scans, aren't loops any more and aren't listed.
+    ///
+    /// Nothing is listed if the `Open` and `Close` targets don't match
+    /// how the brackets nest, as when ops were edited without calling
+    /// [`Program::relink_loops`].
+    pub fn loops(&self) -> Vec<LoopInfo> {
+        // Indices into loops of the loops not closed yet
+        let mut unclosed = Vec::new();
+        let mut loops: Vec<LoopInfo> = Vec::new();
+        for (i, op) in self.ops.iter().enumerate() {
+            match *op {
+                Op::Open(close) => {
+                    unclosed.push(loops.len());
+                    loops.push(LoopInfo {
+                        open: i,
+                        close: close as usize,
+                        body_len: 0,
+                        depth: unclosed.len() - 1,
+                        span: self.span(i),
+                    });
+                }
+                Op::Close(open) => {
+                    let Some(index) = unclosed.pop() else {
+                        return Vec::new();
+                    };
+                    let info = &mut loops[index];
+                    if info.close != i || info.open != open as usize {
+                        return Vec::new();
+                    }
+                    info.body_len = i - info.open - 1;
+                    info.span = self.span(i);
+                }
+                _ => {}
+            }
+        }
+        if !unclosed.is_empty() {
+            return Vec::new();
+        }
+        loops
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hello_world() {
         let program = Program::from_source("++++++++[->++[->++++<]<]>>.----[------>+<]>.").unwrap();
         let mut output = Vec::new();
         program
@@ -248,4 +2007,1566 @@ mod tests {
             .unwrap();
         assert_eq!(String::from_utf8(output).unwrap(), "@\n");
     }
+
+    #[test]
+    fn test_run_tee() {
+        let program = Program::from_source("++++++++[>++++++++<-]>+.+.+.").unwrap();
+        let mut first = Vec::new();
+        let mut second = Vec::new();
+        let mut tee = TeeWriter::new(vec![&mut first as &mut dyn Write, &mut second]);
+        program
+            .run(&Config::default(), None, None, None, Some(&mut tee))
+            .unwrap();
+        drop(tee);
+        assert_eq!(first, b"ABC");
+        assert_eq!(second, first);
+    }
+
+    #[test]
+    fn test_run_discard_state() {
+        let program = Program::from_source(include_str!("../tests/programs/echo.b")).unwrap();
+        let config = Config::default();
+
+        let mut output = Vec::new();
+        program
+            .run(
+                &config,
+                None,
+                None,
+                Some(&mut &b"Hi"[..]),
+                Some(&mut output),
+            )
+            .unwrap();
+        let mut discarded_output = Vec::new();
+        program
+            .run_discard_state(&config, Some(&mut &b"Hi"[..]), Some(&mut discarded_output))
+            .unwrap();
+        assert_eq!(output, b"H");
+        assert_eq!(discarded_output, output);
+
+        let program = Program::from_source("<").unwrap();
+        assert_eq!(
+            program.run_discard_state(&config, None, None),
+            Err(ExecutionError::PointerUnderflow {
+                span: program.span(0),
+                kind: AccessKind::Move,
+            })
+        );
+    }
+
+    // Spans in execution errors are worked out from source offsets, and
+    // still point at the right line and column
+    #[test]
+    fn test_execution_error_line_column() {
+        let config = Config::default();
+        let at = |line, col, start, end| Span {
+            start,
+            end,
+            line,
+            col,
+        };
+
+        let program = Program::from_source("+\n  <").unwrap();
+        assert_eq!(
+            program.run_discard_state(&config, None, None),
+            Err(ExecutionError::PointerUnderflow {
+                span: at(2, 3, 4, 5),
+                kind: AccessKind::Move,
+            })
+        );
+
+        let program = Program::from_source("+\r\n\r\n [<]").unwrap();
+        assert_eq!(
+            program.run_discard_state(&config, None, None),
+            Err(ExecutionError::PointerUnderflow {
+                span: at(3, 2, 6, 9),
+                kind: AccessKind::ScanTarget,
+            })
+        );
+
+        let program = Program::from_fragments(&["+[\n-]", "", "  \n>+\n>>"]).unwrap();
+        let config = Config {
+            tape_size: 3,
+            ..Default::default()
+        };
+        assert_eq!(
+            program.run_discard_state(&config, None, None),
+            Err(ExecutionError::PointerOverflow {
+                span: at(4, 1, 11, 13),
+                pointer: 3,
+                tape_len: 3,
+                kind: AccessKind::Move,
+            })
+        );
+    }
+
+    // Programs are compiled for 8-bit cells, so wider ones are refused
+    // rather than run as 8 bits
+    #[test]
+    fn test_unsupported_cell_width() {
+        let program = Program::from_source(&format!("{}.", "+".repeat(257))).unwrap();
+        let config = Config {
+            cell_width: CellWidth::Sixteen,
+            ..Default::default()
+        };
+        let unsupported = ExecutionError::UnsupportedCellWidth {
+            width: CellWidth::Sixteen,
+        };
+        let mut output = Vec::new();
+        assert_eq!(
+            program.run(&config, None, None, None, Some(&mut output)),
+            Err(unsupported)
+        );
+        assert!(output.is_empty());
+
+        let mut machine = Machine::new(&program, &config, None, None);
+        assert_eq!(
+            machine.step(None, None),
+            Err(ExecutionError::UnsupportedCellWidth {
+                width: CellWidth::Sixteen,
+            })
+        );
+        assert_eq!(
+            ExecutionError::UnsupportedCellWidth {
+                width: CellWidth::ThirtyTwo,
+            }
+            .to_string(),
+            "unsupported cell width: thirty_two"
+        );
+    }
+
+    #[test]
+    fn test_run_in_place() {
+        let program = Program::from_source(",>,[-<+>]<.>++").unwrap();
+        let config = Config {
+            tape_size: 8,
+            ..Default::default()
+        };
+        let mut tape = [0u8; 8];
+
+        for input in [b"\x03\x04", b"\x10\x01"] {
+            tape.fill(0);
+            let mut pointer = 0;
+            let mut output = Vec::new();
+            program
+                .run_in_place(
+                    &config,
+                    &mut tape,
+                    &mut pointer,
+                    Some(&mut &input[..]),
+                    Some(&mut output),
+                )
+                .unwrap();
+
+            let mut owned_output = Vec::new();
+            let owned = program
+                .run(
+                    &config,
+                    None,
+                    None,
+                    Some(&mut &input[..]),
+                    Some(&mut owned_output),
+                )
+                .unwrap();
+            assert_eq!(tape[..], owned.tape[..]);
+            assert_eq!(pointer, owned.pointer);
+            assert_eq!(output, owned_output);
+        }
+        assert_eq!(tape[..2], [17, 2]);
+
+        // Only an owned tape grows
+        let program = Program::from_source(">>+").unwrap();
+        let config = Config {
+            grow_tape: true,
+            ..Default::default()
+        };
+        let mut pointer = 0;
+        assert!(matches!(
+            program.run_in_place(&config, &mut tape[..2], &mut pointer, None, None),
+            Err(ExecutionError::PointerOverflow { pointer: 2, .. })
+        ));
+        let mut tape = vec![0u8; 2];
+        program
+            .run_in_place_growable(&config, &mut tape, &mut pointer, None, None)
+            .unwrap();
+        assert_eq!(tape, [0, 0, 1]);
+        assert_eq!(pointer, 2);
+    }
+
+    #[test]
+    fn test_from_source_limited() {
+        let source = "++++++++[>++++++++<-]>+.";
+        let program = Program::from_source_limited(source, 24).unwrap();
+        assert!(program.ops_eq(&Program::from_source(source).unwrap()));
+
+        let err = Program::from_source_limited(source, 23).unwrap_err();
+        assert_eq!(
+            err,
+            CompileError::SourceTooLarge {
+                size: 24,
+                limit: 23
+            }
+        );
+        assert_eq!(
+            err.to_string(),
+            "source too large: 24 bytes (limit 23 bytes)"
+        );
+    }
+
+    #[test]
+    fn test_from_fragments() {
+        let fragments = ["++++++++[->++[->++++<]<]", "\n>>.", "----[------>+<]>."];
+        let program = Program::from_fragments(&fragments).unwrap();
+        let mut output = Vec::new();
+        program
+            .run(&Config::default(), None, None, None, Some(&mut output))
+            .unwrap();
+        assert_eq!(output, b"@\n");
+
+        // Spans are positions in the joined source
+        let span_of = |op: Op| {
+            let i = program.ops.iter().position(|o| *o == op).unwrap();
+            program.span(i)
+        };
+        let span = span_of(Op::Mul(1, 4));
+        assert_eq!(
+            (program.fragment_of(&span), span.start, span.line),
+            (0, 13, 1)
+        );
+        let span = span_of(Op::Move(2));
+        assert_eq!(
+            (program.fragment_of(&span), span.start, span.line, span.col),
+            (1, 25, 2, 1)
+        );
+        let span = span_of(Op::Add(252));
+        assert_eq!(
+            (program.fragment_of(&span), span.start, span.end, span.col),
+            (2, 28, 32, 4)
+        );
+
+        // Empty fragments take up no source
+        let program = Program::from_fragments(&["+", "", "-"]).unwrap();
+        assert_eq!(program.fragment_of(&program.span(1)), 2);
+        // Programs from a single source are all fragment 0
+        let program = Program::from_source("+>-").unwrap();
+        assert_eq!(program.fragment_of(&program.span(2)), 0);
+
+        // Each fragment must balance on its own
+        let err = Program::from_fragments(&["+[", "-]"]).unwrap_err();
+        assert_eq!(
+            err,
+            CompileError::InFragment {
+                fragment: 0,
+                error: Box::new(CompileError::UnmatchedOpen {
+                    span: Span {
+                        start: 1,
+                        end: 2,
+                        line: 1,
+                        col: 2,
+                    }
+                }),
+            }
+        );
+        let err = Program::from_fragments(&["+", "-\n", "]"]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "fragment 2: unmatched ']' at line 2, column 1"
+        );
+        assert!(std::error::Error::source(&err).is_some());
+    }
+
+    #[test]
+    fn test_map_ops() {
+        let source = "++++++++[->++[->++++<]<]>>.----[------>+<]>.";
+        let mut program = Program::from_source(source).unwrap();
+        let loops = program.op_histogram()["Open"];
+
+        // Put a no-op move before each loop, shifting everything after
+        program
+            .map_ops(|op, span| match op {
+                Op::Open(_) => vec![(Op::Move(0), *span), (op.clone(), *span)],
+                _ => vec![(op.clone(), *span)],
+            })
+            .unwrap();
+        assert_eq!(program.op_histogram()["Move"], 6 + loops);
+        assert_eq!(program.ops.len(), program.spans().len());
+
+        let mut output = Vec::new();
+        program
+            .run(&Config::default(), None, None, None, Some(&mut output))
+            .unwrap();
+        assert_eq!(output, b"@\n");
+
+        // Dropping a close is reported and leaves the program as it was
+        let before = program.clone();
+        let result = program.map_ops(|op, span| match op {
+            Op::Close(_) => vec![],
+            _ => vec![(op.clone(), *span)],
+        });
+        assert!(matches!(result, Err(CompileError::UnmatchedOpen { .. })));
+        assert!(program.ops_eq(&before));
+
+        // So is an op that can't be run
+        let result = program.map_ops(|op, span| match op {
+            Op::Out => vec![(Op::Scan(0), *span)],
+            _ => vec![(op.clone(), *span)],
+        });
+        let index = before.ops.iter().position(|op| *op == Op::Out).unwrap();
+        assert_eq!(
+            result,
+            Err(CompileError::InvalidOps {
+                index,
+                reason: "scan with zero step"
+            })
+        );
+        assert!(program.ops_eq(&before));
+    }
+
+    // Ops edited directly run as edited once loops are relinked
+    #[test]
+    fn test_edit_ops() {
+        let config = Config {
+            tape_size: 4,
+            ..Default::default()
+        };
+        let mut program = Program::from_source(">+>+<<").unwrap();
+        program.ops[2] = Op::Move(5);
+        program.relink_loops().unwrap();
+        assert!(matches!(
+            program.run(&config, None, None, None, None),
+            Err(ExecutionError::PointerOverflow { pointer: 6, .. })
+        ));
+
+        // Ops added without relinking still run one by one
+        let mut program = Program::from_source(">+>+<<").unwrap();
+        program.ops.push(Op::Add(3));
+        program.ranges.push(program.ranges[0]);
+        let result = program.run(&config, None, None, None, None).unwrap();
+        assert_eq!(result.tape, vec![3, 1, 1, 0]);
+
+        // As do straight-line runs edited without relinking
+        let mut program = Program::from_source("+>+>+").unwrap();
+        program.ops[2] = Op::Out;
+        program.ops[1] = Op::Move(3);
+        let config = Config {
+            tape_size: 8,
+            ..Default::default()
+        };
+        let weighted = Config {
+            cost_model: CostModel::Weighted,
+            ..config.clone()
+        };
+        let run = |config: &Config| {
+            let mut output = Vec::new();
+            let result = program
+                .run(config, None, None, None, Some(&mut output))
+                .unwrap();
+            (result.tape, result.pointer, output)
+        };
+        assert_eq!(run(&weighted), (vec![1, 0, 0, 0, 1, 0, 0, 0], 4, vec![0]));
+        assert_eq!(run(&weighted), run(&config));
+    }
+
+    #[test]
+    fn test_map_ops_origins() {
+        let mut program = Program::from_source("+[>]").unwrap();
+        program
+            .map_ops(|op, span| match op {
+                Op::Scan(n) => vec![(Op::Move(0), *span), (Op::Scan(*n), *span)],
+                _ => vec![(op.clone(), *span)],
+            })
+            .unwrap();
+        assert_eq!(
+            program.op_origins(),
+            [OpOrigin::Direct, OpOrigin::ScanLoop, OpOrigin::ScanLoop]
+        );
+    }
+
+    #[test]
+    fn test_is_synthetic() {
+        let program = Program::from_source("+>[->++<]").unwrap();
+        assert_eq!(
+            program.ops,
+            vec![Op::Add(1), Op::Move(1), Op::Mul(1, 2), Op::Set(0)]
+        );
+        assert!(!program.is_synthetic(0));
+        assert!(!program.is_synthetic(1));
+        assert!(program.is_synthetic(2));
+        assert!(program.is_synthetic(3));
+        assert!(!program.is_synthetic(4));
+
+        // A loop kept as a loop is written out in the source
+        let program = Program::from_source("+[.-]").unwrap();
+        assert!((0..program.ops.len()).all(|i| !program.is_synthetic(i)));
+    }
+
+    #[test]
+    fn test_op_histogram() {
+        let program = Program::from_source("++++++++[->++[->++++<]<]>>.----[------>+<]>.").unwrap();
+        let histogram = program.op_histogram();
+        assert_eq!(histogram["Mul"], 1);
+        assert_eq!(histogram["Set"], 1);
+        assert_eq!(histogram["Open"], 2);
+        assert_eq!(histogram["Close"], 2);
+        assert_eq!(histogram["Out"], 2);
+        assert!(!histogram.contains_key("Scan"));
+        assert_eq!(histogram.values().sum::<usize>(), program.ops.len());
+    }
+
+    #[test]
+    fn test_min_tape_size() {
+        let size = |source: &str| Program::from_source(source).unwrap().min_tape_size();
+        assert_eq!(size(">>>+"), Some(4));
+        assert_eq!(size(""), Some(1));
+        // Moves are merged, so only where they end up matters
+        assert_eq!(size(">>>><<"), Some(3));
+        // Balanced loops touch the same cells each time around
+        assert_eq!(size("+[>>+<<-]"), Some(3));
+        assert_eq!(size(",[>>.<[>>>.<<<-]<]"), Some(5));
+        // Multiplication targets count
+        assert_eq!(size("+[->>>+<<<]"), Some(4));
+        // Drifting loops and scans can go anywhere
+        assert_eq!(size("+[>+]"), None);
+        assert_eq!(size(">+[>]"), None);
+
+        // The result is enough to run on
+        let program = Program::from_source(">>>+[-<+>]<<<.").unwrap();
+        let config = Config {
+            tape_size: program.min_tape_size().unwrap(),
+            ..Default::default()
+        };
+        assert!(program.run(&config, None, None, None, None).is_ok());
+    }
+
+    #[test]
+    fn test_io_ops() {
+        let program = Program::from_source(",.,.").unwrap();
+        let span = |col: usize| Span {
+            start: col - 1,
+            end: col,
+            line: 1,
+            col,
+        };
+        assert_eq!(
+            program.io_ops(),
+            vec![
+                (0, IoKind::In, span(1)),
+                (1, IoKind::Out, span(2)),
+                (2, IoKind::In, span(3)),
+                (3, IoKind::Out, span(4)),
+            ]
+        );
+
+        let program = Program::from_source("+[->+<]>").unwrap();
+        assert!(program.io_ops().is_empty());
+    }
+
+    #[test]
+    fn test_loops() {
+        let program = Program::from_source("[[+.]]\n,[.,]").unwrap();
+        let span = |start: usize, end: usize, line: usize, col: usize| Span {
+            start,
+            end,
+            line,
+            col,
+        };
+        assert_eq!(
+            program.loops(),
+            vec![
+                LoopInfo {
+                    open: 0,
+                    close: 5,
+                    body_len: 4,
+                    depth: 0,
+                    span: span(0, 6, 1, 1),
+                },
+                LoopInfo {
+                    open: 1,
+                    close: 4,
+                    body_len: 2,
+                    depth: 1,
+                    span: span(1, 5, 1, 2),
+                },
+                LoopInfo {
+                    open: 7,
+                    close: 10,
+                    body_len: 2,
+                    depth: 0,
+                    span: span(8, 12, 2, 2),
+                },
+            ]
+        );
+
+        // The inner loop is a clear, leaving one loop around a Set
+        let program = Program::from_source("[[+]]").unwrap();
+        let loops = program.loops();
+        assert_eq!(loops.len(), 1);
+        assert_eq!(
+            (loops[0].open, loops[0].close, loops[0].body_len),
+            (0, 2, 1)
+        );
+
+        // Brackets edited without relinking aren't listed
+        let mut program = Program::from_source("+[>[-<+>]<]").unwrap();
+        assert_eq!(program.loops().len(), 1);
+        let ops = program.ops.clone();
+        let open = ops.iter().position(|op| matches!(op, Op::Open(_))).unwrap();
+        program.ops.swap(open, ops.len() - 1);
+        assert_eq!(program.loops(), vec![]);
+        program.ops = ops.clone();
+        program.ops[open] = Op::Open(0);
+        assert_eq!(program.loops(), vec![]);
+        program.ops = ops;
+        program.ops.insert(open, Op::Close(open as u32));
+        assert_eq!(program.loops(), vec![]);
+    }
+
+    #[test]
+    fn test_run_with_tape_program() {
+        let mut tape = vec![0u8; 16];
+        tape[8..12].copy_from_slice(b"+++.");
+
+        let host = Program::from_source("++").unwrap();
+        let mut output = Vec::new();
+        let result = host
+            .run_with_tape_program(
+                &Config::default(),
+                Some(tape),
+                None,
+                8,
+                None,
+                Some(&mut output),
+            )
+            .unwrap();
+        assert_eq!(output, vec![5]);
+        assert_eq!(result.tape[0], 5);
+        assert_eq!(result.pointer, 0);
+
+        // Host program writes "+" (43) to cell 0, which then increments itself
+        let host = Program::from_source(">++++++[-<+++++++>]<+").unwrap();
+        let result = host
+            .run_with_tape_program(&Config::default(), None, None, 0, None, None)
+            .unwrap();
+        assert_eq!(result.tape[0], 44);
+        assert_eq!(result.tape[1], 0);
+    }
+
+    #[test]
+    fn test_run_with_tape_program_errors() {
+        let mut tape = vec![0u8; 4];
+        tape[1] = b'[';
+        let host = Program::from_source("").unwrap();
+        let result =
+            host.run_with_tape_program(&Config::default(), Some(tape), None, 1, None, None);
+        assert!(matches!(
+            result,
+            Err(TapeProgramError::Compile(
+                CompileError::UnmatchedOpen { .. }
+            ))
+        ));
+
+        let host = Program::from_source("<").unwrap();
+        let result = host.run_with_tape_program(&Config::default(), None, None, 0, None, None);
+        assert!(matches!(
+            result,
+            Err(TapeProgramError::Host(
+                ExecutionError::PointerUnderflow { .. }
+            ))
+        ));
+    }
+
+    // Loops rewritten into Mul ops leave the pointer where it started
+    #[test]
+    fn test_mul_loop_pointer() {
+        let cases: &[(&str, [u8; 8])] = &[
+            ("[->+<]", [0, 0, 0, 0, 5, 0, 0, 0]),
+            ("[>+<-]", [0, 0, 0, 0, 5, 0, 0, 0]),
+            ("[-<+>]", [0, 0, 5, 0, 0, 0, 0, 0]),
+            ("[->+>+<<]", [0, 0, 0, 0, 5, 5, 0, 0]),
+            ("[->>++>+++<<<]", [0, 0, 0, 0, 0, 10, 15, 0]),
+            ("[<<->>-]", [0, 251, 0, 0, 0, 0, 0, 0]),
+            ("[>>>+<<<<<+>>-]", [0, 5, 0, 0, 0, 0, 5, 0]),
+        ];
+        for (source, expected) in cases {
+            let program = Program::from_source(source).unwrap();
+            assert!(
+                !program.ops.iter().any(|op| matches!(op, Op::Move(_))),
+                "{} compiled to {:?}",
+                source,
+                program.ops
+            );
+
+            let mut tape = vec![0u8; 8];
+            tape[3] = 5;
+            let result = program
+                .run(&Config::default(), Some(tape), Some(3), None, None)
+                .unwrap();
+            assert_eq!(result.pointer, 3, "{}", source);
+            assert_eq!(result.tape[..8], *expected, "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_mul_loop_subtract() {
+        let program = Program::from_source(">[->-<]").unwrap();
+        assert!(program.ops.contains(&Op::Mul(1, 255)));
+
+        // The same loop, interpreted
+        let ops = [
+            Op::Move(1),
+            Op::Open(6),
+            Op::Add(255),
+            Op::Move(1),
+            Op::Add(255),
+            Op::Move(-1),
+            Op::Close(1),
+        ];
+        let spans = [program.span(0); 7];
+
+        let config = Config {
+            tape_size: 3,
+            ..Default::default()
+        };
+        for (source, target) in [(3, 10), (10, 3), (255, 0), (0, 7), (128, 128)] {
+            let tape = vec![0, source, target];
+            let compiled = program.run(&config, Some(tape.clone()), None, None, None);
+            let interpreted = run_ops(&ops, &spans, &config, Some(tape), None, None, None);
+            let (compiled, interpreted) = (compiled.unwrap(), interpreted.unwrap());
+            // Only the ops run differ
+            assert_eq!(compiled.tape, interpreted.tape);
+            assert_eq!(compiled.pointer, interpreted.pointer);
+            assert_eq!(compiled.tape, [0, 0, target.wrapping_sub(source)]);
+        }
+    }
+
+    #[test]
+    fn test_run_ops() {
+        // ,[->+<]>. hand-built with an explicit loop
+        let ops = vec![
+            Op::In,
+            Op::Open(6),
+            Op::Add(255),
+            Op::Move(1),
+            Op::Add(1),
+            Op::Move(-1),
+            Op::Close(1),
+            Op::Move(1),
+            Op::Out,
+        ];
+        let spans = vec![
+            Span {
+                start: 0,
+                end: 0,
+                line: 1,
+                col: 1,
+            };
+            ops.len()
+        ];
+        let mut input: &[u8] = b"A";
+        let mut output = Vec::new();
+        let result = run_ops(
+            &ops,
+            &spans,
+            &Config::default(),
+            None,
+            None,
+            Some(&mut input),
+            Some(&mut output),
+        )
+        .unwrap();
+        assert_eq!(output, b"A");
+        assert_eq!(result.tape[..2], [0, 65]);
+        assert_eq!(result.pointer, 1);
+    }
+
+    #[test]
+    fn test_run_ops_invalid() {
+        let span = Span {
+            start: 0,
+            end: 0,
+            line: 1,
+            col: 1,
+        };
+        let run = |ops: &[Op], n: usize| {
+            run_ops(
+                ops,
+                &vec![span; n],
+                &Config::default(),
+                None,
+                None,
+                None,
+                None,
+            )
+        };
+
+        assert_eq!(
+            run(&[Op::Add(1), Op::Out], 1),
+            Err(ExecutionError::InvalidOps {
+                index: 1,
+                reason: "ops and spans differ in length",
+            })
+        );
+        assert!(matches!(
+            run(&[Op::Open(5), Op::Close(0)], 2),
+            Err(ExecutionError::InvalidOps { index: 0, .. })
+        ));
+        assert!(matches!(
+            run(&[Op::Open(1), Op::Close(0), Op::Close(0)], 3),
+            Err(ExecutionError::InvalidOps { index: 2, .. })
+        ));
+        assert!(matches!(
+            run(&[Op::Open(2), Op::Out, Op::Out], 3),
+            Err(ExecutionError::InvalidOps { index: 0, .. })
+        ));
+        assert!(matches!(
+            run(&[Op::Add(1), Op::Scan(0)], 2),
+            Err(ExecutionError::InvalidOps { index: 1, .. })
+        ));
+    }
+
+    // Valid ops with the widest operands fail cleanly instead of
+    // overflowing
+    #[test]
+    fn test_run_ops_extreme_operands() {
+        let span = Span {
+            start: 0,
+            end: 0,
+            line: 1,
+            col: 1,
+        };
+        let run = |ops: &[Op], config: &Config| {
+            run_ops(ops, &vec![span; ops.len()], config, None, None, None, None)
+        };
+        let config = Config::default();
+
+        for step in [i32::MIN, i32::MIN + 1] {
+            assert!(matches!(
+                run(&[Op::Add(1), Op::Scan(step)], &config),
+                Err(ExecutionError::PointerUnderflow { .. })
+            ));
+        }
+        assert!(matches!(
+            run(&[Op::Add(1), Op::Scan(i32::MAX)], &config),
+            Err(ExecutionError::PointerOverflow { .. })
+        ));
+        assert!(matches!(
+            run(&[Op::Move(i32::MIN)], &config),
+            Err(ExecutionError::PointerUnderflow { .. })
+        ));
+        assert!(matches!(
+            run(&[Op::Add(1), Op::Mul(i32::MIN, 1)], &config),
+            Err(ExecutionError::PointerUnderflow { .. })
+        ));
+
+        // On a wrapping tape of 2^k cells the step comes round to the
+        // start without reaching a zero
+        let config = Config {
+            tape_size: 4,
+            wrap_pointer: true,
+            ..Default::default()
+        };
+        assert!(matches!(
+            run(&[Op::Add(1), Op::Scan(i32::MIN)], &config),
+            Err(ExecutionError::EndlessScan { .. })
+        ));
+    }
+
+    #[test]
+    fn test_halt() {
+        let options = CompileOptions {
+            halt_char: Some(b'!'),
+            ..Default::default()
+        };
+        let program = Program::from_source_with_options("+++.>++!>+++.", &options).unwrap();
+        let mut output = Vec::new();
+        let result = program
+            .run(&Config::default(), None, None, None, Some(&mut output))
+            .unwrap();
+        assert_eq!(output, vec![3]);
+        assert_eq!(result.tape[..3], [3, 2, 0]);
+        assert_eq!(result.pointer, 1);
+
+        // Halting inside a loop
+        let program = Program::from_source_with_options("+++++[>+<-<!]", &options).unwrap();
+        let result = program
+            .run(&Config::default(), None, Some(1), None, None)
+            .unwrap();
+        assert_eq!(result.tape[..3], [0, 4, 1]);
+        assert_eq!(result.pointer, 0);
+
+        // Off by default
+        let program = Program::from_source("+++!+").unwrap();
+        let result = program
+            .run(&Config::default(), None, None, None, None)
+            .unwrap();
+        assert_eq!(result.tape[0], 4);
+    }
+
+    #[test]
+    fn test_run_with_debug() {
+        let options = CompileOptions {
+            debug_char: Some(b'#'),
+            ..Default::default()
+        };
+        let config = Config {
+            tape_size: 4,
+            ..Default::default()
+        };
+        let program = Program::from_source_with_options("++#>+++[>+<-#]>", &options).unwrap();
+        let mut hits = Vec::new();
+        let result = program
+            .run_with_debug(&config, None, None, None, None, &mut |pointer, tape| {
+                hits.push((pointer, tape.to_vec()))
+            })
+            .unwrap();
+        assert_eq!(
+            hits,
+            vec![
+                (0, vec![2, 0, 0, 0]),
+                (1, vec![2, 2, 1, 0]),
+                (1, vec![2, 1, 2, 0]),
+                (1, vec![2, 0, 3, 0]),
+            ]
+        );
+        assert_eq!(result.tape, [2, 0, 3, 0]);
+        assert_eq!(result.pointer, 2);
+
+        // Without a callback, and without the option, breakpoints do nothing
+        let result = program.run(&config, None, None, None, None).unwrap();
+        assert_eq!(result.tape, [2, 0, 3, 0]);
+        let program = Program::from_source("++#>").unwrap();
+        assert!(!program.ops.contains(&Op::Debug));
+    }
+
+    #[test]
+    fn test_run_segment() {
+        let program = Program::from_source("+++[>++.<-]>.").unwrap();
+        let config = Config {
+            tape_size: 4,
+            ..Default::default()
+        };
+        let mut expected_output = Vec::new();
+        let expected = program
+            .run(&config, None, None, None, Some(&mut expected_output))
+            .unwrap();
+
+        // Stop partway into the loop's first iteration
+        let state = ExecutionResult {
+            tape: vec![0; 4],
+            pointer: 0,
+            output_truncated: false,
+            ops_executed: 0,
+        };
+        let mut output = Vec::new();
+        let (state, ip, finished) = program
+            .run_segment(&config, state, 0, 5, None, Some(&mut output))
+            .unwrap();
+        assert_eq!(state.tape, [3, 2, 0, 0]);
+        assert_eq!(state.pointer, 1);
+        assert_eq!(ip, 5);
+        assert!(!finished);
+        assert_eq!(output, [2]);
+
+        let (state, ip, finished) = program
+            .run_segment(&config, state, ip, 1000, None, Some(&mut output))
+            .unwrap();
+        assert!(finished);
+        assert_eq!(ip, program.ops.len());
+        assert_eq!(state, expected);
+        assert_eq!(output, expected_output);
+
+        // Nothing left to run
+        let (again, ip, finished) = program
+            .run_segment(&config, state.clone(), ip, 1000, None, None)
+            .unwrap();
+        assert_eq!(again, state);
+        assert_eq!(ip, program.ops.len());
+        assert!(finished);
+    }
+
+    #[test]
+    fn test_run_segment_tweak() {
+        // Changing the tape between segments changes what the rest does
+        let program = Program::from_source("+[>+.<-]").unwrap();
+        let config = Config::default();
+        let state = ExecutionResult {
+            tape: vec![0; 2],
+            pointer: 0,
+            output_truncated: false,
+            ops_executed: 0,
+        };
+        let (mut state, ip, _) = program
+            .run_segment(&config, state, 0, 1, None, None)
+            .unwrap();
+        assert_eq!(state.tape, [1, 0]);
+        state.tape[0] = 3;
+        let mut output = Vec::new();
+        let (state, _, finished) = program
+            .run_segment(&config, state, ip, 1000, None, Some(&mut output))
+            .unwrap();
+        assert!(finished);
+        assert_eq!(output, [1, 2, 3]);
+        assert_eq!(state.tape, [0, 3]);
+
+        // Halting finishes the program
+        let options = CompileOptions {
+            halt_char: Some(b'!'),
+            ..Default::default()
+        };
+        let program = Program::from_source_with_options("+!+", &options).unwrap();
+        let state = ExecutionResult {
+            tape: vec![0; 1],
+            pointer: 0,
+            output_truncated: false,
+            ops_executed: 0,
+        };
+        let (state, ip, finished) = program
+            .run_segment(&config, state, 0, 10, None, None)
+            .unwrap();
+        assert_eq!(state.tape, [1]);
+        assert_eq!(ip, program.ops.len());
+        assert!(finished);
+    }
+
+    #[test]
+    fn test_grow_tape() {
+        let program = Program::from_source(&format!("{}+", ">".repeat(100_000))).unwrap();
+        let config = Config {
+            grow_tape: true,
+            ..Default::default()
+        };
+        let result = program.run(&config, None, None, None, None).unwrap();
+        assert_eq!(result.tape.len(), 100_001);
+        assert_eq!(result.tape[100_000], 1);
+        assert_eq!(result.pointer, 100_000);
+
+        // Without growth, or past the cap, the move overflows
+        assert!(matches!(
+            program.run(&Config::default(), None, None, None, None),
+            Err(ExecutionError::PointerOverflow {
+                tape_len: DEFAULT_TAPE_SIZE,
+                ..
+            })
+        ));
+        let capped = Config {
+            max_tape_size: 50_000,
+            ..config.clone()
+        };
+        assert!(matches!(
+            program.run(&capped, None, None, None, None),
+            Err(ExecutionError::PointerOverflow {
+                pointer: 100_000,
+                ..
+            })
+        ));
+
+        // Only the end of the tape grows
+        let program = Program::from_source("+<").unwrap();
+        assert!(matches!(
+            program.run(&config, None, None, None, None),
+            Err(ExecutionError::PointerUnderflow { .. })
+        ));
+    }
+
+    #[test]
+    fn test_run_supplied_tape_size() {
+        let program = Program::from_source(">>>>>+").unwrap();
+        let config = Config {
+            tape_size: 8,
+            ..Default::default()
+        };
+
+        // Short tape is padded up to tape_size
+        let result = program
+            .run(&config, Some(vec![1, 2]), None, None, None)
+            .unwrap();
+        assert_eq!(result.tape, vec![1, 2, 0, 0, 0, 1, 0, 0]);
+        assert_eq!(result.pointer, 5);
+
+        // Long tape is kept whole
+        let result = program
+            .run(&config, Some(vec![7; 10]), None, None, None)
+            .unwrap();
+        assert_eq!(result.tape, vec![7, 7, 7, 7, 7, 8, 7, 7, 7, 7]);
+
+        let config = Config {
+            tape_size: 4,
+            ..Default::default()
+        };
+        assert!(matches!(
+            program.run(&config, Some(vec![1, 2]), None, None, None),
+            Err(ExecutionError::PointerOverflow { tape_len: 4, .. })
+        ));
+    }
+
+    #[test]
+    fn test_run_with_state() {
+        let program = Program::from_source("+>++>+++>").unwrap();
+        let config = Config {
+            tape_size: 3,
+            ..Default::default()
+        };
+        let (error, state) = program
+            .run_with_state(&config, None, None, None, None)
+            .unwrap_err();
+        assert!(matches!(error, ExecutionError::PointerOverflow { .. }));
+        assert_eq!(state.tape, vec![1, 2, 3]);
+        assert_eq!(state.pointer, 2);
+
+        // Failing in a loop, with the tape as the faulting scan found it
+        let program = Program::from_source("+>+>+[<]").unwrap();
+        let (error, state) = program
+            .run_with_state(&Config::default(), None, None, None, None)
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            ExecutionError::PointerUnderflow {
+                kind: AccessKind::ScanTarget,
+                ..
+            }
+        ));
+        assert_eq!(state.tape[..4], [1, 1, 1, 0]);
+        assert_eq!(state.pointer, 2);
+
+        // Success is as from run
+        let program = Program::from_source("++[->+++<]").unwrap();
+        let state = program
+            .run_with_state(&Config::default(), None, None, None, None)
+            .unwrap();
+        assert_eq!(
+            state,
+            program
+                .run(&Config::default(), None, None, None, None)
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_run_empty_program() {
+        let program = Program::from_source("no commands here").unwrap();
+        assert!(program.ops.is_empty());
+        let config = Config {
+            tape_size: 4,
+            report: ReportOptions {
+                op_count: true,
+                max_pointer: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (result, report) = program
+            .run_with_report(&config, Some(vec![9, 8, 7, 6]), Some(3), None, None)
+            .unwrap();
+        assert_eq!(result.tape, vec![9, 8, 7, 6]);
+        assert_eq!(result.pointer, 3);
+        assert_eq!(report.op_count, Some(0));
+        assert_eq!(report.max_pointer, Some(3));
+    }
+
+    #[test]
+    fn test_run_cancellable() {
+        let program = Program::from_source("+[]").unwrap();
+        let cancel = Arc::new(AtomicBool::new(false));
+        let setter = {
+            let cancel = cancel.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(std::time::Duration::from_millis(20));
+                cancel.store(true, std::sync::atomic::Ordering::Relaxed);
+            })
+        };
+        let result = program.run_cancellable(&Config::default(), None, None, None, None, cancel);
+        setter.join().unwrap();
+        assert_eq!(
+            result,
+            Err(ExecutionError::Cancelled {
+                span: program.span(2)
+            })
+        );
+
+        // Set before starting, nothing runs
+        let program = Program::from_source("+.").unwrap();
+        let mut output = Vec::new();
+        let cancel = Arc::new(AtomicBool::new(true));
+        let result = program.run_cancellable(
+            &Config::default(),
+            None,
+            None,
+            None,
+            Some(&mut output),
+            cancel,
+        );
+        assert!(matches!(result, Err(ExecutionError::Cancelled { .. })));
+        assert!(output.is_empty());
+
+        let cancel = Arc::new(AtomicBool::new(false));
+        let result = program.run_cancellable(&Config::default(), None, None, None, None, cancel);
+        assert_eq!(result.unwrap().tape[0], 1);
+    }
+
+    #[test]
+    fn test_ops_executed() {
+        // Compiles to an add, a multiplication, a clear, a move and a
+        // loop running its three ops three times
+        let program = Program::from_source("+++[->+<]>[.-]").unwrap();
+        let run = |config: &Config| {
+            program
+                .run(config, None, None, None, Some(&mut Vec::new()))
+                .unwrap()
+                .ops_executed
+        };
+        assert_eq!(run(&Config::default()), 14);
+        let weighted = Config {
+            cost_model: CostModel::Weighted,
+            ..Default::default()
+        };
+        assert_eq!(run(&weighted), 15);
+
+        let config = Config {
+            report: ReportOptions {
+                op_count: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (result, report) = program
+            .run_with_report(&config, None, None, None, None)
+            .unwrap();
+        assert_eq!(Some(result.ops_executed), report.op_count);
+    }
+
+    #[test]
+    fn test_run_with_report() {
+        // Reads two bytes, prints their sum from cell 2
+        let program = Program::from_source(",>,[-<+>]<[->>+<<]>>.").unwrap();
+        let config = Config {
+            report: ReportOptions {
+                op_count: true,
+                max_pointer: true,
+                io_bytes: true,
+                op_counts: false,
+                cell_profile: false,
+                pointer_path: false,
+            },
+            ..Default::default()
+        };
+        let mut input: &[u8] = b"\x02\x03";
+        let mut output = Vec::new();
+        let (result, report) = program
+            .run_with_report(&config, None, None, Some(&mut input), Some(&mut output))
+            .unwrap();
+        assert_eq!(output, [5]);
+        assert_eq!(result.pointer, 2);
+        assert_eq!(
+            report,
+            RunReport {
+                op_count: Some(program.ops.len()),
+                max_pointer: Some(2),
+                input_bytes: Some(2),
+                output_bytes: Some(1),
+                op_counts: None,
+                cell_profile: None,
+                pointer_path: None,
+            }
+        );
+
+        let config = Config {
+            report: ReportOptions {
+                op_counts: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let program = Program::from_source("+++[>.<-]").unwrap();
+        let (_, report) = program
+            .run_with_report(&config, None, None, None, None)
+            .unwrap();
+        assert_eq!(
+            report,
+            RunReport {
+                op_counts: Some(vec![1, 1, 3, 3, 3, 3, 3]),
+                ..Default::default()
+            }
+        );
+
+        // Input after the zero that ends a cat program isn't read
+        let config = Config {
+            report: ReportOptions {
+                io_bytes: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let program = Program::from_source(",[.,]").unwrap();
+        let mut input: &[u8] = b"ab\0cd";
+        let (_, report) = program
+            .run_with_report(&config, None, None, Some(&mut input), None)
+            .unwrap();
+        assert_eq!(report.input_bytes, Some(3));
+        assert_eq!(input, b"cd");
+    }
+
+    #[test]
+    fn test_cell_profile() {
+        // Counts cell 1 down by two, moving a unit to cell 2 each time
+        let program = Program::from_source("+>++++++++++++++++++++[-->+<]").unwrap();
+        let config = Config {
+            report: ReportOptions {
+                cell_profile: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (result, report) = program
+            .run_with_report(&config, None, None, None, None)
+            .unwrap();
+        assert_eq!(result.tape[..3], [1, 0, 10]);
+
+        let cells = report.cell_profile.unwrap();
+        assert_eq!(cells.len(), 3);
+        assert!(cells[1] > cells[0] && cells[1] > cells[2]);
+        assert_eq!(report.op_counts, None);
+
+        // Same result without profiling
+        assert_eq!(
+            program
+                .run(&Config::default(), None, None, None, None)
+                .unwrap(),
+            result
+        );
+    }
+
+    #[test]
+    fn test_pointer_path() {
+        let config = Config {
+            report: ReportOptions {
+                pointer_path: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let program = Program::from_source(">>.<").unwrap();
+        let (_, report) = program
+            .run_with_report(&config, None, None, None, None)
+            .unwrap();
+        assert_eq!(report.pointer_path, Some(vec![2, 2, 1]));
+
+        // Straight-line blocks are stepped through op by op
+        let program = Program::from_source("+[>+>+<<-]>>>+>").unwrap();
+        let (result, report) = program
+            .run_with_report(&config, None, None, None, None)
+            .unwrap();
+        assert_eq!(report.pointer_path, Some(vec![0, 0, 0, 0, 3, 3, 4]));
+        assert_eq!(result.pointer, 4);
+    }
+
+    #[test]
+    fn test_poison_tape() {
+        let config = Config {
+            tape_size: 64,
+            poison_tape: Some(42),
+            ..Default::default()
+        };
+
+        // Relies on cell 1 starting at zero
+        let sloppy = Program::from_source("++++++++[>++++++++<-]>+.").unwrap();
+        assert_eq!(sloppy.run_to_string(&Config::default(), None).unwrap(), "A");
+        assert_ne!(sloppy.run_to_string(&config, None).unwrap(), "A");
+
+        // Clears its cells before use
+        let careful = Program::from_source("[-]++++++++>[-]<[>++++++++<-]>+.").unwrap();
+        assert_eq!(careful.run_to_string(&config, None).unwrap(), "A");
+
+        let result = sloppy.run(&config, None, None, None, None).unwrap();
+        assert!(result.tape[2..].iter().all(|&cell| cell != 0));
+        let again = sloppy.run(&config, None, None, None, None).unwrap();
+        assert_eq!(result.tape, again.tape);
+
+        let result = sloppy
+            .run(&config, Some(vec![0, 0, 0]), None, None, None)
+            .unwrap();
+        assert_eq!(result.tape[2], 0);
+        assert!(result.tape[3..].iter().all(|&cell| cell != 0));
+    }
+
+    #[test]
+    fn test_run_to_string() {
+        let program = Program::from_source("++++++++[->++[->++++<]<]>>.----[------>+<]>.").unwrap();
+        assert_eq!(
+            program.run_to_string(&Config::default(), None).unwrap(),
+            "@\n"
+        );
+
+        let program = Program::from_source(",.,.,.").unwrap();
+        let mut input: &[u8] = b"a\xffb";
+        assert_eq!(
+            program
+                .run_to_string(&Config::default(), Some(&mut input))
+                .unwrap(),
+            "a\u{fffd}b"
+        );
+
+        let program = Program::from_source("+.<").unwrap();
+        assert!(matches!(
+            program.run_to_string(&Config::default(), None),
+            Err(ExecutionError::PointerUnderflow { .. })
+        ));
+    }
+
+    #[test]
+    fn test_run_output_hash() {
+        let config = Config::default();
+        let a = Program::from_source("++++++++[->++[->++++<]<]>>.----[------>+<]>.").unwrap();
+        let b = Program::from_source("++++++++[>++++++++<-]>.<++++[>-------------<-]>--.").unwrap();
+        let c = Program::from_source("++++++++[>++++++++<-]>+.").unwrap();
+
+        let hash_a = a.run_output_hash(&config, None).unwrap();
+        assert_eq!(hash_a, a.run_output_hash(&config, None).unwrap());
+        assert_eq!(hash_a, b.run_output_hash(&config, None).unwrap());
+        assert_ne!(hash_a, c.run_output_hash(&config, None).unwrap());
+
+        let mut input: &[u8] = b"xy";
+        let echo = Program::from_source(",.,.").unwrap();
+        let mut expected = HashingWriter::new();
+        expected.write_all(b"xy").unwrap();
+        assert_eq!(
+            echo.run_output_hash(&config, Some(&mut input)).unwrap(),
+            expected.finish()
+        );
+    }
+
+    #[test]
+    fn test_ops_eq() {
+        let a = Program::from_source(" , [ - ] ").unwrap();
+        let b = Program::from_source(",[-]").unwrap();
+        assert!(a.ops_eq(&b));
+        assert!(!a.spans().eq(b.spans()));
+
+        // Both clear loops compile to Set(0)
+        let c = Program::from_source(",[+]").unwrap();
+        assert!(a.ops_eq(&c));
+
+        let d = Program::from_source(",[-]+").unwrap();
+        assert!(!a.ops_eq(&d));
+        let e = Program::from_source(",[--]").unwrap();
+        assert!(!a.ops_eq(&e));
+    }
+
+    #[test]
+    fn test_diagnostic_severity() {
+        let program = Program::from_source("+[]>[>+<<]").unwrap();
+        let severities: Vec<_> = program
+            .diagnostics
+            .iter()
+            .map(|d| (d.kind, d.kind.severity()))
+            .collect();
+        assert_eq!(
+            severities,
+            vec![
+                (DiagnosticKind::EmptyLoop, Severity::Error),
+                (DiagnosticKind::UnbalancedLoop, Severity::Warning),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_config_deserialize() {
+        let config: Config = serde_json::from_str(
+            r#"{"tape_size": 10, "eof_behavior": "max", "newline_translation": "lf_to_crlf"}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config,
+            Config {
+                tape_size: 10,
+                eof_behavior: EofBehavior::MaxValue,
+                newline_translation: NewlineTranslation::LfToCrlf,
+                ..Default::default()
+            }
+        );
+
+        let config: Config = serde_json::from_str("{}").unwrap();
+        assert_eq!(config, Config::default());
+
+        assert!(serde_json::from_str::<Config>(r#"{"tape_sise": 10}"#).is_err());
+    }
+
+    #[test]
+    fn test_config_round_trip() {
+        let config = Config {
+            tape_size: 10,
+            op_limit: Some(500),
+            time_limit: Some(Duration::from_millis(1500)),
+            eof_behavior: EofBehavior::Unchanged,
+            input_encoding: InputEncoding::Utf8Codepoint,
+            cost_model: CostModel::Weighted,
+            max_loop_iterations: Some(3),
+            poison_tape: Some(7),
+            report: ReportOptions {
+                op_count: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let json = serde_json::to_string(&config).unwrap();
+        assert_eq!(serde_json::from_str::<Config>(&json).unwrap(), config);
+    }
+
+    #[test]
+    fn test_estimated_memory() {
+        let program = Program::from_source("+[>+<-]>[.>]").unwrap();
+        let ops = program.ops.len();
+        let base = Config::default().estimated_memory(&program);
+        assert!(base >= DEFAULT_TAPE_SIZE);
+        assert!(base < 2 * DEFAULT_TAPE_SIZE);
+
+        let profiled = Config {
+            report: ReportOptions {
+                op_counts: true,
+                cell_profile: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert_eq!(
+            profiled.estimated_memory(&program),
+            base + ops * std::mem::size_of::<usize>() + DEFAULT_TAPE_SIZE * 8
+        );
+
+        let path = Config {
+            report: ReportOptions {
+                pointer_path: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert_eq!(path.estimated_memory(&program), usize::MAX);
+        let limited = Config {
+            op_limit: Some(100),
+            ..path
+        };
+        assert_eq!(
+            limited.estimated_memory(&program),
+            base + 200 * std::mem::size_of::<usize>()
+        );
+
+        let grown = Config {
+            grow_tape: true,
+            max_tape_size: 100_000,
+            ..Default::default()
+        };
+        assert_eq!(
+            grown.estimated_memory(&program),
+            base - DEFAULT_TAPE_SIZE + 200_000
+        );
+    }
+
+    #[test]
+    fn test_config_builder() {
+        let config = ConfigBuilder::new().op_limit(Some(5)).build();
+        assert_eq!(config.tape_size, DEFAULT_TAPE_SIZE);
+        assert_eq!(
+            config,
+            Config {
+                op_limit: Some(5),
+                ..Default::default()
+            }
+        );
+
+        let config = ConfigBuilder::new()
+            .tape_size(10)
+            .eof_behavior(EofBehavior::MaxValue)
+            .flush_output(false)
+            .op_limit_action(OpLimitAction::Halt)
+            .build();
+        assert_eq!(
+            config,
+            Config {
+                tape_size: 10,
+                eof_behavior: EofBehavior::MaxValue,
+                flush_output: false,
+                op_limit_action: OpLimitAction::Halt,
+                ..Default::default()
+            }
+        );
+        assert_eq!(ConfigBuilder::new().build(), Config::default());
+    }
+
+    #[test]
+    fn test_config_summary() {
+        assert_eq!(
+            Config::default().summary(),
+            "tape=30000 wrap=off grow=off max_tape=16777216 cells=eight op_limit=none \
+             time_limit=none eof=zero eof_marks_done=off encoding=bytes flush=on \
+             cost=uniform on_limit=error on_output_error=fail loop_limit=none \
+             newlines=none poison=none report=none"
+        );
+
+        let config = Config {
+            tape_size: 10,
+            op_limit: Some(500),
+            eof_behavior: EofBehavior::MaxValue,
+            flush_output: false,
+            op_limit_action: OpLimitAction::Halt,
+            newline_translation: NewlineTranslation::LfToCrlf,
+            poison_tape: Some(7),
+            report: ReportOptions {
+                op_count: true,
+                io_bytes: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert_eq!(
+            config.summary(),
+            "tape=10 wrap=off grow=off max_tape=16777216 cells=eight op_limit=500 \
+             time_limit=none eof=max_value eof_marks_done=off encoding=bytes flush=off \
+             cost=uniform on_limit=halt on_output_error=fail loop_limit=none \
+             newlines=lf_to_crlf poison=7 report=op_count,io_bytes"
+        );
+    }
+
+    #[test]
+    fn test_program_builder() {
+        let source = "++++++++[->++[->++++<]<]>>.\n----[------>+<]>.[-][x[]]>,";
+        let expected = Program::from_source(source).unwrap();
+
+        let mut builder = ProgramBuilder::new();
+        for c in source.chars() {
+            builder.push_char(c).unwrap();
+        }
+        let program = builder.build().unwrap();
+        assert_eq!(program.ops, expected.ops);
+        assert!(program.spans().eq(expected.spans()));
+
+        let mut builder = ProgramBuilder::new();
+        builder.push_str(&source[..20]).unwrap();
+        builder.push_str(&source[20..]).unwrap();
+        let program = builder.build().unwrap();
+        assert_eq!(program.ops, expected.ops);
+        assert!(program.spans().eq(expected.spans()));
+    }
+
+    #[test]
+    fn test_program_builder_errors() {
+        let mut builder = ProgramBuilder::new();
+        builder.push_str("+[").unwrap();
+        assert!(matches!(
+            builder.build(),
+            Err(CompileError::UnmatchedOpen { .. })
+        ));
+
+        // A rejected character leaves the builder usable
+        let mut builder = ProgramBuilder::new();
+        builder.push_str("+").unwrap();
+        assert!(matches!(
+            builder.push_char(']'),
+            Err(CompileError::UnmatchedClose { .. })
+        ));
+        builder.push_char('.').unwrap();
+        let program = builder.build().unwrap();
+        assert_eq!(program.ops, vec![Op::Add(1), Op::Out]);
+        assert_eq!(program.span(1).col, 2);
+
+        assert!(ProgramBuilder::new().build().unwrap().ops.is_empty());
+    }
 }
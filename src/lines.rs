@@ -0,0 +1,114 @@
+use crate::Span;
+
+// Where an op came from, as byte offsets into the source. The line and
+// column are worked out through a LineIndex when a Span is needed,
+// which is only ever on error paths.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct SourceRange {
+    pub(crate) start: u32,
+    pub(crate) end: u32,
+}
+
+impl SourceRange {
+    // Keeps the offsets of a span, capped to fit in a u32. Its line and
+    // column are dropped.
+    pub(crate) fn of(span: &Span) -> Self {
+        let offset = |n: usize| n.min(u32::MAX as usize) as u32;
+        Self {
+            start: offset(span.start),
+            end: offset(span.end),
+        }
+    }
+}
+
+// Offsets at which the lines of a source start, line 1 first.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct LineIndex {
+    starts: Vec<u32>,
+}
+
+impl Default for LineIndex {
+    fn default() -> Self {
+        Self { starts: vec![0] }
+    }
+}
+
+impl LineIndex {
+    // Builds an index from line starts, which must begin with 0 and
+    // rise, or returns None.
+    pub(crate) fn from_starts(starts: Vec<u32>) -> Option<Self> {
+        let valid = starts.first() == Some(&0) && starts.windows(2).all(|w| w[0] < w[1]);
+        valid.then_some(Self { starts })
+    }
+
+    pub(crate) fn starts(&self) -> &[u32] {
+        &self.starts
+    }
+
+    // Records that a line starts at offset, after any line so far.
+    pub(crate) fn push(&mut self, offset: u32) {
+        self.starts.push(offset);
+    }
+
+    // The span covering range, with the line and column of its start.
+    pub(crate) fn span(&self, range: SourceRange) -> Span {
+        let line = self.starts.partition_point(|&start| start <= range.start);
+        Span {
+            start: range.start as usize,
+            end: range.end as usize,
+            line,
+            col: (range.start - self.starts[line - 1]) as usize + 1,
+        }
+    }
+}
+
+// The spans of a run of ops, either as given or stored compactly as a
+// program keeps them.
+#[derive(Clone, Copy)]
+pub(crate) enum Spans<'a> {
+    Full(&'a [Span]),
+    Compact(&'a [SourceRange], &'a LineIndex),
+}
+
+impl Spans<'_> {
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            Spans::Full(spans) => spans.len(),
+            Spans::Compact(ranges, _) => ranges.len(),
+        }
+    }
+
+    pub(crate) fn get(&self, index: usize) -> Span {
+        match self {
+            Spans::Full(spans) => spans[index],
+            Spans::Compact(ranges, lines) => lines.span(ranges[index]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line_index() {
+        let mut lines = LineIndex::default();
+        // "ab\n\ncd\n"
+        lines.push(3);
+        lines.push(4);
+        lines.push(7);
+        let span = |start, end| lines.span(SourceRange { start, end });
+        let at = |span: Span| (span.line, span.col);
+        assert_eq!(at(span(0, 1)), (1, 1));
+        assert_eq!(at(span(2, 3)), (1, 3));
+        assert_eq!(at(span(3, 4)), (2, 1));
+        assert_eq!(at(span(5, 7)), (3, 2));
+        assert_eq!(span(5, 7).end, 7);
+        assert_eq!(at(span(7, 7)), (4, 1));
+
+        assert!(LineIndex::from_starts(vec![0, 3, 4]).is_some());
+        assert!(LineIndex::from_starts(vec![]).is_none());
+        assert!(LineIndex::from_starts(vec![1]).is_none());
+        assert!(LineIndex::from_starts(vec![0, 4, 4]).is_none());
+    }
+}
@@ -1,15 +1,20 @@
 use clap::{Parser, Subcommand, ValueEnum};
-use rustfuck::{Config, EofBehavior, Program};
+use rustfuck::{
+    CellWidth, CompileError, CompileOptions, Config, EofBehavior, ExecutionError, ExecutionResult,
+    Machine, Op, OpLimitAction, Program, RepeatReader, ReportOptions, Severity, Span, TeeWriter,
+};
 use serde::{Deserialize, Serialize};
 use std::fs;
-use std::io::{self, BufRead, Write};
-use std::path::PathBuf;
+use std::io::{self, BufRead, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
 #[derive(Debug, Clone, Copy, ValueEnum)]
 enum EofArg {
     Zero,
     Unchanged,
     Max,
+    Error,
 }
 
 impl From<EofArg> for EofBehavior {
@@ -18,10 +23,52 @@ impl From<EofArg> for EofBehavior {
             EofArg::Zero => EofBehavior::Zero,
             EofArg::Unchanged => EofBehavior::Unchanged,
             EofArg::Max => EofBehavior::MaxValue,
+            EofArg::Error => EofBehavior::Error,
         }
     }
 }
 
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum LimitActionArg {
+    Error,
+    Halt,
+}
+
+impl From<LimitActionArg> for OpLimitAction {
+    fn from(arg: LimitActionArg) -> Self {
+        match arg {
+            LimitActionArg::Error => OpLimitAction::Error,
+            LimitActionArg::Halt => OpLimitAction::Halt,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum TapeSizeArg {
+    Cells(usize),
+    // Use Program::min_tape_size, keeping the configured size when the
+    // program's reach isn't bounded
+    Auto,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum TargetArg {
+    C,
+    Rust,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum RepeatCount {
+    Times(usize),
+    Forever,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum DumpFormat {
+    Text,
+    Json,
+}
+
 #[derive(Parser, Debug)]
 #[command(name = "rustfuck")]
 #[command(about = "A brainfuck interpreter")]
@@ -30,40 +77,183 @@ struct Cli {
     command: Commands,
 }
 
+// Parsed once, so the size difference doesn't matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand, Debug)]
 enum Commands {
     /// Run a brainfuck program
     Run(RunArgs),
+    /// Time the bundled programs and print their throughput
+    Bench(BenchArgs),
+    /// Print the compiled ops of a brainfuck program
+    Disasm(DisasmArgs),
+    /// Translate a brainfuck program to source in another language
+    Transpile(TranspileArgs),
 }
 
 #[derive(Parser, Debug)]
-struct RunArgs {
+struct TranspileArgs {
+    /// Path to brainfuck source file
+    program: PathBuf,
+
+    /// Language to translate to
+    #[arg(long, value_enum)]
+    target: TargetArg,
+
+    /// Write the translation to file instead of stdout
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+
+    /// Tape size, or `auto` to fit it to the program [default: 30000]
+    #[arg(short = 'm', long = "memory", value_parser = parse_tape_size)]
+    tape_size: Option<TapeSizeArg>,
+
+    /// EOF behavior [default: unchanged]
+    #[arg(short, long, value_enum)]
+    eof: Option<EofArg>,
+}
+
+#[derive(Parser, Debug)]
+struct DisasmArgs {
     /// Path to brainfuck source file
     program: PathBuf,
+}
+
+#[derive(Parser, Debug)]
+struct BenchArgs {
+    /// Number of times to run each program
+    #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u32).range(1..))]
+    iterations: u32,
+
+    /// Run each program with Machine::run_to_completion instead of
+    /// Program::run_with_report, to compare the two
+    #[arg(long)]
+    machine: bool,
+}
+
+// Programs run by the bench subcommand: name, source, input and tape
+// size.
+const BENCH_PROGRAMS: &[(&str, &str, &[u8], usize)] = &[
+    (
+        "basicops",
+        include_str!("../tests/programs/basicops.b"),
+        include_bytes!("../tests/programs/basicops.in"),
+        30000,
+    ),
+    (
+        "factor",
+        include_str!("../tests/programs/factor.b"),
+        include_bytes!("../tests/programs/factor.in"),
+        30000,
+    ),
+    (
+        "memoryhog",
+        include_str!("../tests/programs/memoryhog.b"),
+        b"",
+        65536,
+    ),
+];
+
+#[derive(Parser, Debug)]
+struct RunArgs {
+    /// Path to brainfuck source file, or `-` to read it from stdin
+    #[arg(required_unless_present = "code")]
+    program: Option<PathBuf>,
+
+    /// Brainfuck source to run, instead of a file
+    #[arg(
+        short = 'c',
+        long = "code",
+        value_name = "SOURCE",
+        conflicts_with = "program"
+    )]
+    code: Option<String>,
 
     /// Read input from file instead of stdin
     #[arg(short, long)]
     input: Option<PathBuf>,
 
+    /// Feed the input to the program COUNT times over, or endlessly with `inf`
+    #[arg(long, value_name = "COUNT", value_parser = parse_repeat_count, conflicts_with = "repeat")]
+    input_repeat: Option<RepeatCount>,
+
     /// Write output to file instead of stdout
     #[arg(short, long)]
     output: Option<PathBuf>,
 
-    /// Tape size
-    #[arg(short = 'm', long = "memory", default_value_t = rustfuck::DEFAULT_TAPE_SIZE)]
-    tape_size: usize,
+    /// Tape size, or `auto` to fit it to the program [default: 30000]
+    #[arg(short = 'm', long = "memory", value_parser = parse_tape_size)]
+    tape_size: Option<TapeSizeArg>,
 
     /// Max operations (default: unlimited)
     #[arg(short = 'l', long = "limit")]
     op_limit: Option<usize>,
 
-    /// EOF behavior
-    #[arg(short, long, value_enum, default_value_t = EofArg::Unchanged)]
-    eof: EofArg,
+    /// What to do when the operation limit is exceeded [default: error]
+    #[arg(long, value_enum)]
+    limit_action: Option<LimitActionArg>,
+
+    /// EOF behavior [default: unchanged]
+    #[arg(short, long, value_enum)]
+    eof: Option<EofArg>,
+
+    /// Refuse source files larger than this many bytes
+    #[arg(long, value_name = "BYTES")]
+    max_source_size: Option<usize>,
+
+    /// Load settings from a JSON config file; other flags override it
+    #[arg(long)]
+    config: Option<PathBuf>,
 
     /// Enable batch/ndjson mode
     #[arg(long)]
     batch: bool,
+
+    /// Also write output to this file
+    #[arg(long, value_name = "PATH")]
+    tee: Option<PathBuf>,
+
+    /// Escape non-printable output bytes for display
+    #[arg(long)]
+    ascii: bool,
+
+    /// With --ascii, break escaped output into lines of N characters
+    #[arg(long, value_name = "N", requires = "ascii", value_parser = clap::value_parser!(u32).range(1..))]
+    wrap: Option<u32>,
+
+    /// Echo input bytes consumed by the program to stderr
+    #[arg(long)]
+    echo_input: bool,
+
+    /// Run the program N times and print timings to stderr
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    repeat: Option<u32>,
+
+    /// Print the final tape, trimmed of trailing zeros, and pointer to stderr
+    #[arg(long)]
+    dump_tape: bool,
+
+    /// Print only cells START to END (exclusive) of the final tape; implies --dump-tape
+    #[arg(long, value_name = "START:END", value_parser = parse_range)]
+    dump_tape_range: Option<[usize; 2]>,
+
+    /// Treat `#` as a breakpoint that prints the pointer and nearby cells to stderr
+    #[arg(long)]
+    debug: bool,
+
+    /// Compile and print diagnostics without running; fail on errors
+    #[arg(long, conflicts_with_all = ["dump_ops", "batch"])]
+    dry_run: bool,
+
+    /// Print the compiled ops instead of running the program
+    #[arg(long, value_enum, num_args = 0..=1, require_equals = true, default_missing_value = "text")]
+    dump_ops: Option<DumpFormat>,
+}
+
+#[derive(Debug, Serialize)]
+struct DumpOps<'a> {
+    ops: &'a [Op],
+    spans: &'a [Span],
 }
 
 #[derive(Debug, Deserialize)]
@@ -80,6 +270,7 @@ struct BatchInput {
     pointer: Option<usize>,
     input: Option<Vec<u8>>,
     config: Option<BatchConfig>,
+    tape_window: Option<[usize; 2]>,
 }
 
 #[derive(Debug, Serialize)]
@@ -88,6 +279,8 @@ struct BatchOutputOk {
     id: Option<String>,
     ok: bool,
     tape: Vec<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tape_window: Option<[usize; 2]>,
     pointer: usize,
     output: Vec<u8>,
 }
@@ -100,6 +293,63 @@ struct BatchOutputErr {
     error: String,
 }
 
+// Escapes output bytes that aren't printable ASCII, so that control
+// characters show up as readable text.
+// With wrap set, starts a new line before an escape that would cross
+// the given column, so escapes are never split.
+struct EscapingWriter<W: Write> {
+    inner: W,
+    wrap: Option<usize>,
+    column: usize,
+}
+
+impl<W: Write> Write for EscapingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut hex = [0u8; 4];
+        for &b in buf {
+            let escaped: &[u8] = match b {
+                b'\n' => b"\\n",
+                b'\r' => b"\\r",
+                b'\t' => b"\\t",
+                b'\\' => b"\\\\",
+                b' '..=b'~' => std::slice::from_ref(&b),
+                _ => {
+                    write!(&mut hex[..], "\\x{:02x}", b)?;
+                    &hex
+                }
+            };
+            if let Some(wrap) = self.wrap {
+                if self.column > 0 && self.column + escaped.len() > wrap {
+                    self.inner.write_all(b"\n")?;
+                    self.column = 0;
+                }
+                self.column += escaped.len();
+            }
+            self.inner.write_all(escaped)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Copies every byte read from the inner reader to stderr.
+struct EchoingReader<R: Read> {
+    inner: R,
+}
+
+impl<R: Read> Read for EchoingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        let mut stderr = io::stderr();
+        stderr.write_all(&buf[..n])?;
+        stderr.flush()?;
+        Ok(n)
+    }
+}
+
 fn trim_tape(mut tape: Vec<u8>) -> Vec<u8> {
     while tape.last() == Some(&0) {
         tape.pop();
@@ -107,14 +357,117 @@ fn trim_tape(mut tape: Vec<u8>) -> Vec<u8> {
     tape
 }
 
+// Cuts the tape down to the cells in [start, end), clamped to the tape.
+// Returns the cells and the bounds actually used.
+fn window_tape(tape: Vec<u8>, [start, end]: [usize; 2]) -> (Vec<u8>, [usize; 2]) {
+    let end = end.min(tape.len());
+    let start = start.min(end);
+    (tape[start..end].to_vec(), [start, end])
+}
+
 fn parse_eof_string(s: &str) -> EofBehavior {
     match s.to_lowercase().as_str() {
         "unchanged" => EofBehavior::Unchanged,
         "max" => EofBehavior::MaxValue,
+        "error" => EofBehavior::Error,
         _ => EofBehavior::Zero,
     }
 }
 
+// Reads a setting from the environment, if set.
+fn env_setting<T>(
+    name: &str,
+    parse: impl Fn(&str) -> Result<T, String>,
+) -> Result<Option<T>, String> {
+    match std::env::var(name) {
+        Ok(value) => parse(&value)
+            .map(Some)
+            .map_err(|e| format!("invalid {}: {}", name, e)),
+        Err(std::env::VarError::NotPresent) => Ok(None),
+        Err(e) => Err(format!("invalid {}: {}", name, e)),
+    }
+}
+
+// The configuration commands start from. EOF leaves the cell
+// unchanged, unlike in the library.
+fn default_config() -> Config {
+    Config {
+        eof_behavior: EofBehavior::Unchanged,
+        ..Default::default()
+    }
+}
+
+// Builds the run configuration. Flags take precedence over the config
+// file, which takes precedence over environment variables, which take
+// precedence over the defaults. A tape size of `auto` fits the tape to
+// the program.
+fn build_config(args: &RunArgs, program: &Program) -> Result<Config, String> {
+    let mut config = default_config();
+    let mut auto_tape_size = false;
+
+    let parse_usize = |s: &str| s.parse::<usize>().map_err(|e| e.to_string());
+    match env_setting("RUSTFUCK_TAPE_SIZE", parse_tape_size)? {
+        Some(TapeSizeArg::Cells(tape_size)) => config.tape_size = tape_size,
+        Some(TapeSizeArg::Auto) => auto_tape_size = true,
+        None => {}
+    }
+    if let Some(op_limit) = env_setting("RUSTFUCK_OP_LIMIT", parse_usize)? {
+        config.op_limit = Some(op_limit);
+    }
+    if let Some(eof) = env_setting("RUSTFUCK_EOF", |s| EofArg::from_str(s, true))? {
+        config.eof_behavior = eof.into();
+    }
+
+    if let Some(path) = &args.config {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("failed to read config file {}: {}", path.display(), e))?;
+        let overrides: serde_json::Map<String, serde_json::Value> =
+            serde_json::from_str(&contents).map_err(|e| format!("invalid config file: {}", e))?;
+        let mut merged = match serde_json::to_value(&config) {
+            Ok(serde_json::Value::Object(map)) => map,
+            _ => unreachable!("Config serializes to an object"),
+        };
+        auto_tape_size &= !overrides.contains_key("tape_size");
+        merged.extend(overrides);
+        config = serde_json::from_value(serde_json::Value::Object(merged))
+            .map_err(|e| format!("invalid config file: {}", e))?;
+    }
+
+    match args.tape_size {
+        Some(TapeSizeArg::Cells(tape_size)) => {
+            config.tape_size = tape_size;
+            auto_tape_size = false;
+        }
+        Some(TapeSizeArg::Auto) => auto_tape_size = true,
+        None => {}
+    }
+    if auto_tape_size {
+        if let Some(size) = program.min_tape_size() {
+            config.tape_size = size;
+        }
+    }
+    if let Some(op_limit) = args.op_limit {
+        config.op_limit = Some(op_limit);
+    }
+    if let Some(limit_action) = args.limit_action {
+        config.op_limit_action = limit_action.into();
+    }
+    if let Some(eof) = args.eof {
+        config.eof_behavior = eof.into();
+    }
+    if args.batch {
+        config.flush_output = false;
+    }
+
+    // Programs are compiled for 8-bit cells
+    if config.cell_width != CellWidth::Eight {
+        let width = config.cell_width;
+        return Err(ExecutionError::UnsupportedCellWidth { width }.to_string());
+    }
+
+    Ok(config)
+}
+
 // Processes batches of input/output for the program, read/written
 // from/to stdin/stdout. These are expected to be newline separated
 // json objects.
@@ -122,91 +475,148 @@ fn run_batch(program: &Program, base_config: &Config) {
     let stdin = io::stdin();
     let mut stdout = io::stdout();
 
-    for line in stdin.lock().lines() {
-        let line = match line {
-            Ok(l) => l,
-            Err(e) => {
-                let err = BatchOutputErr {
-                    id: None,
-                    ok: false,
-                    error: format!("failed to read input line: {}", e),
-                };
-                let _ = serde_json::to_writer(&mut stdout, &err);
-                let _ = writeln!(stdout);
-                continue;
-            }
-        };
+    for result in BatchResults::new(program, base_config, stdin.lock()) {
+        let _ = serde_json::to_writer(&mut stdout, &result);
+        let _ = writeln!(stdout);
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum BatchOutput {
+    Ok(BatchOutputOk),
+    Err(BatchOutputErr),
+}
 
-        if line.trim().is_empty() {
-            continue;
+// Runs the program once per input line, yielding each result as soon
+// as its line has been read and run. Blank lines are skipped. Lines are
+// read into the same buffer each time to save allocating one per line.
+struct BatchResults<'a, R: BufRead> {
+    program: &'a Program,
+    base_config: &'a Config,
+    input: R,
+    line: String,
+}
+
+impl<'a, R: BufRead> BatchResults<'a, R> {
+    fn new(program: &'a Program, base_config: &'a Config, input: R) -> Self {
+        Self {
+            program,
+            base_config,
+            input,
+            line: String::new(),
         }
+    }
+}
 
-        let batch_input: BatchInput = match serde_json::from_str(&line) {
-            Ok(bi) => bi,
-            Err(e) => {
-                let err = BatchOutputErr {
-                    id: None,
-                    ok: false,
-                    error: format!("invalid JSON: {}", e),
-                };
-                let _ = serde_json::to_writer(&mut stdout, &err);
-                let _ = writeln!(stdout);
-                continue;
-            }
-        };
+impl<R: BufRead> Iterator for BatchResults<'_, R> {
+    type Item = BatchOutput;
 
-        let config = if let Some(bc) = &batch_input.config {
-            Config {
-                tape_size: bc.tape_size.unwrap_or(base_config.tape_size),
-                op_limit: bc.op_limit.or(base_config.op_limit),
-                eof_behavior: bc
-                    .eof_behavior
-                    .as_ref()
-                    .map(|s| parse_eof_string(s))
-                    .unwrap_or(base_config.eof_behavior),
-                flush_output: false,
+    fn next(&mut self) -> Option<BatchOutput> {
+        loop {
+            self.line.clear();
+            match self.input.read_line(&mut self.line) {
+                Ok(0) => return None,
+                Ok(_) => {}
+                Err(e) => {
+                    return Some(BatchOutput::Err(BatchOutputErr {
+                        id: None,
+                        ok: false,
+                        error: format!("failed to read input line: {}", e),
+                    }));
+                }
             }
-        } else {
-            Config {
-                flush_output: false,
-                ..base_config.clone()
+            if !self.line.trim().is_empty() {
+                return Some(run_batch_line(self.program, self.base_config, &self.line));
             }
-        };
+        }
+    }
+}
 
-        let input_bytes = batch_input.input.unwrap_or_default();
-        let mut output_buf = Vec::new();
-        let mut input_slice = input_bytes.as_slice();
+fn run_batch_line(program: &Program, base_config: &Config, line: &str) -> BatchOutput {
+    let batch_input: BatchInput = match serde_json::from_str(line) {
+        Ok(bi) => bi,
+        Err(e) => {
+            return BatchOutput::Err(BatchOutputErr {
+                id: None,
+                ok: false,
+                error: format!("invalid JSON: {}", e),
+            });
+        }
+    };
 
-        let result = program.run(
-            &config,
-            batch_input.tape,
-            batch_input.pointer,
-            Some(&mut input_slice),
-            Some(&mut output_buf),
-        );
+    let config = if let Some(bc) = &batch_input.config {
+        Config {
+            tape_size: bc.tape_size.unwrap_or(base_config.tape_size),
+            op_limit: bc.op_limit.or(base_config.op_limit),
+            eof_behavior: bc
+                .eof_behavior
+                .as_ref()
+                .map(|s| parse_eof_string(s))
+                .unwrap_or(base_config.eof_behavior),
+            flush_output: false,
+            ..base_config.clone()
+        }
+    } else {
+        Config {
+            flush_output: false,
+            ..base_config.clone()
+        }
+    };
 
-        match result {
-            Ok(exec_result) => {
-                let out = BatchOutputOk {
-                    id: batch_input.id,
-                    ok: true,
-                    tape: trim_tape(exec_result.tape),
-                    pointer: exec_result.pointer,
-                    output: output_buf,
-                };
-                let _ = serde_json::to_writer(&mut stdout, &out);
-                let _ = writeln!(stdout);
-            }
-            Err(e) => {
-                let err = BatchOutputErr {
-                    id: batch_input.id,
-                    ok: false,
-                    error: e.to_string(),
-                };
-                let _ = serde_json::to_writer(&mut stdout, &err);
-                let _ = writeln!(stdout);
+    let input_bytes = batch_input.input.unwrap_or_default();
+    let mut output_buf = Vec::new();
+    let mut input_slice = input_bytes.as_slice();
+
+    let result = program.run(
+        &config,
+        batch_input.tape,
+        batch_input.pointer,
+        Some(&mut input_slice),
+        Some(&mut output_buf),
+    );
+
+    match result {
+        Ok(exec_result) => {
+            let (tape, tape_window) = match batch_input.tape_window {
+                Some(window) => {
+                    let (tape, window) = window_tape(exec_result.tape, window);
+                    (tape, Some(window))
+                }
+                None => (trim_tape(exec_result.tape), None),
+            };
+            BatchOutput::Ok(BatchOutputOk {
+                id: batch_input.id,
+                ok: true,
+                tape,
+                tape_window,
+                pointer: exec_result.pointer,
+                output: output_buf,
+            })
+        }
+        Err(e) => BatchOutput::Err(BatchOutputErr {
+            id: batch_input.id,
+            ok: false,
+            error: e.to_string(),
+        }),
+    }
+}
+
+fn dump_ops(program: &Program, format: DumpFormat) {
+    match format {
+        DumpFormat::Text => {
+            for (i, (op, span)) in program.ops.iter().zip(program.spans()).enumerate() {
+                println!("{:04}  {:?} ({}:{})", i, op, span.line, span.col);
             }
         }
+        DumpFormat::Json => {
+            let spans: Vec<Span> = program.spans().collect();
+            let dump = DumpOps {
+                ops: &program.ops,
+                spans: &spans,
+            };
+            println!("{}", serde_json::to_string(&dump).unwrap());
+        }
     }
 }
 
@@ -216,6 +626,21 @@ fn run_normal(program: &Program, config: &Config, args: &RunArgs) -> Result<(),
     } else {
         Box::new(io::stdin())
     };
+    let input: Box<dyn io::Read> = match args.input_repeat {
+        Some(count) => {
+            let mut data = Vec::new();
+            let mut input = input;
+            input
+                .read_to_end(&mut data)
+                .map_err(|e| format!("failed to read input: {}", e))?;
+            let times = match count {
+                RepeatCount::Times(n) => Some(n),
+                RepeatCount::Forever => None,
+            };
+            Box::new(RepeatReader::new(data, times))
+        }
+        None => input,
+    };
 
     let output: Box<dyn io::Write> = if let Some(path) = &args.output {
         Box::new(
@@ -224,35 +649,331 @@ fn run_normal(program: &Program, config: &Config, args: &RunArgs) -> Result<(),
     } else {
         Box::new(io::stdout())
     };
+    let output: Box<dyn io::Write> = if let Some(path) = &args.tee {
+        let file =
+            fs::File::create(path).map_err(|e| format!("failed to create tee file: {}", e))?;
+        Box::new(TeeWriter::new(vec![output, Box::new(file)]))
+    } else {
+        output
+    };
+    let output: Box<dyn io::Write> = if args.ascii {
+        Box::new(EscapingWriter {
+            inner: output,
+            wrap: args.wrap.map(|n| n as usize),
+            column: 0,
+        })
+    } else {
+        output
+    };
+
+    let dump = args.dump_tape || args.dump_tape_range.is_some();
+
+    if let Some(repeat) = args.repeat {
+        let result = run_repeated(program, config, args, repeat, input, output)?;
+        if dump {
+            dump_tape(result, args.dump_tape_range);
+        }
+        return Ok(());
+    }
 
-    let mut input = input;
+    let mut input = echo_input(input, args.echo_input);
     let mut output = output;
 
-    program
-        .run(config, None, None, Some(&mut input), Some(&mut output))
-        .map_err(|e| e.to_string())?;
+    if dump || args.debug {
+        let result = program
+            .run_with_debug(
+                config,
+                None,
+                None,
+                Some(&mut input),
+                Some(&mut output),
+                &mut print_breakpoint,
+            )
+            .map_err(|e| e.to_string())?;
+        if dump {
+            dump_tape(result, args.dump_tape_range);
+        }
+    } else {
+        program
+            .run_discard_state(config, Some(&mut input), Some(&mut output))
+            .map_err(|e| e.to_string())?;
+    }
+
+    Ok(())
+}
+
+fn echo_input<'a>(input: Box<dyn io::Read + 'a>, echo: bool) -> Box<dyn io::Read + 'a> {
+    if echo {
+        Box::new(EchoingReader { inner: input })
+    } else {
+        input
+    }
+}
+
+// Prints the tape, trimmed or windowed, and the pointer to stderr.
+fn dump_tape(result: ExecutionResult, range: Option<[usize; 2]>) {
+    let (tape, label) = match range {
+        Some(range) => {
+            let (tape, [start, end]) = window_tape(result.tape, range);
+            (tape, format!("tape[{}..{}]", start, end))
+        }
+        None => (trim_tape(result.tape), "tape".to_string()),
+    };
+    eprintln!("{}: {:?}", label, tape);
+    eprintln!("pointer: {}", result.pointer);
+}
+
+// Prints the cells around the pointer at a `#` breakpoint.
+fn print_breakpoint(pointer: usize, tape: &[u8]) {
+    let start = pointer.saturating_sub(8);
+    let end = (pointer + 9).min(tape.len());
+    eprintln!("tape[{}..{}]: {:?}", start, end, &tape[start..end]);
+    eprintln!("pointer: {}", pointer);
+}
+
+fn parse_tape_size(s: &str) -> Result<TapeSizeArg, String> {
+    if s == "auto" {
+        return Ok(TapeSizeArg::Auto);
+    }
+    s.parse()
+        .map(TapeSizeArg::Cells)
+        .map_err(|e| format!("expected a number of cells or \"auto\": {}", e))
+}
+
+fn parse_repeat_count(s: &str) -> Result<RepeatCount, String> {
+    if s == "inf" {
+        return Ok(RepeatCount::Forever);
+    }
+    s.parse()
+        .map(RepeatCount::Times)
+        .map_err(|e| format!("expected a count or \"inf\": {}", e))
+}
+
+fn parse_range(s: &str) -> Result<[usize; 2], String> {
+    let (start, end) = s
+        .split_once(':')
+        .ok_or_else(|| "expected START:END".to_string())?;
+    let start = start.parse().map_err(|e| format!("invalid start: {}", e))?;
+    let end = end.parse().map_err(|e| format!("invalid end: {}", e))?;
+    Ok([start, end])
+}
+
+// Runs the program several times on a fresh tape, replaying the same
+// input each time, and reports wall-clock timings to stderr. Only the
+// last run's output is kept.
+fn run_repeated(
+    program: &Program,
+    config: &Config,
+    args: &RunArgs,
+    repeat: u32,
+    mut input: Box<dyn io::Read>,
+    mut output: Box<dyn io::Write>,
+) -> Result<ExecutionResult, String> {
+    let mut input_bytes = Vec::new();
+    input
+        .read_to_end(&mut input_bytes)
+        .map_err(|e| format!("failed to read input: {}", e))?;
+
+    let mut timings = Vec::with_capacity(repeat as usize);
+    let mut result = None;
+    for i in 0..repeat {
+        let last = i + 1 == repeat;
+        let mut input = echo_input(Box::new(input_bytes.as_slice()), last && args.echo_input);
+        let mut sink = io::sink();
+        let output: &mut dyn io::Write = if last { &mut output } else { &mut sink };
+
+        let start = Instant::now();
+        let run = program
+            .run(config, None, None, Some(&mut input), Some(output))
+            .map_err(|e| e.to_string())?;
+        timings.push(start.elapsed());
+        result = Some(run);
+    }
+
+    let min = timings.iter().min().unwrap();
+    let max = timings.iter().max().unwrap();
+    let mean = timings.iter().sum::<Duration>() / repeat;
+    eprintln!("runs: {}", repeat);
+    eprintln!("min:  {:?}", min);
+    eprintln!("mean: {:?}", mean);
+    eprintln!("max:  {:?}", max);
+
+    Ok(result.unwrap())
+}
+
+// Runs each bundled program the given number of times and prints the
+// ops executed and the rate they were executed at.
+fn run_bench(args: &BenchArgs) -> Result<(), String> {
+    for &(name, source, input, tape_size) in BENCH_PROGRAMS {
+        let program = Program::from_source(source).map_err(|e| format!("{}: {}", name, e))?;
+        let config = Config {
+            tape_size,
+            flush_output: false,
+            report: ReportOptions {
+                op_count: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let mut ops = 0;
+        let start = Instant::now();
+        for _ in 0..args.iterations {
+            let mut input = input;
+            ops += if args.machine {
+                Machine::new(&program, &config, None, None)
+                    .run_to_completion(Some(&mut input), Some(&mut io::sink()))
+                    .map_err(|e| format!("{}: {}", name, e))?
+                    .ops_executed
+            } else {
+                let (_, report) = program
+                    .run_with_report(&config, None, None, Some(&mut input), Some(&mut io::sink()))
+                    .map_err(|e| format!("{}: {}", name, e))?;
+                report.op_count.unwrap_or(0)
+            };
+        }
+        let elapsed = start.elapsed();
 
+        println!(
+            "{:<10} {:>12} ops  {:>10.3?}  {:>14.0} ops/sec",
+            name,
+            ops,
+            elapsed,
+            ops as f64 / elapsed.as_secs_f64()
+        );
+    }
     Ok(())
 }
 
+// Reads a program's source, exiting with an error if it can't be read.
+fn read_source(path: &Path) -> String {
+    match fs::read_to_string(path) {
+        Ok(s) => s,
+        Err(e) => {
+            if e.kind() == io::ErrorKind::NotFound {
+                eprintln!("Error: file not found: {}", path.display());
+            } else {
+                eprintln!("Error reading {}: {}", path.display(), e);
+            }
+            std::process::exit(1);
+        }
+    }
+}
+
+fn run_transpile(args: &TranspileArgs) -> Result<(), String> {
+    let source = read_source(&args.program);
+    let program = Program::from_source(&source).map_err(|e| format!("Compile error: {}", e))?;
+
+    let mut config = default_config();
+    if let Some(eof) = args.eof {
+        config.eof_behavior = eof.into();
+    }
+    match args.tape_size {
+        Some(TapeSizeArg::Cells(size)) => config.tape_size = size,
+        Some(TapeSizeArg::Auto) => {
+            if let Some(size) = program.min_tape_size() {
+                config.tape_size = size;
+            }
+        }
+        None => {}
+    }
+    let code = match args.target {
+        TargetArg::C => program.to_c(&config),
+        TargetArg::Rust => program.to_rust(&config),
+    };
+
+    match &args.output {
+        Some(path) => {
+            fs::write(path, code).map_err(|e| format!("Error: failed to write output file: {}", e))
+        }
+        None => io::stdout()
+            .write_all(code.as_bytes())
+            .map_err(|e| format!("Error: {}", e)),
+    }
+}
+
 fn main() {
     let cli = Cli::parse();
 
     match cli.command {
-        Commands::Run(args) => {
-            let source = match fs::read_to_string(&args.program) {
-                Ok(s) => s,
+        Commands::Transpile(args) => {
+            if let Err(e) = run_transpile(&args) {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        Commands::Disasm(args) => {
+            let source = read_source(&args.program);
+            match Program::from_source(&source) {
+                Ok(program) => print!("{}", program.disassemble()),
                 Err(e) => {
-                    if e.kind() == io::ErrorKind::NotFound {
-                        eprintln!("Error: file not found: {}", args.program.display());
-                    } else {
-                        eprintln!("Error reading {}: {}", args.program.display(), e);
-                    }
+                    eprintln!("Compile error: {}", e);
                     std::process::exit(1);
                 }
+            }
+        }
+        Commands::Bench(args) => {
+            if let Err(e) = run_bench(&args) {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Commands::Run(args) => {
+            let source = match (&args.code, &args.program) {
+                (Some(code), _) => code.clone(),
+                (None, Some(path)) if path.as_os_str() == "-" => {
+                    // Stdin can only carry one of the program and its input
+                    if args.batch {
+                        eprintln!(
+                            "Error: --batch reads from stdin, so the program can't be read from it"
+                        );
+                        std::process::exit(1);
+                    }
+                    if args.input.is_none() {
+                        eprintln!("Error: the program is read from stdin, so its input must be given with -i");
+                        std::process::exit(1);
+                    }
+                    let mut source = String::new();
+                    if let Err(e) = io::stdin().read_to_string(&mut source) {
+                        eprintln!("Error reading program from stdin: {}", e);
+                        std::process::exit(1);
+                    }
+                    source
+                }
+                (None, Some(path)) => {
+                    // Check the size up front so a huge file isn't read at all
+                    if let Some(limit) = args.max_source_size {
+                        if let Ok(metadata) = fs::metadata(path) {
+                            if metadata.len() > limit as u64 {
+                                eprintln!(
+                                    "Compile error: {}",
+                                    CompileError::SourceTooLarge {
+                                        size: metadata.len() as usize,
+                                        limit,
+                                    }
+                                );
+                                std::process::exit(1);
+                            }
+                        }
+                    }
+                    read_source(path)
+                }
+                // clap requires one of them
+                (None, None) => unreachable!(),
             };
 
-            let program = match Program::from_source(&source) {
+            let options = CompileOptions {
+                debug_char: args.debug.then_some(b'#'),
+                ..Default::default()
+            };
+            let compiled = match args.max_source_size {
+                Some(limit) if source.len() > limit => Err(CompileError::SourceTooLarge {
+                    size: source.len(),
+                    limit,
+                }),
+                _ => Program::from_source_with_options(&source, &options),
+            };
+            let program = match compiled {
                 Ok(p) => p,
                 Err(e) => {
                     eprintln!("Compile error: {}", e);
@@ -260,11 +981,33 @@ fn main() {
                 }
             };
 
-            let config = Config {
-                tape_size: args.tape_size,
-                op_limit: args.op_limit,
-                eof_behavior: args.eof.into(),
-                flush_output: !args.batch,
+            if let Some(format) = args.dump_ops {
+                dump_ops(&program, format);
+                return;
+            }
+
+            if args.dry_run {
+                let mut errors = false;
+                for diagnostic in &program.diagnostics {
+                    let severity = match diagnostic.kind.severity() {
+                        Severity::Warning => "warning",
+                        Severity::Error => "error",
+                    };
+                    errors |= diagnostic.kind.severity() == Severity::Error;
+                    eprintln!("{}: {}", severity, diagnostic);
+                }
+                if errors {
+                    std::process::exit(1);
+                }
+                return;
+            }
+
+            let config = match build_config(&args, &program) {
+                Ok(c) => c,
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(1);
+                }
             };
 
             if args.batch {
@@ -276,3 +1019,65 @@ fn main() {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    // Hands out one line per read, counting the reads
+    struct LineReader<'a> {
+        lines: std::slice::Iter<'a, &'a str>,
+        reads: &'a Cell<usize>,
+    }
+
+    impl Read for LineReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.reads.set(self.reads.get() + 1);
+            let Some(line) = self.lines.next() else {
+                return Ok(0);
+            };
+            buf[..line.len()].copy_from_slice(line.as_bytes());
+            Ok(line.len())
+        }
+    }
+
+    #[test]
+    fn test_batch_results_streaming() {
+        let program = Program::from_source(",[.,]").unwrap();
+        let config = Config::default();
+        let lines = [
+            "{\"id\": \"a\", \"input\": [104, 105]}\n",
+            "\n",
+            "{\"id\": \"b\", \"input\": [33]}\n",
+            "{\"id\": \"c\", \"tape_size\": }\n",
+        ];
+        let reads = Cell::new(0);
+        let reader = io::BufReader::new(LineReader {
+            lines: lines.iter(),
+            reads: &reads,
+        });
+        let mut results = BatchResults::new(&program, &config, reader);
+
+        let Some(BatchOutput::Ok(a)) = results.next() else {
+            panic!("expected a result for a");
+        };
+        assert_eq!(a.id.as_deref(), Some("a"));
+        assert_eq!(a.output, b"hi");
+        // Only the first line has been read so far
+        assert_eq!(reads.get(), 1);
+
+        let Some(BatchOutput::Ok(b)) = results.next() else {
+            panic!("expected a result for b");
+        };
+        assert_eq!(b.id.as_deref(), Some("b"));
+        assert_eq!(b.output, b"!");
+        assert_eq!(reads.get(), 3);
+
+        assert!(matches!(
+            results.next(),
+            Some(BatchOutput::Err(BatchOutputErr { id: None, .. }))
+        ));
+        assert!(results.next().is_none());
+    }
+}
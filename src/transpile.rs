@@ -0,0 +1,393 @@
+use crate::{Config, EofBehavior, Op, Span};
+use std::fmt::Write;
+
+// Emits a WebAssembly text module running the ops. The tape lives at
+// the start of linear memory and the pointer in a local.
+pub(crate) fn to_wat(ops: &[Op]) -> String {
+    let mut wat = String::new();
+    wat.push_str("(module\n");
+    wat.push_str("  (import \"env\" \"input\" (func $input (result i32)))\n");
+    wat.push_str("  (import \"env\" \"output\" (func $output (param i32)))\n");
+    wat.push_str("  (memory (export \"memory\") 1)\n");
+    wat.push_str("  (func (export \"run\")\n");
+    wat.push_str("    (local $p i32)\n");
+    wat.push_str("    (local $v i32)\n");
+
+    let mut depth = 2;
+    for (i, op) in ops.iter().enumerate() {
+        if let Op::Close(_) = op {
+            depth -= 2;
+        }
+        let indent = "  ".repeat(depth);
+        let line = match op {
+            Op::Add(n) => format!(
+                "(i32.store8 (local.get $p) (i32.add (i32.load8_u (local.get $p)) (i32.const {})))",
+                n
+            ),
+            Op::Move(n) => format!("(local.set $p (i32.add (local.get $p) (i32.const {})))", n),
+            Op::Out => "(call $output (i32.load8_u (local.get $p)))".to_string(),
+            // A negative return from input means EOF, which leaves the
+            // cell unchanged
+            Op::In => {
+                "(local.set $v (call $input))\n".to_string()
+                    + &indent
+                    + "(if (i32.ge_s (local.get $v) (i32.const 0)) \
+                   (then (i32.store8 (local.get $p) (local.get $v))))"
+            }
+            Op::Open(_) => format!(
+                "(block $b{i}\n{indent}  (loop $l{i}\n{indent}    \
+                 (br_if $b{i} (i32.eqz (i32.load8_u (local.get $p))))"
+            ),
+            Op::Close(target) => format!("    (br $l{})))", target),
+            Op::Set(n) => format!("(i32.store8 (local.get $p) (i32.const {}))", n),
+            Op::Mul(offset, factor) => format!(
+                "(i32.store8 (i32.add (local.get $p) (i32.const {offset})) \
+                 (i32.add (i32.load8_u (i32.add (local.get $p) (i32.const {offset}))) \
+                 (i32.mul (i32.load8_u (local.get $p)) (i32.const {factor}))))"
+            ),
+            Op::Scan(step) => format!(
+                "(block $b{i}\n{indent}  (loop $l{i}\n{indent}    \
+                 (br_if $b{i} (i32.eqz (i32.load8_u (local.get $p))))\n{indent}    \
+                 (local.set $p (i32.add (local.get $p) (i32.const {step})))\n{indent}    \
+                 (br $l{i})))"
+            ),
+            Op::Halt => "(return)".to_string(),
+            Op::Debug => "(nop)".to_string(),
+        };
+        writeln!(wat, "{}{}", indent, line).unwrap();
+        if let Op::Open(_) = op {
+            depth += 2;
+        }
+    }
+
+    wat.push_str("  )\n");
+    wat.push_str(")\n");
+    wat
+}
+
+// Index expression for the cell at an offset from the pointer, in
+// Rust or C
+fn cell_index(offset: i32) -> String {
+    match offset {
+        0 => "p".to_string(),
+        n if n > 0 => format!("p + {}", n),
+        n => format!("p - {}", n.unsigned_abs()),
+    }
+}
+
+// Emits a standalone Rust program running the ops as straight-line
+// code, with loops as `while`. The tape is a fixed vector and indexing
+// off either end panics.
+pub(crate) fn to_rust(ops: &[Op], config: &Config) -> String {
+    let mut rs = String::new();
+    rs.push_str("use std::io::{self, Read, Write};\n\n");
+    rs.push_str("#[allow(unused_mut, unused_variables, unreachable_code)]\n");
+    rs.push_str("fn run(input: &mut impl Read, out: &mut impl Write) -> io::Result<()> {\n");
+    writeln!(rs, "    let mut tape = vec![0u8; {}];", config.tape_size).unwrap();
+    rs.push_str("    let mut p: usize = 0;\n");
+
+    let eof = match config.eof_behavior {
+        EofBehavior::Zero => " else { tape[p] = 0; }",
+        EofBehavior::Unchanged => "",
+        EofBehavior::MaxValue => " else { tape[p] = 255; }",
+        EofBehavior::Error => {
+            " else { return Err(io::Error::new(io::ErrorKind::UnexpectedEof, \
+             \"unexpected end of input\")); }"
+        }
+    };
+
+    let mut depth = 1;
+    for op in ops {
+        if let Op::Close(_) = op {
+            depth -= 1;
+        }
+        let indent = "    ".repeat(depth);
+        let line = match op {
+            Op::Add(n) => format!("tape[p] = tape[p].wrapping_add({});", n),
+            Op::Move(n) if *n >= 0 => format!("p += {};", n),
+            Op::Move(n) => format!("p -= {};", n.unsigned_abs()),
+            Op::Out => "out.write_all(&[tape[p]])?;".to_string(),
+            Op::In => {
+                "let mut b = [0u8];\n".to_string()
+                    + &indent
+                    + "if input.read(&mut b)? == 1 { tape[p] = b[0]; }"
+                    + eof
+            }
+            Op::Open(_) => "while tape[p] != 0 {".to_string(),
+            Op::Close(_) => "}".to_string(),
+            Op::Set(n) => format!("tape[p] = {}u8;", n),
+            Op::Mul(offset, factor) => {
+                let cell = cell_index(*offset);
+                format!("tape[{cell}] = tape[{cell}].wrapping_add(tape[p].wrapping_mul({factor}));")
+            }
+            Op::Scan(1) => "p += tape[p..].iter().position(|&c| c == 0).unwrap();".to_string(),
+            Op::Scan(-1) => "p = tape[..=p].iter().rposition(|&c| c == 0).unwrap();".to_string(),
+            Op::Scan(step) if *step > 0 => format!("while tape[p] != 0 {{ p += {}; }}", step),
+            Op::Scan(step) => format!("while tape[p] != 0 {{ p -= {}; }}", step.unsigned_abs()),
+            Op::Halt => "return Ok(());".to_string(),
+            Op::Debug => "// breakpoint".to_string(),
+        };
+        writeln!(rs, "{}{}", indent, line).unwrap();
+        if let Op::Open(_) = op {
+            depth += 1;
+        }
+    }
+
+    rs.push_str("    Ok(())\n");
+    rs.push_str("}\n\n");
+    rs.push_str("fn main() -> io::Result<()> {\n");
+    rs.push_str("    let mut input = io::stdin().lock();\n");
+    rs.push_str("    let mut out = io::BufWriter::new(io::stdout().lock());\n");
+    rs.push_str("    run(&mut input, &mut out)?;\n");
+    rs.push_str("    out.flush()\n");
+    rs.push_str("}\n");
+    rs
+}
+
+// Emits a standalone C program running the ops, with the tape a
+// static array and the pointer an index into it. Moving off the tape
+// isn't checked.
+pub(crate) fn to_c(ops: &[Op], config: &Config) -> String {
+    let mut c = String::new();
+    c.push_str("#include <stdio.h>\n");
+    c.push_str("#include <string.h>\n\n");
+    writeln!(c, "#define TAPE_SIZE {}\n", config.tape_size).unwrap();
+    c.push_str("static unsigned char tape[TAPE_SIZE];\n\n");
+    c.push_str("int main(void) {\n");
+    c.push_str("    size_t p = 0;\n");
+    c.push_str("    int c;\n");
+
+    let eof = match config.eof_behavior {
+        EofBehavior::Zero => "tape[p] = 0;",
+        EofBehavior::Unchanged => "",
+        EofBehavior::MaxValue => "tape[p] = 255;",
+        EofBehavior::Error => "fputs(\"unexpected end of input\\n\", stderr); return 1;",
+    };
+    let mut depth = 1;
+    for op in ops {
+        if let Op::Close(_) = op {
+            depth -= 1;
+        }
+        let indent = "    ".repeat(depth);
+        let line = match op {
+            Op::Add(n) => format!("tape[p] += {};", n),
+            Op::Move(n) if *n >= 0 => format!("p += {};", n),
+            Op::Move(n) => format!("p -= {};", n.unsigned_abs()),
+            Op::Out => "putchar(tape[p]);".to_string(),
+            Op::In if eof.is_empty() => "if ((c = getchar()) != EOF) tape[p] = c;".to_string(),
+            Op::In => format!(
+                "if ((c = getchar()) != EOF) tape[p] = c; else {{ {} }}",
+                eof
+            ),
+            Op::Open(_) => "while (tape[p]) {".to_string(),
+            Op::Close(_) => "}".to_string(),
+            Op::Set(n) => format!("tape[p] = {};", n),
+            Op::Mul(offset, factor) => {
+                format!("tape[{}] += tape[p] * {};", cell_index(*offset), factor)
+            }
+            Op::Scan(1) => {
+                "p = (unsigned char *)memchr(tape + p, 0, TAPE_SIZE - p) - tape;".to_string()
+            }
+            Op::Scan(step) if *step > 0 => format!("while (tape[p]) p += {};", step),
+            Op::Scan(step) => format!("while (tape[p]) p -= {};", step.unsigned_abs()),
+            Op::Halt => "return 0;".to_string(),
+            Op::Debug => "/* breakpoint */".to_string(),
+        };
+        writeln!(c, "{}{}", indent, line).unwrap();
+        if let Op::Open(_) = op {
+            depth += 1;
+        }
+    }
+
+    c.push_str("    return 0;\n");
+    c.push_str("}\n");
+    c
+}
+
+// Lists the ops one per line with their index and source position.
+// Adds and multiplication factors are shown as signed bytes, and loop
+// brackets with the index of their partner.
+pub(crate) fn disassemble(ops: &[Op], spans: &[Span]) -> String {
+    let mut text = String::new();
+    for (i, (op, span)) in ops.iter().zip(spans).enumerate() {
+        let op = match op {
+            Op::Add(n) => format!("ADD {:+}", *n as i8),
+            Op::Move(n) => format!("MOVE {:+}", n),
+            Op::Out => "OUT".to_string(),
+            Op::In => "IN".to_string(),
+            Op::Open(target) => format!("OPEN {:04}", target),
+            Op::Close(target) => format!("CLOSE {:04}", target),
+            Op::Set(n) => format!("SET {}", n),
+            Op::Mul(offset, factor) => format!("MUL {:+} x{}", offset, *factor as i8),
+            Op::Scan(step) => format!("SCAN {:+}", step),
+            Op::Halt => "HALT".to_string(),
+            Op::Debug => "DEBUG".to_string(),
+        };
+        writeln!(
+            text,
+            "{:04}  {:<12} (line {}, col {})",
+            i, op, span.line, span.col
+        )
+        .unwrap();
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compile::compile;
+
+    #[test]
+    fn test_to_wat() {
+        let (ops, _) = compile(",[.-]>+[<]").unwrap();
+        let wat = to_wat(&ops);
+
+        assert!(wat.starts_with("(module\n"));
+        assert!(wat.contains("(memory (export \"memory\") 1)"));
+        assert!(wat.contains("(func (export \"run\")"));
+        assert!(wat.contains("(call $input)"));
+        assert!(wat.contains("(call $output (i32.load8_u (local.get $p)))"));
+        assert!(wat.contains("i32.store8"));
+
+        // The loop opened at op 1 and the scan at op 7
+        assert!(wat.contains("(block $b1\n"));
+        assert!(wat.contains("(loop $l1\n"));
+        assert!(wat.contains("(br $l1)))"));
+        assert!(wat.contains("(br_if $b7 "));
+        assert!(wat.contains("(i32.const -1)"));
+
+        let open = wat.matches('(').count();
+        let close = wat.matches(')').count();
+        assert_eq!(open, close);
+    }
+
+    #[test]
+    fn test_to_wat_mul() {
+        let (ops, _) = compile(",[->+++<]").unwrap();
+        assert_eq!(ops, vec![Op::In, Op::Mul(1, 3), Op::Set(0)]);
+        let wat = to_wat(&ops);
+        assert!(wat.contains("(i32.mul (i32.load8_u (local.get $p)) (i32.const 3))"));
+        assert!(!wat.contains("loop"));
+    }
+
+    #[test]
+    fn test_to_rust() {
+        let hello = "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.\
+                     +++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.";
+        let (ops, _) = compile(hello).unwrap();
+        let rs = to_rust(&ops, &Config::default());
+
+        assert!(rs.contains("fn main() -> io::Result<()> {"));
+        assert!(rs.contains("let mut tape = vec![0u8; 30000];"));
+        assert!(rs.contains("tape[p] = tape[p].wrapping_add(8);"));
+        assert!(rs.contains("while tape[p] != 0 {"));
+        assert!(rs.contains("out.write_all(&[tape[p]])?;"));
+        assert_eq!(
+            rs.matches("while tape[p] != 0 {").count(),
+            ops.iter().filter(|op| matches!(op, Op::Open(_))).count()
+        );
+        assert_eq!(rs.matches('{').count(), rs.matches('}').count());
+    }
+
+    #[test]
+    fn test_to_rust_mul_and_scan() {
+        let (ops, _) = compile(",[->+++<<-->]<[<]>[>]").unwrap();
+        let rs = to_rust(&ops, &Config::default());
+        assert!(rs.contains("tape[p + 1] = tape[p + 1].wrapping_add(tape[p].wrapping_mul(3));"));
+        assert!(rs.contains("tape[p - 1] = tape[p - 1].wrapping_add(tape[p].wrapping_mul(254));"));
+        assert!(rs.contains("p = tape[..=p].iter().rposition(|&c| c == 0).unwrap();"));
+        assert!(rs.contains("p += tape[p..].iter().position(|&c| c == 0).unwrap();"));
+        assert!(
+            rs.contains("if input.read(&mut b)? == 1 { tape[p] = b[0]; } else { tape[p] = 0; }")
+        );
+
+        // EOF is handled as the config says
+        let rs = |eof_behavior| {
+            let config = Config {
+                eof_behavior,
+                ..Default::default()
+            };
+            to_rust(&ops, &config)
+        };
+        assert!(rs(EofBehavior::Unchanged)
+            .contains("if input.read(&mut b)? == 1 { tape[p] = b[0]; }\n"));
+        assert!(rs(EofBehavior::MaxValue).contains("} else { tape[p] = 255; }"));
+        assert!(rs(EofBehavior::Error).contains(
+            "} else { return Err(io::Error::new(io::ErrorKind::UnexpectedEof, \
+             \"unexpected end of input\")); }"
+        ));
+    }
+
+    #[test]
+    fn test_to_rust_clear() {
+        let (ops, _) = compile("+++[-]>++[-]+").unwrap();
+        let rs = to_rust(&ops, &Config::default());
+        assert!(rs.contains("let mut tape = vec![0u8; 30000];"));
+        assert!(rs.contains("    tape[p] = 0u8;\n    p += 1;\n"));
+        assert!(rs.contains("tape[p] = 1u8;"));
+        assert!(!rs.contains("while"));
+    }
+
+    #[test]
+    fn test_to_c() {
+        let (ops, _) = compile(",[->+<].").unwrap();
+        let c = to_c(&ops, &Config::default());
+        assert!(c.contains("#define TAPE_SIZE 30000\n"));
+        assert!(c.contains("static unsigned char tape[TAPE_SIZE];"));
+        assert!(c.contains("int main(void) {"));
+        assert!(c.contains("if ((c = getchar()) != EOF) tape[p] = c; else { tape[p] = 0; }"));
+        assert!(c.contains("tape[p + 1] += tape[p] * 1;"));
+        assert!(c.contains("tape[p] = 0;"));
+        assert!(c.contains("putchar(tape[p]);"));
+        assert!(!c.contains("while"));
+        assert!(c.ends_with("    return 0;\n}\n"));
+    }
+
+    #[test]
+    fn test_to_c_loops_and_config() {
+        let (ops, _) = compile(",[.>[>]<[-<++>]<<,]").unwrap();
+        let config = Config {
+            tape_size: 100,
+            eof_behavior: EofBehavior::Unchanged,
+            ..Default::default()
+        };
+        let c = to_c(&ops, &config);
+        assert!(c.contains("#define TAPE_SIZE 100\n"));
+        assert!(c.contains("\n    if ((c = getchar()) != EOF) tape[p] = c;\n"));
+        assert!(c.contains("\n    while (tape[p]) {\n        putchar(tape[p]);\n"));
+        assert!(c.contains("p = (unsigned char *)memchr(tape + p, 0, TAPE_SIZE - p) - tape;"));
+        assert!(c.contains("tape[p - 1] += tape[p] * 2;"));
+        assert!(c.contains("p -= 2;"));
+        assert_eq!(c.matches('{').count(), c.matches('}').count());
+
+        let config = Config {
+            eof_behavior: EofBehavior::Error,
+            ..Default::default()
+        };
+        assert!(to_c(&ops, &config)
+            .contains("fputs(\"unexpected end of input\\n\", stderr); return 1;"));
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let (ops, spans) = compile(",[->+<]").unwrap();
+        assert_eq!(
+            disassemble(&ops, &spans),
+            "0000  IN           (line 1, col 1)\n\
+             0001  MUL +1 x1    (line 1, col 2)\n\
+             0002  SET 0        (line 1, col 2)\n"
+        );
+
+        let (ops, spans) = compile("+\n>[-<-->]>[<]-,[.,]").unwrap();
+        let text = disassemble(&ops, &spans);
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), ops.len());
+        assert_eq!(lines[1], "0001  MOVE +1      (line 2, col 1)");
+        assert_eq!(lines[2], "0002  MUL -1 x-2   (line 2, col 2)");
+        assert_eq!(lines[5], "0005  SCAN -1      (line 2, col 10)");
+        assert_eq!(lines[6], "0006  SET 255      (line 2, col 13)");
+        assert_eq!(lines[8], "0008  OPEN 0011    (line 2, col 15)");
+        assert_eq!(lines[11], "0011  CLOSE 0008   (line 2, col 15)");
+    }
+}
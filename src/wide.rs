@@ -0,0 +1,403 @@
+use crate::execute::{read_byte, read_codepoint};
+use crate::{
+    AccessKind, CellWidth, CompileError, Config, EofBehavior, ExecutionError, InputEncoding,
+    OpLimitAction, Span, Tape,
+};
+use std::io::{Read, Write};
+
+// Ops for cells that aren't 8 bits wide. Runs of + and - and of < and
+// > are merged, but nothing else is optimized. The main compiler works
+// on wrapping 8-bit cells, where adds are folded modulo 256 and a clear
+// or multiplication loop means something else.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum PlainOp {
+    Add(i64),
+    Move(i64),
+    Out,
+    In,
+    Open(usize),
+    Close(usize),
+}
+
+// Compiles source to plain ops, ignoring bytes that aren't commands.
+pub(crate) fn compile_plain(source: &str) -> Result<(Vec<PlainOp>, Vec<Span>), CompileError> {
+    let mut ops = Vec::new();
+    let mut spans: Vec<Span> = Vec::new();
+    let mut loop_stack = Vec::new();
+    let (mut line, mut col) = (1, 1);
+    for (pos, byte) in source.bytes().enumerate() {
+        let span = Span {
+            start: pos,
+            end: pos + 1,
+            line,
+            col,
+        };
+        if byte == b'\n' {
+            line += 1;
+            col = 1;
+        } else {
+            col += 1;
+        }
+        let op = match byte {
+            b'+' | b'-' => PlainOp::Add(if byte == b'+' { 1 } else { -1 }),
+            b'>' | b'<' => PlainOp::Move(if byte == b'>' { 1 } else { -1 }),
+            b'.' => PlainOp::Out,
+            b',' => PlainOp::In,
+            b'[' => {
+                loop_stack.push(ops.len());
+                PlainOp::Open(0)
+            }
+            b']' => {
+                let open = loop_stack
+                    .pop()
+                    .ok_or(CompileError::UnmatchedClose { span })?;
+                ops[open] = PlainOp::Open(ops.len());
+                PlainOp::Close(open)
+            }
+            _ => continue,
+        };
+        match (ops.last_mut(), &op) {
+            (Some(PlainOp::Add(a)), PlainOp::Add(b))
+            | (Some(PlainOp::Move(a)), PlainOp::Move(b)) => {
+                *a += b;
+                spans.last_mut().unwrap().end = span.end;
+            }
+            _ => {
+                ops.push(op);
+                spans.push(span);
+            }
+        }
+    }
+    if let Some(open) = loop_stack.pop() {
+        return Err(CompileError::UnmatchedOpen { span: spans[open] });
+    }
+    Ok((ops, spans))
+}
+
+// A cell that plain ops can run on.
+pub(crate) trait Cell: Clone + Default + PartialEq {
+    // What `,` stores at EOF under EofBehavior::MaxValue
+    fn max_value() -> Self;
+    fn add(&mut self, n: i64);
+    // The cell for a byte or codepoint read by `,`
+    fn from_input(value: u32) -> Self;
+    // What `.` writes
+    fn low_byte(&self) -> u8;
+}
+
+// A fixed-width cell that arithmetic wraps around in.
+trait FixedCell: Cell {
+    fn into_tape(tape: Vec<Self>) -> Tape;
+}
+
+macro_rules! impl_cell {
+    ($cell:ty, $variant:ident) => {
+        impl Cell for $cell {
+            fn max_value() -> Self {
+                <$cell>::MAX
+            }
+
+            fn add(&mut self, n: i64) {
+                *self = self.wrapping_add(n as $cell);
+            }
+
+            // Keeps as many low bits as fit
+            fn from_input(value: u32) -> Self {
+                value as $cell
+            }
+
+            fn low_byte(&self) -> u8 {
+                *self as u8
+            }
+        }
+
+        impl FixedCell for $cell {
+            fn into_tape(tape: Vec<Self>) -> Tape {
+                Tape::$variant(tape)
+            }
+        }
+    };
+}
+
+impl_cell!(u8, Eight);
+impl_cell!(u16, Sixteen);
+impl_cell!(u32, ThirtyTwo);
+
+// Runs plain ops on a zeroed tape of config.tape_size cells, reading
+// input as encoding says. Returns the tape and the pointer.
+//
+// Uses the tape size, op limit and limit action, EOF behavior and
+// output flushing from the config. Pointer wrapping, growing tapes,
+// the time limit and the other settings are ignored.
+pub(crate) fn run_plain<C: Cell>(
+    ops: &[PlainOp],
+    spans: &[Span],
+    config: &Config,
+    encoding: InputEncoding,
+    mut input: Option<&mut dyn Read>,
+    mut output: Option<&mut dyn Write>,
+) -> Result<(Vec<C>, usize), ExecutionError> {
+    let mut tape = vec![C::default(); config.tape_size];
+    let mut pointer = 0usize;
+    let mut ip = 0;
+    let mut opcount = 0usize;
+    let op_limit = config.op_limit.unwrap_or(usize::MAX);
+    let mut input_done = false;
+
+    while ip < ops.len() {
+        let span = spans[ip];
+        match ops[ip] {
+            PlainOp::Add(n) => tape[pointer].add(n),
+            PlainOp::Move(n) => {
+                let new_ptr = pointer as i64 + n;
+                if new_ptr < 0 {
+                    return Err(ExecutionError::PointerUnderflow {
+                        span,
+                        kind: AccessKind::Move,
+                    });
+                }
+                if new_ptr as usize >= tape.len() {
+                    return Err(ExecutionError::PointerOverflow {
+                        span,
+                        pointer: new_ptr as usize,
+                        tape_len: tape.len(),
+                        kind: AccessKind::Move,
+                    });
+                }
+                pointer = new_ptr as usize;
+            }
+            PlainOp::Out => {
+                if let Some(out) = &mut output {
+                    out.write_all(&[tape[pointer].low_byte()])
+                        .and_then(|()| {
+                            if config.flush_output {
+                                out.flush()
+                            } else {
+                                Ok(())
+                            }
+                        })
+                        .map_err(|source| ExecutionError::IoError { span, source })?;
+                }
+            }
+            PlainOp::In => {
+                if let Some(inp) = &mut input {
+                    let read = if input_done {
+                        Ok(None)
+                    } else {
+                        match encoding {
+                            InputEncoding::Bytes => read_byte(&mut **inp).map(|b| b.map(u32::from)),
+                            InputEncoding::Utf8Codepoint => read_codepoint(&mut **inp),
+                        }
+                    };
+                    match read.map_err(|source| ExecutionError::IoError { span, source })? {
+                        Some(value) => tape[pointer] = C::from_input(value),
+                        None => {
+                            input_done = config.eof_marks_done;
+                            match config.eof_behavior {
+                                EofBehavior::Zero => tape[pointer] = C::default(),
+                                EofBehavior::Unchanged => {}
+                                EofBehavior::MaxValue => tape[pointer] = C::max_value(),
+                                EofBehavior::Error => {
+                                    return Err(ExecutionError::UnexpectedEof { span })
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            PlainOp::Open(close) => {
+                if tape[pointer] == C::default() {
+                    ip = close;
+                }
+            }
+            PlainOp::Close(open) => {
+                if tape[pointer] != C::default() {
+                    ip = open;
+                }
+            }
+        }
+        ip += 1;
+        opcount += 1;
+        if opcount > op_limit {
+            match config.op_limit_action {
+                OpLimitAction::Error => return Err(ExecutionError::OperationLimit { span }),
+                OpLimitAction::Halt => break,
+            }
+        }
+    }
+
+    if let Some(out) = output {
+        let span = spans.last().copied().unwrap_or(Span {
+            start: 0,
+            end: 0,
+            line: 1,
+            col: 1,
+        });
+        out.flush()
+            .map_err(|source| ExecutionError::IoError { span, source })?;
+    }
+
+    Ok((tape, pointer))
+}
+
+/// A brainfuck program run on cells of the width set in
+/// [`Config::cell_width`].
+///
+/// Arithmetic wraps at the cell width. `,` stores the byte or codepoint
+/// read, keeping as many low bits as fit, and `.` writes the low byte
+/// of the cell. Loops run as written, without the optimizations that
+/// [`Program`](crate::Program) applies to 8-bit cells.
+#[derive(Clone, Debug)]
+pub struct WideProgram {
+    ops: Vec<PlainOp>,
+    spans: Vec<Span>,
+}
+
+/// State of the machine after running a [`WideProgram`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct WideExecutionResult {
+    pub tape: Tape,
+    pub pointer: usize,
+}
+
+impl WideProgram {
+    /// Compiles source, ignoring bytes that aren't commands.
+    pub fn from_source(source: &str) -> Result<Self, CompileError> {
+        let (ops, spans) = compile_plain(source)?;
+        Ok(Self { ops, spans })
+    }
+
+    /// Runs the program on a zeroed tape of `config.tape_size` cells.
+    ///
+    /// Uses the cell width, tape size, op limit and limit action, EOF
+    /// behavior, input encoding and output flushing from the config.
+    /// Under [`EofBehavior::MaxValue`] the cell is set to the largest
+    /// value of its width. Other settings are ignored, including
+    /// [`Config::wrap_pointer`], [`Config::grow_tape`] and
+    /// [`Config::time_limit`], and a run can't be cancelled.
+    pub fn run(
+        &self,
+        config: &Config,
+        input: Option<&mut dyn Read>,
+        output: Option<&mut dyn Write>,
+    ) -> Result<WideExecutionResult, ExecutionError> {
+        match config.cell_width {
+            CellWidth::Eight => self.run_cells::<u8>(config, input, output),
+            CellWidth::Sixteen => self.run_cells::<u16>(config, input, output),
+            CellWidth::ThirtyTwo => self.run_cells::<u32>(config, input, output),
+        }
+    }
+
+    fn run_cells<C: FixedCell>(
+        &self,
+        config: &Config,
+        input: Option<&mut dyn Read>,
+        output: Option<&mut dyn Write>,
+    ) -> Result<WideExecutionResult, ExecutionError> {
+        let encoding = config.input_encoding;
+        let (tape, pointer) =
+            run_plain::<C>(&self.ops, &self.spans, config, encoding, input, output)?;
+        Ok(WideExecutionResult {
+            tape: C::into_tape(tape),
+            pointer,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(source: &str, width: CellWidth, input: &[u8]) -> (WideExecutionResult, Vec<u8>) {
+        let program = WideProgram::from_source(source).unwrap();
+        let config = Config {
+            tape_size: 4,
+            cell_width: width,
+            ..Default::default()
+        };
+        let mut input = input;
+        let mut output = Vec::new();
+        let result = program
+            .run(&config, Some(&mut input), Some(&mut output))
+            .unwrap();
+        (result, output)
+    }
+
+    #[test]
+    fn test_add_wraps_at_width() {
+        let (result, _) = run(&"+".repeat(65537), CellWidth::Sixteen, b"");
+        assert_eq!(result.tape, Tape::Sixteen(vec![1, 0, 0, 0]));
+        let (result, _) = run(&"+".repeat(65536), CellWidth::Sixteen, b"");
+        assert_eq!(result.tape.get(0), Some(0));
+        let (result, _) = run("->-", CellWidth::Sixteen, b"");
+        assert_eq!(result.tape, Tape::Sixteen(vec![65535, 65535, 0, 0]));
+
+        let (result, _) = run(&"+".repeat(257), CellWidth::Eight, b"");
+        assert_eq!(result.tape, Tape::Eight(vec![1, 0, 0, 0]));
+        let (result, _) = run("-", CellWidth::ThirtyTwo, b"");
+        assert_eq!(result.tape.get(0), Some(u32::MAX));
+    }
+
+    #[test]
+    fn test_loops() {
+        // 300 * 300 in cell 2, which only fits from 32 bits
+        let source = format!("{}[->{}<]>[->+<]>.", "+".repeat(300), "+".repeat(300));
+        let (result, output) = run(&source, CellWidth::ThirtyTwo, b"");
+        assert_eq!(result.tape, Tape::ThirtyTwo(vec![0, 0, 90000, 0]));
+        assert_eq!(result.pointer, 2);
+        // Only the low byte is written
+        assert_eq!(output, [(90000 % 256) as u8]);
+
+        let (result, _) = run(&source, CellWidth::Sixteen, b"");
+        assert_eq!(result.tape.get(2), Some(90000 % 65536));
+    }
+
+    #[test]
+    fn test_io() {
+        let (result, output) = run(",.>,.", CellWidth::Sixteen, b"AB");
+        assert_eq!(output, b"AB");
+        assert_eq!(result.tape, Tape::Sixteen(vec![65, 66, 0, 0]));
+
+        // Codepoints keep as many bits as fit
+        let program = WideProgram::from_source(",>,").unwrap();
+        let run = |cell_width| {
+            let config = Config {
+                tape_size: 2,
+                cell_width,
+                input_encoding: InputEncoding::Utf8Codepoint,
+                eof_behavior: EofBehavior::MaxValue,
+                ..Default::default()
+            };
+            program
+                .run(&config, Some(&mut "€".as_bytes()), None)
+                .unwrap()
+                .tape
+        };
+        assert_eq!(run(CellWidth::Eight), Tape::Eight(vec![0xac, 255]));
+        assert_eq!(run(CellWidth::Sixteen), Tape::Sixteen(vec![0x20ac, 65535]));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(matches!(
+            WideProgram::from_source("[+"),
+            Err(CompileError::UnmatchedOpen { .. })
+        ));
+
+        let program = WideProgram::from_source("+\n<").unwrap();
+        let result = program.run(&Config::default(), None, None);
+        assert!(matches!(
+            result,
+            Err(ExecutionError::PointerUnderflow { span, .. }) if span.line == 2
+        ));
+
+        let program = WideProgram::from_source("+[]").unwrap();
+        let config = Config {
+            op_limit: Some(100),
+            cell_width: CellWidth::Sixteen,
+            ..Default::default()
+        };
+        let result = program.run(&config, None, None);
+        assert!(matches!(result, Err(ExecutionError::OperationLimit { .. })));
+    }
+}
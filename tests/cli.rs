@@ -6,7 +6,7 @@ use std::io::Write;
 use tempfile::NamedTempFile;
 
 fn cmd() -> Command {
-    assert_cmd::cargo::cargo_bin_cmd!("rustfuck").into()
+    assert_cmd::cargo::cargo_bin_cmd!("rustfuck")
 }
 
 fn batch_results(output: &[u8]) -> Vec<Value> {
@@ -60,6 +60,51 @@ fn test_io_stdin_stdout() {
         .stdout("X");
 }
 
+#[test]
+fn test_inline_code() {
+    cmd()
+        .arg("run")
+        .arg("-c")
+        .arg(",.")
+        .write_stdin("X")
+        .assert()
+        .success()
+        .stdout("X");
+
+    cmd()
+        .arg("run")
+        .arg("tests/programs/echo.b")
+        .arg("-c")
+        .arg(",.")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("cannot be used with"));
+}
+
+#[test]
+fn test_program_from_stdin() {
+    let mut input_file = NamedTempFile::new().unwrap();
+    write!(input_file, "Y").unwrap();
+
+    cmd()
+        .arg("run")
+        .arg("-")
+        .arg("-i")
+        .arg(input_file.path())
+        .write_stdin(",.")
+        .assert()
+        .success()
+        .stdout("Y");
+
+    cmd()
+        .arg("run")
+        .arg("-")
+        .write_stdin(",.")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("must be given with -i"));
+}
+
 #[test]
 fn test_io_file_in() {
     let mut input_file = NamedTempFile::new().unwrap();
@@ -112,6 +157,150 @@ fn test_io_file_in_and_out() {
     assert_eq!(output, b"W");
 }
 
+#[test]
+fn test_io_tee() {
+    let tee_file = NamedTempFile::new().unwrap();
+
+    cmd()
+        .arg("run")
+        .arg("tests/programs/echo.b")
+        .arg("--tee")
+        .arg(tee_file.path())
+        .write_stdin("T")
+        .assert()
+        .success()
+        .stdout("T");
+
+    let teed = fs::read(tee_file.path()).unwrap();
+    assert_eq!(teed, b"T");
+}
+
+#[test]
+fn test_io_echo_input() {
+    cmd()
+        .arg("run")
+        .arg("tests/programs/echo.b")
+        .arg("--echo-input")
+        .write_stdin("Q")
+        .assert()
+        .success()
+        .stdout("Q")
+        .stderr("Q");
+
+    cmd()
+        .arg("run")
+        .arg("tests/programs/echo.b")
+        .write_stdin("Q")
+        .assert()
+        .success()
+        .stderr("");
+}
+
+#[test]
+fn test_io_input_repeat() {
+    let mut program = NamedTempFile::new().unwrap();
+    write!(program, ",.,.,.,.,.,.").unwrap();
+
+    cmd()
+        .arg("run")
+        .arg(program.path())
+        .arg("--input-repeat=3")
+        .write_stdin("ab")
+        .assert()
+        .success()
+        .stdout("ababab");
+
+    // Past the end of the repeats reads hit EOF
+    cmd()
+        .arg("run")
+        .arg(program.path())
+        .arg("--input-repeat=2")
+        .arg("--eof=zero")
+        .write_stdin("ab")
+        .assert()
+        .success()
+        .stdout("abab\0\0");
+
+    let mut program = NamedTempFile::new().unwrap();
+    write!(program, "++++++++++[>,.<-]").unwrap();
+
+    cmd()
+        .arg("run")
+        .arg(program.path())
+        .arg("--input-repeat=inf")
+        .write_stdin("xyz")
+        .assert()
+        .success()
+        .stdout("xyzxyzxyzx");
+
+    cmd()
+        .arg("run")
+        .arg(program.path())
+        .arg("--input-repeat=often")
+        .assert()
+        .failure();
+}
+
+#[test]
+fn test_io_ascii() {
+    let mut program = NamedTempFile::new().unwrap();
+    write!(
+        program,
+        "+++++++.>++++++++++.>++++++++[-<++++++++>]<+.>++++[-<++++++++++++++++++++>]<."
+    )
+    .unwrap();
+
+    cmd()
+        .arg("run")
+        .arg(program.path())
+        .arg("--ascii")
+        .assert()
+        .success()
+        .stdout("\\x07\\nK\\x9b");
+
+    cmd()
+        .arg("run")
+        .arg(program.path())
+        .assert()
+        .success()
+        .stdout(predicate::eq(b"\x07\nK\x9b" as &[u8]));
+}
+
+#[test]
+fn test_io_ascii_wrap() {
+    let mut program = NamedTempFile::new().unwrap();
+    write!(program, "++++++++[>++++++++<-]>+.+.+.-------------------------------------------------------------------------.").unwrap();
+
+    // ABC then \xfa, which would cross column 5 and moves to a new line
+    cmd()
+        .arg("run")
+        .arg(program.path())
+        .arg("--ascii")
+        .arg("--wrap")
+        .arg("5")
+        .assert()
+        .success()
+        .stdout("ABC\n\\xfa");
+
+    cmd()
+        .arg("run")
+        .arg(program.path())
+        .arg("--ascii")
+        .arg("--wrap")
+        .arg("2")
+        .assert()
+        .success()
+        .stdout("AB\nC\n\\xfa");
+
+    cmd()
+        .arg("run")
+        .arg(program.path())
+        .arg("--wrap")
+        .arg("5")
+        .assert()
+        .failure();
+}
+
 // =============================================================================
 // Runtime configuration flags
 // =============================================================================
@@ -139,6 +328,52 @@ fn test_cfg_memory_exceeded() {
         .stderr(predicate::str::contains("pointer overflow"));
 }
 
+#[test]
+fn test_cfg_env() {
+    cmd()
+        .arg("run")
+        .arg("tests/programs/memoryhog.b")
+        .env("RUSTFUCK_TAPE_SIZE", "65536")
+        .assert()
+        .success();
+
+    // Flags win over the environment
+    cmd()
+        .arg("run")
+        .arg("tests/programs/memoryhog.b")
+        .env("RUSTFUCK_TAPE_SIZE", "65536")
+        .arg("-m")
+        .arg("65534")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("pointer overflow"));
+
+    cmd()
+        .arg("run")
+        .arg("tests/programs/echo.b")
+        .env("RUSTFUCK_EOF", "max")
+        .write_stdin("")
+        .assert()
+        .success()
+        .stdout(predicate::eq(vec![255u8]));
+
+    cmd()
+        .arg("run")
+        .arg("tests/programs/basicops.b")
+        .env("RUSTFUCK_OP_LIMIT", "10")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("operation limit"));
+
+    cmd()
+        .arg("run")
+        .arg("tests/programs/echo.b")
+        .env("RUSTFUCK_TAPE_SIZE", "big")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("invalid RUSTFUCK_TAPE_SIZE"));
+}
+
 #[test]
 fn test_cfg_op_limit_success() {
     cmd()
@@ -162,6 +397,36 @@ fn test_cfg_op_limit_exceeded() {
         .stderr(predicate::str::contains("operation limit exceeded"));
 }
 
+#[test]
+fn test_cfg_limit_action_halt() {
+    let mut program = NamedTempFile::new().unwrap();
+    write!(program, "++++++++[>++++++++<-]>+[.]").unwrap();
+
+    cmd()
+        .arg("run")
+        .arg(program.path())
+        .arg("-l")
+        .arg("50")
+        .arg("--limit-action")
+        .arg("halt")
+        .assert()
+        .success()
+        .stdout(predicate::str::is_match("^A+$").unwrap())
+        .stderr("");
+
+    cmd()
+        .arg("run")
+        .arg(program.path())
+        .arg("-l")
+        .arg("50")
+        .arg("--limit-action")
+        .arg("error")
+        .assert()
+        .failure()
+        .stdout(predicate::str::is_match("^A+$").unwrap())
+        .stderr(predicate::str::contains("operation limit exceeded"));
+}
+
 #[test]
 fn test_cfg_eof_default() {
     let mut program = NamedTempFile::new().unwrap();
@@ -200,6 +465,32 @@ fn test_cfg_eof_unchanged() {
         .success();
 }
 
+#[test]
+fn test_cfg_eof_error() {
+    cmd()
+        .arg("run")
+        .arg("tests/programs/echo.b")
+        .arg("-e")
+        .arg("error")
+        .write_stdin("")
+        .assert()
+        .failure()
+        .stdout("")
+        .stderr(predicate::str::contains(
+            "unexpected end of input at line 1, column 1",
+        ));
+
+    cmd()
+        .arg("run")
+        .arg("tests/programs/echo.b")
+        .arg("-e")
+        .arg("error")
+        .write_stdin("A")
+        .assert()
+        .success()
+        .stdout("A");
+}
+
 #[test]
 fn test_cfg_eof_max() {
     cmd()
@@ -213,48 +504,166 @@ fn test_cfg_eof_max() {
         .stdout(predicate::eq(vec![255u8]));
 }
 
-// =============================================================================
-// Batch mode
-// =============================================================================
-
 #[test]
-fn test_batch_empty() {
-    let out = cmd()
+fn test_cfg_file() {
+    let mut config = NamedTempFile::new().unwrap();
+    write!(config, r#"{{"tape_size": 100, "eof_behavior": "max"}}"#).unwrap();
+
+    cmd()
         .arg("run")
-        .arg("tests/programs/empty.b")
-        .arg("--batch")
-        .write_stdin("{}\n")
-        .output()
-        .unwrap();
+        .arg("tests/programs/memoryhog.b")
+        .arg("--config")
+        .arg(config.path())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("pointer overflow"));
 
-    assert_eq!(
-        batch_results(&out.stdout),
-        vec![json!({"ok": true, "tape": [], "pointer": 0, "output": []})]
-    );
+    cmd()
+        .arg("run")
+        .arg("tests/programs/echo.b")
+        .arg("--config")
+        .arg(config.path())
+        .write_stdin("")
+        .assert()
+        .success()
+        .stdout(predicate::eq(vec![255u8]));
 }
 
 #[test]
-fn test_batch_id_preserved() {
-    let out = cmd()
+fn test_cfg_file_overridden_by_flags() {
+    let mut config = NamedTempFile::new().unwrap();
+    write!(config, r#"{{"tape_size": 100, "eof_behavior": "max"}}"#).unwrap();
+
+    cmd()
         .arg("run")
-        .arg("tests/programs/empty.b")
-        .arg("--batch")
-        .write_stdin(batch_input(&[json!({"id": "foo"})]))
-        .output()
-        .unwrap();
+        .arg("tests/programs/memoryhog.b")
+        .arg("--config")
+        .arg(config.path())
+        .arg("-m")
+        .arg("65536")
+        .assert()
+        .success();
 
-    assert_eq!(
-        batch_results(&out.stdout),
-        vec![json!({"id": "foo", "ok": true, "tape": [], "pointer": 0, "output": []})]
-    );
+    cmd()
+        .arg("run")
+        .arg("tests/programs/echo.b")
+        .arg("--config")
+        .arg(config.path())
+        .arg("-e")
+        .arg("zero")
+        .write_stdin("")
+        .assert()
+        .success()
+        .stdout(predicate::eq(vec![0u8]));
 }
 
 #[test]
-fn test_batch_multiple_lines() {
-    let out = cmd()
+fn test_cfg_file_invalid() {
+    let mut config = NamedTempFile::new().unwrap();
+    write!(config, r#"{{"tape_sise": 100}}"#).unwrap();
+
+    cmd()
         .arg("run")
         .arg("tests/programs/echo.b")
-        .arg("--batch")
+        .arg("--config")
+        .arg(config.path())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("invalid config file"));
+
+    cmd()
+        .arg("run")
+        .arg("tests/programs/echo.b")
+        .arg("--config")
+        .arg("nonexistent.json")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("failed to read config file"));
+
+    // Only 8-bit cells are run
+    let mut config = NamedTempFile::new().unwrap();
+    write!(config, r#"{{"cell_width": "sixteen"}}"#).unwrap();
+    cmd()
+        .arg("run")
+        .arg("-c")
+        .arg(format!("{}.", "+".repeat(257)))
+        .arg("--config")
+        .arg(config.path())
+        .assert()
+        .failure()
+        .stdout(predicate::str::is_empty())
+        .stderr(predicate::str::contains("unsupported cell width: sixteen"));
+}
+
+#[test]
+fn test_repeat() {
+    cmd()
+        .arg("run")
+        .arg("tests/programs/echo.b")
+        .arg("--repeat")
+        .arg("3")
+        .write_stdin("R")
+        .assert()
+        .success()
+        .stdout("R")
+        .stderr(
+            predicate::str::contains("runs: 3")
+                .and(predicate::str::contains("min:"))
+                .and(predicate::str::contains("mean:"))
+                .and(predicate::str::contains("max:")),
+        );
+
+    cmd()
+        .arg("run")
+        .arg("tests/programs/echo.b")
+        .arg("--repeat")
+        .arg("0")
+        .assert()
+        .failure();
+}
+
+// =============================================================================
+// Batch mode
+// =============================================================================
+
+#[test]
+fn test_batch_empty() {
+    let out = cmd()
+        .arg("run")
+        .arg("tests/programs/empty.b")
+        .arg("--batch")
+        .write_stdin("{}\n")
+        .output()
+        .unwrap();
+
+    assert_eq!(
+        batch_results(&out.stdout),
+        vec![json!({"ok": true, "tape": [], "pointer": 0, "output": []})]
+    );
+}
+
+#[test]
+fn test_batch_id_preserved() {
+    let out = cmd()
+        .arg("run")
+        .arg("tests/programs/empty.b")
+        .arg("--batch")
+        .write_stdin(batch_input(&[json!({"id": "foo"})]))
+        .output()
+        .unwrap();
+
+    assert_eq!(
+        batch_results(&out.stdout),
+        vec![json!({"id": "foo", "ok": true, "tape": [], "pointer": 0, "output": []})]
+    );
+}
+
+#[test]
+fn test_batch_multiple_lines() {
+    let out = cmd()
+        .arg("run")
+        .arg("tests/programs/echo.b")
+        .arg("--batch")
         .write_stdin(batch_input(&[
             json!({"id": "a", "input": [65]}),
             json!({"id": "b", "input": [66]}),
@@ -292,6 +701,49 @@ fn test_batch_empty_lines_skipped() {
     );
 }
 
+#[test]
+fn test_batch_many_lines() {
+    let mut program = NamedTempFile::new().unwrap();
+    write!(program, ",+.").unwrap();
+
+    // Mixes in blank lines, CRLF endings and a bad line to check that
+    // nothing leaks between lines read into the shared buffer
+    let mut stdin = String::new();
+    for i in 0..1000 {
+        match i % 4 {
+            0 => stdin.push_str(&format!("{{\"id\":\"{}\",\"input\":[{}]}}\n\n", i, i % 256)),
+            1 => stdin.push_str(&format!("{{\"id\":\"{}\",\"input\":[{}]}}\r\n", i, i % 256)),
+            2 => stdin.push_str(&format!("{{\"id\":\"{}\"}}\n", i)),
+            _ => stdin.push_str("nope\n"),
+        }
+    }
+
+    let out = cmd()
+        .arg("run")
+        .arg(program.path())
+        .arg("--batch")
+        .write_stdin(stdin)
+        .output()
+        .unwrap();
+
+    let results = batch_results(&out.stdout);
+    assert_eq!(results.len(), 1000);
+    for (i, result) in results.iter().enumerate() {
+        match i % 4 {
+            0 | 1 => {
+                let v = (i % 256 + 1) % 256;
+                assert_eq!(result["id"], json!(i.to_string()));
+                assert_eq!(result["output"], json!([v]));
+            }
+            2 => {
+                assert_eq!(result["id"], json!(i.to_string()));
+                assert_eq!(result["output"], json!([1]));
+            }
+            _ => assert_eq!(result["ok"], json!(false)),
+        }
+    }
+}
+
 #[test]
 fn test_batch_initial_state() {
     let mut program = NamedTempFile::new().unwrap();
@@ -319,6 +771,31 @@ fn test_batch_initial_state() {
     );
 }
 
+// A supplied tape shorter than the tape size is padded with zeros.
+#[test]
+fn test_batch_short_tape_padded() {
+    let mut program = NamedTempFile::new().unwrap();
+    write!(program, ">>>>+").unwrap();
+
+    let out = cmd()
+        .arg("run")
+        .arg(program.path())
+        .arg("--batch")
+        .write_stdin(batch_input(&[
+            json!({"tape": [1, 2]}),
+            json!({"tape": [1, 2], "config": {"tape_size": 3}}),
+        ]))
+        .output()
+        .unwrap();
+
+    let results = batch_results(&out.stdout);
+    assert_eq!(
+        results[0],
+        json!({"ok": true, "tape": [1, 2, 0, 0, 1], "pointer": 4, "output": []})
+    );
+    assert_eq!(results[1]["ok"], false);
+}
+
 // The tape field in batch output has trailing zeros trimmed.
 #[test]
 fn test_batch_trailing_zeros_trimmed() {
@@ -339,6 +816,36 @@ fn test_batch_trailing_zeros_trimmed() {
     );
 }
 
+// The tape_window field limits the returned tape to a slice.
+#[test]
+fn test_batch_tape_window() {
+    let mut program = NamedTempFile::new().unwrap();
+    write!(program, "+>++>+++>++++>+++++").unwrap();
+
+    let out = cmd()
+        .arg("run")
+        .arg(program.path())
+        .arg("--batch")
+        .write_stdin(batch_input(&[
+            json!({"id": "a", "tape_window": [1, 3]}),
+            json!({"id": "b", "tape_window": [3, 8]}),
+            json!({"id": "c", "tape_window": [5, 2], "config": {"tape_size": 6}}),
+            json!({"id": "d", "tape_window": [4, 100], "config": {"tape_size": 6}}),
+        ]))
+        .output()
+        .unwrap();
+
+    assert_eq!(
+        batch_results(&out.stdout),
+        vec![
+            json!({"id": "a", "ok": true, "tape": [2, 3], "tape_window": [1, 3], "pointer": 4, "output": []}),
+            json!({"id": "b", "ok": true, "tape": [4, 5, 0, 0, 0], "tape_window": [3, 8], "pointer": 4, "output": []}),
+            json!({"id": "c", "ok": true, "tape": [], "tape_window": [2, 2], "pointer": 4, "output": []}),
+            json!({"id": "d", "ok": true, "tape": [5, 0], "tape_window": [4, 6], "pointer": 4, "output": []}),
+        ]
+    );
+}
+
 // =============================================================================
 // Batch mode with runtime config
 // =============================================================================
@@ -389,6 +896,7 @@ fn test_batch_cfg_eof() {
             json!({"id": "max", "input": [], "config": {"eof_behavior": "max"}}),
             json!({"id": "zero", "input": [], "config": {"eof_behavior": "zero"}}),
             json!({"id": "unchanged", "input": [], "tape": [42], "config": {"eof_behavior": "unchanged"}}),
+            json!({"id": "error", "input": [], "config": {"eof_behavior": "error"}}),
             json!({"id": "default", "input": []}),
             json!({"id": "invalid", "input": [], "config": {"eof_behavior": "invalid_value"}}),
         ]))
@@ -401,12 +909,352 @@ fn test_batch_cfg_eof() {
             json!({"id": "max", "ok": true, "tape": [255], "pointer": 0, "output": [255]}),
             json!({"id": "zero", "ok": true, "tape": [], "pointer": 0, "output": [0]}),
             json!({"id": "unchanged", "ok": true, "tape": [42], "pointer": 0, "output": [42]}),
+            json!({"id": "error", "ok": false, "error": "unexpected end of input at line 1, column 1"}),
             json!({"id": "default", "ok": true, "tape": [], "pointer": 0, "output": [0]}),
             json!({"id": "invalid", "ok": true, "tape": [], "pointer": 0, "output": [0]}),
         ]
     );
 }
 
+// =============================================================================
+// Op dumps
+// =============================================================================
+
+#[test]
+fn test_dry_run() {
+    let mut program = NamedTempFile::new().unwrap();
+    write!(program, "+[]\n>[>+<<].").unwrap();
+
+    cmd()
+        .arg("run")
+        .arg(program.path())
+        .arg("--dry-run")
+        .assert()
+        .failure()
+        .stdout("")
+        .stderr(
+            "error: empty loop may not terminate at line 1, column 2\n\
+             warning: unbalanced loop: loop body has nonzero net pointer movement \
+             at line 2, column 2\n",
+        );
+
+    let mut program = NamedTempFile::new().unwrap();
+    write!(program, ",[.,]").unwrap();
+
+    cmd()
+        .arg("run")
+        .arg(program.path())
+        .arg("--dry-run")
+        .write_stdin("abc")
+        .assert()
+        .success()
+        .stdout("")
+        .stderr("");
+
+    // Warnings alone don't fail
+    let mut program = NamedTempFile::new().unwrap();
+    write!(program, ",[--]").unwrap();
+
+    cmd()
+        .arg("run")
+        .arg(program.path())
+        .arg("--dry-run")
+        .assert()
+        .success()
+        .stderr(predicate::str::starts_with("warning: clear loop"));
+}
+
+#[test]
+fn test_dump_tape() {
+    let mut program = NamedTempFile::new().unwrap();
+    write!(program, "+>++>+++>>").unwrap();
+
+    cmd()
+        .arg("run")
+        .arg(program.path())
+        .arg("--dump-tape")
+        .assert()
+        .success()
+        .stdout("")
+        .stderr("tape: [1, 2, 3]\npointer: 4\n");
+
+    cmd()
+        .arg("run")
+        .arg(program.path())
+        .arg("--dump-tape-range=1:6")
+        .assert()
+        .success()
+        .stderr("tape[1..6]: [2, 3, 0, 0, 0]\npointer: 4\n");
+
+    cmd()
+        .arg("run")
+        .arg(program.path())
+        .arg("--dump-tape-range=1")
+        .assert()
+        .failure();
+}
+
+#[test]
+fn test_memory_auto() {
+    let mut program = NamedTempFile::new().unwrap();
+    write!(program, ">>>+").unwrap();
+
+    cmd()
+        .arg("run")
+        .arg(program.path())
+        .arg("-m")
+        .arg("auto")
+        .arg("--dump-tape-range=0:100")
+        .assert()
+        .success()
+        .stderr("tape[0..4]: [0, 0, 0, 1]\npointer: 3\n");
+
+    // The environment takes `auto` too, and -m still wins over it
+    cmd()
+        .arg("run")
+        .arg(program.path())
+        .env("RUSTFUCK_TAPE_SIZE", "auto")
+        .arg("--dump-tape-range=0:100")
+        .assert()
+        .success()
+        .stderr("tape[0..4]: [0, 0, 0, 1]\npointer: 3\n");
+    cmd()
+        .arg("run")
+        .arg(program.path())
+        .env("RUSTFUCK_TAPE_SIZE", "auto")
+        .arg("-m")
+        .arg("6")
+        .arg("--dump-tape-range=0:100")
+        .assert()
+        .success()
+        .stderr("tape[0..6]: [0, 0, 0, 1, 0, 0]\npointer: 3\n");
+
+    // A scan can reach any cell, so the usual size is kept
+    let mut program = NamedTempFile::new().unwrap();
+    write!(program, ",[>]").unwrap();
+
+    cmd()
+        .arg("run")
+        .arg(program.path())
+        .arg("-m")
+        .arg("auto")
+        .arg("--dump-tape-range=0:100")
+        .assert()
+        .success()
+        .stderr(predicate::str::starts_with("tape[0..100]: "));
+
+    cmd()
+        .arg("run")
+        .arg(program.path())
+        .arg("-m")
+        .arg("lots")
+        .assert()
+        .failure();
+}
+
+#[test]
+fn test_debug_breakpoint() {
+    let mut program = NamedTempFile::new().unwrap();
+    write!(program, "+>++#>+++").unwrap();
+
+    cmd()
+        .arg("run")
+        .arg(program.path())
+        .arg("--debug")
+        .arg("--memory=4")
+        .assert()
+        .success()
+        .stderr("tape[0..4]: [1, 2, 0, 0]\npointer: 1\n");
+
+    // Without the flag, # is a comment
+    cmd()
+        .arg("run")
+        .arg(program.path())
+        .assert()
+        .success()
+        .stderr("");
+}
+
+#[test]
+fn test_dump_ops_text() {
+    let mut program = NamedTempFile::new().unwrap();
+    write!(program, ",\n[->+<]").unwrap();
+
+    cmd()
+        .arg("run")
+        .arg(program.path())
+        .arg("--dump-ops")
+        .assert()
+        .success()
+        .stdout("0000  In (1:1)\n0001  Mul(1, 1) (2:1)\n0002  Set(0) (2:1)\n");
+}
+
+#[test]
+fn test_dump_ops_json() {
+    let mut program = NamedTempFile::new().unwrap();
+    write!(program, ",[->+<].[>.<-]").unwrap();
+
+    let out = cmd()
+        .arg("run")
+        .arg(program.path())
+        .arg("--dump-ops=json")
+        .output()
+        .unwrap();
+    assert!(out.status.success());
+
+    let dump: Value = serde_json::from_slice(&out.stdout).unwrap();
+    assert_eq!(
+        dump["ops"],
+        json!([
+            {"op": "In"},
+            {"op": "Mul", "args": [1, 1]},
+            {"op": "Set", "args": 0},
+            {"op": "Out"},
+            {"op": "Open", "args": 9},
+            {"op": "Move", "args": 1},
+            {"op": "Out"},
+            {"op": "Move", "args": -1},
+            {"op": "Add", "args": 255},
+            {"op": "Close", "args": 4},
+        ])
+    );
+    assert_eq!(
+        dump["spans"][1],
+        json!({"start": 1, "end": 7, "line": 1, "col": 2})
+    );
+    assert_eq!(dump["spans"].as_array().unwrap().len(), 10);
+}
+
+// =============================================================================
+// Bench
+// =============================================================================
+
+#[test]
+fn test_bench() {
+    cmd()
+        .arg("bench")
+        .arg("--iterations")
+        .arg("1")
+        .assert()
+        .success()
+        .stdout(predicate::str::is_match(r"(?m)^factor +\d+ ops .* \d+ ops/sec$").unwrap());
+
+    cmd()
+        .arg("bench")
+        .arg("--iterations")
+        .arg("1")
+        .arg("--machine")
+        .assert()
+        .success()
+        .stdout(predicate::str::is_match(r"(?m)^factor +\d+ ops .* \d+ ops/sec$").unwrap());
+
+    cmd()
+        .arg("bench")
+        .arg("--iterations")
+        .arg("0")
+        .assert()
+        .failure();
+}
+
+// =============================================================================
+// Disasm
+// =============================================================================
+
+#[test]
+fn test_disasm() {
+    let mut file = NamedTempFile::new().unwrap();
+    file.write_all(b"+++[->++<]>.").unwrap();
+
+    cmd()
+        .arg("disasm")
+        .arg(file.path())
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("MUL"));
+
+    let mut file = NamedTempFile::new().unwrap();
+    file.write_all(b"+[").unwrap();
+    cmd()
+        .arg("disasm")
+        .arg(file.path())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Compile error"));
+}
+
+// =============================================================================
+// Transpile
+// =============================================================================
+
+#[test]
+fn test_transpile() {
+    cmd()
+        .arg("transpile")
+        .arg("tests/programs/echo.b")
+        .arg("--target")
+        .arg("c")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("unsigned char"))
+        .stdout(predicate::str::contains("#define TAPE_SIZE 30000"));
+
+    cmd()
+        .arg("transpile")
+        .arg("tests/programs/echo.b")
+        .arg("--target")
+        .arg("rust")
+        .arg("-m")
+        .arg("100")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("vec![0u8; 100]"));
+
+    let output = NamedTempFile::new().unwrap();
+    cmd()
+        .arg("transpile")
+        .arg("tests/programs/echo.b")
+        .arg("--target")
+        .arg("c")
+        .arg("-m")
+        .arg("100")
+        .arg("-o")
+        .arg(output.path())
+        .assert()
+        .success()
+        .stdout("");
+    let code = fs::read_to_string(output.path()).unwrap();
+    assert!(code.contains("#define TAPE_SIZE 100"));
+
+    // EOF leaves the cell unchanged unless -e says otherwise, as for run
+    cmd()
+        .arg("transpile")
+        .arg("tests/programs/echo.b")
+        .arg("--target")
+        .arg("c")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("tape[p] = c;\n"));
+    cmd()
+        .arg("transpile")
+        .arg("tests/programs/echo.b")
+        .arg("--target")
+        .arg("rust")
+        .arg("-e")
+        .arg("zero")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("} else { tape[p] = 0; }"));
+
+    cmd()
+        .arg("transpile")
+        .arg("tests/programs/unmatched.b")
+        .arg("--target")
+        .arg("c")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Compile error"));
+}
+
 // =============================================================================
 // Errors
 // =============================================================================
@@ -441,6 +1289,31 @@ fn test_compile_error() {
         .stderr(predicate::str::contains("unmatched '['"));
 }
 
+#[test]
+fn test_source_too_large() {
+    cmd()
+        .arg("run")
+        .arg("tests/programs/echo.b")
+        .arg("--max-source-size")
+        .arg("1")
+        .write_stdin("X")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "source too large: 2 bytes (limit 1 bytes)",
+        ));
+
+    cmd()
+        .arg("run")
+        .arg("tests/programs/echo.b")
+        .arg("--max-source-size")
+        .arg("2")
+        .write_stdin("X")
+        .assert()
+        .success()
+        .stdout("X");
+}
+
 #[test]
 fn test_runtime_error() {
     cmd()